@@ -0,0 +1,93 @@
+use crate::components::*;
+use crate::constants::*;
+use crate::field_systems::level_exists;
+use bevy::prelude::*;
+
+/// Ends the round once one or no players are left and tallies the win.
+pub fn battle_round_system(
+    settings: Res<GameSettings>,
+    mut battle: ResMut<Battle>,
+    player_query: Query<(&Player, &PlayerId)>,
+    mut pending: ResMut<PendingState>,
+    time: Res<SimTime>,
+) {
+    if settings.mode != GameMode::Battle {
+        return;
+    }
+
+    let alive: Vec<PlayerId> = player_query
+        .iter()
+        .filter(|(player, _)| player.health > 0)
+        .map(|(_, id)| *id)
+        .collect();
+    if alive.len() > 1 {
+        battle.round_end = None;
+        return;
+    }
+
+    let round_end = battle
+        .round_end
+        .get_or_insert_with(|| Timer::from_seconds(ROUND_END_DELAY, false));
+    round_end.tick(time.delta());
+    if !round_end.finished() {
+        return;
+    }
+
+    let winner = alive.first().copied();
+    if let Some(winner) = winner {
+        battle.wins[winner.0 - 1] += 1;
+    }
+    battle.round_winner = winner;
+    battle.round_end = None;
+    pending.set(AppState::LevelComplete);
+}
+
+/// Moves on to the next round, played on the next arena, or starts a new match once somebody won this one.
+pub fn next_arena(battle: &mut Battle, field: &mut Field, pack: &LevelPack) {
+    if let Some(winner) = battle.match_winner() {
+        println!("Player {} won the match, starting a new one.", winner.0);
+        *battle = Battle::new(battle.best_of);
+    } else {
+        battle.round += 1;
+    }
+
+    // going back to the first arena after the last one
+    field.current_level += 1;
+    if !level_exists(pack, GameMode::Battle, field.current_level) {
+        field.current_level = 1;
+    }
+}
+
+/// The round winner and the standings shown between rounds.
+pub fn results_text(battle: &Battle, player_count: usize) -> String {
+    let mut lines = vec![match battle.round_winner {
+        Some(winner) => format!("Round {}: Player {} wins!", battle.round, winner.0),
+        None => format!("Round {}: Draw!", battle.round),
+    }];
+
+    let mut standings: Vec<(usize, u32)> = battle
+        .wins
+        .iter()
+        .take(player_count)
+        .enumerate()
+        .map(|(index, wins)| (index + 1, *wins))
+        .collect();
+    standings.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    lines.push(String::new());
+    lines.extend(
+        standings
+            .iter()
+            .map(|(id, wins)| format!("Player {}: {} / {}", id, wins, battle.wins_needed())),
+    );
+
+    lines.push(String::new());
+    lines.push(match battle.match_winner() {
+        Some(champion) => format!("Player {} wins the match!", champion.0),
+        None => format!(
+            "Best of {}, first to {} wins takes the match.",
+            battle.best_of,
+            battle.wins_needed()
+        ),
+    });
+    lines.join("\n")
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::time::Duration;
+
+use bomberman::components::{Difficulty, GameMode, LevelPack};
+use bomberman::constants::{BOT_DEADLINE_MS, MATCH_TICK_LIMIT, MAX_PLAYERS};
+use bomberman::net_systems::parse_option;
+use bomberman::protocol_systems::{
+    parse_replay, replay_match, replay_text, run_match, MatchConfig, MatchResult, PlayerSpec,
+};
+
+/// Plays one headless match between the bots given as `bot=<command>` or
+/// `bot=tcp:<address:port>` and the CPU players given as `cpu`, then prints how it went. Also
+/// takes `mode=<battle|campaign>`, `pack=<directory>`, `level=<n>`, `seed=<n>`,
+/// `deadline=<milliseconds>`, `ticks=<n>`, `bot_level=<difficulty>` and `record=<file>` to save
+/// a replay. `replay=<file>` plays a saved replay again instead.
+fn main() {
+    let mut config = MatchConfig {
+        mode: GameMode::Battle,
+        pack: LevelPack::default(),
+        level: 1,
+        seed: 0,
+        deadline: Duration::from_millis(BOT_DEADLINE_MS),
+        max_ticks: MATCH_TICK_LIMIT,
+        bot_difficulty: Difficulty::Normal,
+        players: Vec::new(),
+    };
+    let mut record = None;
+    let mut replay = None;
+
+    for arg in std::env::args().skip(1) {
+        let (key, value) = arg.split_once('=').unwrap_or((&arg, ""));
+        match key {
+            "bot" => config.players.push(PlayerSpec::External(value.to_string())),
+            "cpu" => config.players.push(PlayerSpec::Cpu),
+            "mode" => match GameMode::from_name(value) {
+                Some(mode) => config.mode = mode,
+                None => println!("Can't read '{}', ignoring it.", arg),
+            },
+            "bot_level" => match Difficulty::from_name(value) {
+                Some(difficulty) => config.bot_difficulty = difficulty,
+                None => println!("Can't read '{}', ignoring it.", arg),
+            },
+            "pack" => config.pack = LevelPack::new(value),
+            "level" => config.level = parse_option(&arg, value).unwrap_or(config.level),
+            "seed" => config.seed = parse_option(&arg, value).unwrap_or(config.seed),
+            "deadline" => {
+                if let Some(millis) = parse_option(&arg, value) {
+                    config.deadline = Duration::from_millis(millis);
+                }
+            }
+            "ticks" => config.max_ticks = parse_option(&arg, value).unwrap_or(config.max_ticks),
+            "record" => record = Some(value.to_string()),
+            "replay" => replay = Some(value.to_string()),
+            _ => println!("Unknown argument '{}', ignoring it.", arg),
+        }
+    }
+
+    if let Some(path) = replay {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(error) => {
+                println!("Can't read the replay '{}': {}", path, error);
+                return;
+            }
+        };
+        if let Some((config, actions)) = parse_replay(&text) {
+            let result = replay_match(&config, &actions);
+            print_result(&config, &result);
+        }
+        return;
+    }
+
+    if config.players.is_empty() {
+        println!("Nobody to play, give at least one bot=<command> or cpu.");
+        return;
+    }
+    // the ids the players get, CPU players last
+    config
+        .players
+        .sort_by_key(|spec| matches!(spec, PlayerSpec::Cpu));
+    config.players.truncate(MAX_PLAYERS);
+
+    let result = run_match(&config);
+    print_result(&config, &result);
+    if let Some(path) = record {
+        match fs::write(&path, replay_text(&config, &result)) {
+            Ok(()) => println!("Replay saved to {}.", path),
+            Err(error) => println!("Can't save the replay to '{}': {}", path, error),
+        }
+    }
+}
+
+fn print_result(config: &MatchConfig, result: &MatchResult) {
+    println!("Played {} ticks.", result.ticks);
+    for (index, spec) in config.players.iter().enumerate() {
+        let name = match spec {
+            PlayerSpec::External(command) => command.as_str(),
+            PlayerSpec::Cpu => "cpu",
+        };
+        println!(
+            "Player {} ({}): {} health, {} points",
+            index + 1,
+            name,
+            result.health[index],
+            result.points[index]
+        );
+    }
+    match result.winner {
+        Some(id) => println!("Player {} wins.", id.0),
+        None => println!("Nobody wins."),
+    }
+}
@@ -0,0 +1,38 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bomberman::constants::{SERVER_PORT, TICK_RATE};
+use bomberman::server_systems::Server;
+
+/// Runs battles for the clients who connect with `connect <address:port> [room]`, one room per
+/// name, and broadcasts them to the ones who `spectate`. Listens on the port given as the only
+/// argument.
+fn main() {
+    let port = match std::env::args().nth(1) {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) => port,
+            Err(_) => {
+                println!("Can't listen on port '{}'.", port);
+                return;
+            }
+        },
+        None => SERVER_PORT,
+    };
+
+    let mut server = match Server::bind(port) {
+        Ok(server) => server,
+        Err(error) => {
+            println!("Can't open a socket on port {}: {}", port, error);
+            return;
+        }
+    };
+    println!("Listening on port {}.", port);
+
+    let tick = Duration::from_secs(1) / TICK_RATE;
+    loop {
+        let start = Instant::now();
+        server.receive();
+        server.update(tick.as_secs_f32());
+        thread::sleep(tick.saturating_sub(start.elapsed()));
+    }
+}
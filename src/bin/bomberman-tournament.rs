@@ -0,0 +1,92 @@
+use std::fs;
+use std::time::Duration;
+
+use bomberman::components::LevelPack;
+use bomberman::constants::{BOT_DEADLINE_MS, MATCH_TICK_LIMIT};
+use bomberman::net_systems::parse_option;
+use bomberman::tournament_systems::{
+    matches_csv, matches_json, run_tournament, standings_csv, standings_json, standings_table,
+    TournamentConfig, TournamentFormat,
+};
+
+/// Plays the bots given as `bot=<command>` or `bot=tcp:<address:port>` against each other in
+/// battles, then prints the standings and writes them with the stats of every match to
+/// `<out>/standings.<csv|json>` and `<out>/matches.<csv|json>`. Also takes
+/// `format=<round_robin|bracket>`, `pack=<directory>`, `seed=<n>`, `deadline=<milliseconds>`,
+/// `ticks=<n>`, `out=<directory>`, `output=<csv|json>` and `replays=<directory>`, which defaults
+/// to `<out>/replays`.
+fn main() {
+    let mut config = TournamentConfig {
+        format: TournamentFormat::RoundRobin,
+        bots: Vec::new(),
+        pack: LevelPack::default(),
+        seed: 0,
+        deadline: Duration::from_millis(BOT_DEADLINE_MS),
+        max_ticks: MATCH_TICK_LIMIT,
+        replays: None,
+    };
+    let mut out = "tournament".to_string();
+    let mut json = false;
+
+    for arg in std::env::args().skip(1) {
+        let (key, value) = arg.split_once('=').unwrap_or((&arg, ""));
+        match key {
+            "bot" => config.bots.push(value.to_string()),
+            "format" => match TournamentFormat::from_name(value) {
+                Some(format) => config.format = format,
+                None => println!("Can't read '{}', ignoring it.", arg),
+            },
+            "output" => match value {
+                "csv" => json = false,
+                "json" => json = true,
+                _ => println!("Can't read '{}', ignoring it.", arg),
+            },
+            "pack" => config.pack = LevelPack::new(value),
+            "seed" => config.seed = parse_option(&arg, value).unwrap_or(config.seed),
+            "deadline" => {
+                if let Some(millis) = parse_option(&arg, value) {
+                    config.deadline = Duration::from_millis(millis);
+                }
+            }
+            "ticks" => config.max_ticks = parse_option(&arg, value).unwrap_or(config.max_ticks),
+            "out" => out = value.to_string(),
+            "replays" => config.replays = Some(value.to_string()),
+            _ => println!("Unknown argument '{}', ignoring it.", arg),
+        }
+    }
+    if config.bots.len() < 2 {
+        println!("A tournament needs at least two bots, give them as bot=<command>.");
+        return;
+    }
+    if config.replays.is_none() {
+        config.replays = Some(format!("{}/replays", out));
+    }
+    if let Err(error) = fs::create_dir_all(&out) {
+        println!("Can't create the directory '{}': {}", out, error);
+        return;
+    }
+
+    let (matches, standings) = run_tournament(&config);
+    println!("{}", standings_table(&config, &standings));
+
+    let (extension, standings_text, matches_text) = if json {
+        (
+            "json",
+            standings_json(&config, &standings),
+            matches_json(&config, &matches),
+        )
+    } else {
+        (
+            "csv",
+            standings_csv(&config, &standings),
+            matches_csv(&config, &matches),
+        )
+    };
+    for (name, text) in [("standings", standings_text), ("matches", matches_text)] {
+        let path = format!("{}/{}.{}", out, name, extension);
+        match fs::write(&path, text) {
+            Ok(()) => println!("Saved {}.", path),
+            Err(error) => println!("Can't save '{}': {}", path, error),
+        }
+    }
+}
@@ -5,65 +5,104 @@ use std::time::Duration;
 use crate::components::*;
 use crate::constants::*;
 use crate::enemy_systems::DAMAGE;
-use crate::utils::is_equal;
+use crate::utils::{cell_to_position, is_equal, position_to_cell};
+use bevy::ecs::system::EntityCommands;
 use bevy::prelude::*;
+use bevy::utils::HashSet;
 use bevy_tweening::lens::TransformScaleLens;
 use bevy_tweening::*;
 
 pub fn spawn_bomb_system(
-    key: Res<Input<KeyCode>>,
+    actions: Res<ActionInput>,
     mut commands: Commands,
-    query: Query<&Bomb>,
-    player_query: Query<&Transform, With<Player>>,
-    time: Res<Time>,
+    mut query: Query<(&mut Bomb, &Transform)>,
+    player_query: Query<(&GridCell, &Player, &PlayerId)>,
     textures: Res<GameTextures>,
 ) {
-    if query.is_empty() && key.just_pressed(KeyCode::Space) {
-        if let Ok(transform) = player_query.get_single() {
-            commands
-                .spawn_bundle(SpriteBundle {
-                    texture: textures.bomb.clone(),
-                    transform: Transform {
-                        translation: Vec3 {
-                            x: transform.translation.x,
-                            y: transform.translation.y,
-                            z: 2.,
-                        },
-                        ..Default::default()
-                    },
-                    ..Default::default()
-                })
-                .insert(Bomb {
-                    spawned: time.startup() + time.time_since_startup(),
-                })
-                .insert(Solid)
-                .insert(Animator::new(Tween::new(
-                    EaseFunction::BackInOut,
-                    TweeningType::Loop,
-                    Duration::from_millis(250),
-                    TransformScaleLens {
-                        start: transform.scale,
-                        end: Vec3 {
-                            x: transform.scale.x / 2.,
-                            y: transform.scale.y / 2.,
-                            z: transform.scale.z,
-                        },
-                    },
-                )));
+    for (cell, player, id) in player_query.iter() {
+        let placed = query.iter().filter(|bomb| bomb.0.owner == Some(*id)).count();
+        if placed < player.max_bombs
+            && actions.just_pressed(*id, Action::PlaceBomb)
+            && !query
+                .iter()
+                .any(|bomb| position_to_cell(bomb.1.translation) == cell.0)
+        {
+            // bombs go on the cell the player occupies, even halfway through a step
+            spawn_bomb(
+                &mut commands,
+                &textures,
+                &Transform::from_translation(cell_to_position(cell.0)),
+                Some(*id),
+            );
+        }
+
+        if actions.just_pressed(*id, Action::Detonate) {
+            query
+                .iter_mut()
+                .filter(|bomb| bomb.0.owner == Some(*id))
+                .for_each(|mut bomb| bomb.0.triggered = true);
         }
     }
 }
 
+pub fn spawn_bomb<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    textures: &GameTextures,
+    transform: &Transform,
+    owner: Option<PlayerId>,
+) -> EntityCommands<'w, 's, 'a> {
+    let mut bomb = commands.spawn_bundle(SpriteBundle {
+        texture: textures.bomb.clone(),
+        transform: Transform {
+            translation: Vec3 {
+                x: transform.translation.x,
+                y: transform.translation.y,
+                z: 2.,
+            },
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    bomb.insert(Bomb {
+        fuse: Timer::from_seconds(BOMB_TIMER, false),
+        owner,
+        triggered: false,
+    })
+    .insert(Solid)
+    .insert(bomb_pulse(transform.scale));
+    bomb
+}
+
+/// Shrinks and grows a bomb of the given scale until it goes off.
+pub fn bomb_pulse(scale: Vec3) -> Animator<Transform> {
+    Animator::new(Tween::new(
+        EaseFunction::BackInOut,
+        TweeningType::Loop,
+        Duration::from_millis(250),
+        TransformScaleLens {
+            start: scale,
+            end: Vec3 {
+                x: scale.x / 2.,
+                y: scale.y / 2.,
+                z: scale.z,
+            },
+        },
+    ))
+}
+
 pub fn detonate_bomb_system(
     mut commands: Commands,
-    query: Query<(&Bomb, Entity, &Transform), With<Bomb>>,
+    mut query: Query<(&mut Bomb, Entity, &Transform)>,
     wall_query: Query<&Transform, With<Wall>>,
-    time: Res<Time>,
+    time: Res<SimTime>,
     textures: Res<GameTextures>,
 ) {
+    for (mut bomb, _, _) in query.iter_mut() {
+        bomb.fuse.tick(time.delta());
+    }
+
     for bomb in query.iter() {
-        //println!("bomb.0.spawned.elapsed().as_secs() = {}", bomb.0.spawned.elapsed().as_secs());
-        if bomb.0.spawned.elapsed().as_secs() > BOMB_TIMER {
+        if bomb.0.triggered || bomb.0.fuse.finished() {
             commands.entity(bomb.1).despawn();
             //todo explosion
             //println!("Boom!");
@@ -100,7 +139,8 @@ pub fn detonate_bomb_system(
                     bomb.2.translation.x + CELL_SIZE * i as f32,
                     bomb.2.translation.y,
                     Explosion {
-                        spawned: time.startup() + time.time_since_startup(),
+                        timer: Timer::new(Duration::from_millis(EXPLOSION_DURATION as u64), false),
+                        owner: bomb.0.owner,
                     },
                     textures.explosion.clone(),
                     index,
@@ -127,7 +167,11 @@ pub fn detonate_bomb_system(
                         bomb.2.translation.x,
                         bomb.2.translation.y + CELL_SIZE * i as f32,
                         Explosion {
-                            spawned: time.startup() + time.time_since_startup(),
+                            timer: Timer::new(
+                                Duration::from_millis(EXPLOSION_DURATION as u64),
+                                false,
+                            ),
+                            owner: bomb.0.owner,
                         },
                         textures.explosion.clone(),
                         index,
@@ -143,6 +187,54 @@ pub fn detonate_bomb_system(
     }
 }
 
+/// Returns every cell the bomb's explosion is going to cover, stopping at walls the same way the explosion does.
+pub fn blast_zone(
+    bomb: &(&Bomb, Entity, &Transform),
+    wall_query: &Query<&Transform, With<Wall>>,
+) -> Vec<(i32, i32)> {
+    let (x, y) = position_to_cell(bomb.2.translation);
+
+    let right_boundary = find_boundary(0..=EXPLOSION_SIZE, wall_query, bomb, -1, (1, 0));
+    let left_boundary = find_boundary((-EXPLOSION_SIZE..=0).rev(), wall_query, bomb, 1, (1, 0));
+    let top_boundary = find_boundary(0..=EXPLOSION_SIZE, wall_query, bomb, -1, (0, 1));
+    let bottom_boundary = find_boundary((-EXPLOSION_SIZE..=0).rev(), wall_query, bomb, 1, (0, 1));
+
+    let mut cells: Vec<(i32, i32)> = (left_boundary..=right_boundary).map(|i| (x + i, y)).collect();
+    cells.extend(
+        (bottom_boundary..=top_boundary)
+            .filter(|i| *i != 0)
+            .map(|i| (x, y + i)),
+    );
+    cells
+}
+
+pub fn update_danger_map_system(
+    mut danger_map: ResMut<DangerMap>,
+    query: Query<(&Bomb, Entity, &Transform), With<Bomb>>,
+    wall_query: Query<&Transform, With<Wall>>,
+    explosion_query: Query<&Transform, With<Explosion>>,
+) {
+    danger_map.cells.clear();
+
+    for bomb in query.iter() {
+        let time_left = if bomb.0.triggered {
+            0.
+        } else {
+            (bomb.0.fuse.duration() - bomb.0.fuse.elapsed()).as_secs_f32()
+        };
+        for cell in blast_zone(&bomb, &wall_query) {
+            let entry = danger_map.cells.entry(cell).or_insert(time_left);
+            *entry = entry.min(time_left);
+        }
+    }
+
+    for explosion in explosion_query.iter() {
+        danger_map
+            .cells
+            .insert(position_to_cell(explosion.translation), 0.);
+    }
+}
+
 fn find_boundary<I>(
     range: I,
     wall_query: &Query<&Transform, With<Wall>>,
@@ -189,33 +281,78 @@ fn spawn_explosion(
         .insert(explosion);
 }
 
-pub fn remove_explosion_system(mut commands: Commands, query: Query<(&Explosion, Entity)>) {
-    for explosion in query.iter() {
-        if explosion.0.spawned.elapsed().as_millis() > 250 {
+pub fn remove_explosion_system(
+    mut commands: Commands,
+    mut query: Query<(&mut Explosion, Entity)>,
+    time: Res<SimTime>,
+) {
+    for mut explosion in query.iter_mut() {
+        explosion.0.timer.tick(time.delta());
+        if explosion.0.timer.finished() {
             commands.entity(explosion.1).despawn();
         }
     }
 }
 
+type DestructableQuery<'a> = (
+    &'a Transform,
+    Entity,
+    Option<&'a mut Enemy>,
+    Option<&'a GridCell>,
+    Option<&'a BreakableWall>,
+);
+
 pub fn explosion_destruction_system(
     mut commands: Commands,
-    query: Query<(&Transform, Entity), With<Destructable>>,
-    mut player_query: Query<(&Transform, &mut Player, &mut TextureAtlasSprite), With<Player>>,
-    time: Res<Time>,
-    explosion_query: Query<&Transform, With<Explosion>>,
+    mut query: Query<DestructableQuery, With<Destructable>>,
+    mut player_query: Query<HurtPlayerQuery, (With<Player>, Without<Invulnerable>)>,
+    time: Res<SimTime>,
+    explosion_query: Query<(&Transform, &Explosion)>,
+    archetypes: Res<EnemyArchetypes>,
+    mut scores: ResMut<Scores>,
 ) {
-    for explosion in explosion_query.iter() {
+    // overlapping explosions reach the same wall on the same frame, it only counts once
+    let mut destroyed: HashSet<Entity> = HashSet::new();
+
+    for (explosion, Explosion { owner, .. }) in explosion_query.iter() {
+        let explosion_cell = position_to_cell(explosion.translation);
         query
-            .iter()
-            .filter(|destructable| is_equal(destructable.0, explosion))
-            .for_each(|destructable| {
-                commands.entity(destructable.1).despawn();
+            .iter_mut()
+            .filter(|destructable| match destructable.3 {
+                Some(cell) => cell.0 == explosion_cell,
+                None => is_equal(destructable.0, explosion),
+            })
+            .for_each(|(_, entity, enemy, _, breakable)| match enemy {
+                Some(mut enemy) => {
+                    // one explosion lasts EXPLOSION_DURATION, so it only hurts an enemy once
+                    if time.since(enemy.last_hit).as_millis() > EXPLOSION_DURATION {
+                        enemy.health -= DAMAGE;
+                        enemy.last_hit = time.elapsed();
+                        if enemy.health <= 0 {
+                            commands.entity(entity).despawn();
+                            if let (Some(owner), Some(archetype)) =
+                                (owner, archetypes.list.get(enemy.archetype))
+                            {
+                                scores.add_kill(*owner, archetype.score);
+                            }
+                        }
+                    }
+                }
+                None => {
+                    if destroyed.insert(entity) {
+                        commands.entity(entity).despawn();
+                        if let Some(owner) = owner {
+                            let points = if breakable.is_some() { WALL_SCORE } else { SPAWNER_SCORE };
+                            scores.add(*owner, points);
+                        }
+                    }
+                }
             });
 
-        if let Ok(mut player) = player_query.get_single_mut() {
-            if is_equal(player.0, explosion) && player.1.last_hit.elapsed().as_millis() > 150 {
+        for mut player in player_query.iter_mut() {
+            if player.0 .0 == explosion_cell && time.since(player.1.last_hit).as_millis() > 150 {
                 player.1.health -= DAMAGE;
-                player.1.last_hit = time.startup() + time.time_since_startup();
+                player.1.last_hit = time.elapsed();
                 player.2.color = Color::RED;
             }
         }
@@ -0,0 +1,389 @@
+use std::time::Duration;
+
+use crate::bomb_systems::spawn_bomb;
+use crate::components::*;
+use crate::constants::*;
+use crate::enemy_systems::{spawn_enemy, DAMAGE, DIRECTIONS};
+use crate::utils::{cell_to_position, position_to_cell};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use bevy_tweening::lens::TransformPositionLens;
+use bevy_tweening::*;
+use rand::prelude::*;
+
+/// Attacks cycled through in every phase. A boss moves to the next phase each time it loses
+/// a third of its health.
+const BOSS_PHASES: [&[BossAttack]; 3] = [
+    &[BossAttack::SpawnMinions],
+    &[BossAttack::PlaceBombs, BossAttack::SpawnMinions],
+    &[
+        BossAttack::Charge,
+        BossAttack::PlaceBombs,
+        BossAttack::Charge,
+        BossAttack::SpawnMinions,
+    ],
+];
+
+/// Seconds between two attacks in every phase.
+const BOSS_ATTACK_INTERVALS: [f32; 3] = [5., 4., 2.5];
+
+pub fn spawn_boss(commands: &mut Commands, texture: Handle<Image>, x: i32, y: i32) -> Entity {
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture,
+            sprite: Sprite {
+                color: Color::rgb(0.8, 0.4, 1.),
+                custom_size: Some(Vec2::splat(CELL_SIZE * BOSS_SIZE as f32)),
+                ..Default::default()
+            },
+            transform: Transform {
+                translation: boss_translation((x, y)),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(GridCell((x, y)))
+        .insert(Boss {
+            health: BOSS_HEALTH,
+            last_hit: Duration::ZERO,
+            move_timer: Timer::from_seconds(1. / BOSS_SPEED, true),
+            attack_timer: Timer::from_seconds(BOSS_ATTACK_INTERVALS[0], false),
+            next_attack: 0,
+            charge: None,
+        })
+        .id()
+}
+
+/// Center of the sprite of a boss whose bottom left cell is `cell`.
+pub fn boss_translation(cell: (i32, i32)) -> Vec3 {
+    let mut translation = cell_to_position(cell);
+    translation.x += (BOSS_SIZE - 1) as f32 * CELL_OFFSET;
+    translation.y += (BOSS_SIZE - 1) as f32 * CELL_OFFSET;
+    translation
+}
+
+/// The cells covered by a boss whose bottom left cell is `cell`.
+pub fn boss_cells(cell: (i32, i32)) -> Vec<(i32, i32)> {
+    let (x, y) = cell;
+
+    (0..BOSS_SIZE)
+        .flat_map(|i| (0..BOSS_SIZE).map(move |j| (x + i, y + j)))
+        .collect()
+}
+
+fn boss_phase(boss: &Boss) -> usize {
+    let lost = BOSS_HEALTH - boss.health.max(1);
+    ((lost * BOSS_PHASES.len() as i32 / BOSS_HEALTH) as usize).min(BOSS_PHASES.len() - 1)
+}
+
+pub fn boss_movement_system(
+    mut query: Query<(&Transform, Entity, &mut GridCell, &mut Boss)>,
+    wall_query: Query<&Transform, (With<Solid>, Without<Enemy>)>,
+    time: Res<SimTime>,
+    mut random: ResMut<GameRng>,
+    mut commands: Commands,
+) {
+    let solids: HashSet<(i32, i32)> = wall_query
+        .iter()
+        .map(|wall| position_to_cell(wall.translation))
+        .collect();
+
+    for (transform, entity, mut cell, mut boss) in query.iter_mut() {
+        boss.move_timer.tick(time.delta());
+        if !boss.move_timer.just_finished() {
+            continue;
+        }
+
+        let cells = boss_cells(cell.0);
+        let free = |(dx, dy): (i32, i32)| {
+            cells
+                .iter()
+                .all(|(x, y)| !solids.contains(&(x + dx, y + dy)))
+        };
+
+        let charge = boss.charge;
+        let step = match charge {
+            Some(direction) if free(direction) => Some(direction),
+            Some(_) => {
+                boss.charge = None;
+                boss.move_timer = Timer::from_seconds(1. / BOSS_SPEED, true);
+                None
+            }
+            None => {
+                let options: Vec<(i32, i32)> =
+                    DIRECTIONS.iter().copied().filter(|direction| free(*direction)).collect();
+                options.choose(&mut *random).copied()
+            }
+        };
+
+        if let Some((dx, dy)) = step {
+            cell.0 = (cell.0 .0 + dx, cell.0 .1 + dy);
+            let mut end = boss_translation(cell.0);
+            end.z = transform.translation.z;
+            commands.entity(entity).insert(Animator::new(Tween::new(
+                EaseFunction::QuadraticIn,
+                TweeningType::Once,
+                // the boss glides from cell to cell at its current speed
+                boss.move_timer.duration(),
+                TransformPositionLens {
+                    start: transform.translation,
+                    end,
+                },
+            )));
+        }
+    }
+}
+
+/// What is around a boss, as far as its attacks need it.
+#[derive(SystemParam)]
+pub struct BossSurroundings<'w, 's> {
+    player_query: Query<'w, 's, &'static GridCell, With<Player>>,
+    solid_query: Query<'w, 's, (&'static Transform, Option<&'static GridCell>), With<Solid>>,
+    enemy_query: Query<'w, 's, &'static Enemy>,
+    archetypes: Res<'w, EnemyArchetypes>,
+}
+
+pub fn boss_attack_system(
+    mut commands: Commands,
+    mut boss_query: Query<(&GridCell, &mut Boss)>,
+    surroundings: BossSurroundings,
+    textures: Res<GameTextures>,
+    settings: Res<GameSettings>,
+    time: Res<SimTime>,
+    mut random: ResMut<GameRng>,
+) {
+    let BossSurroundings {
+        player_query,
+        solid_query,
+        enemy_query,
+        archetypes,
+    } = surroundings;
+    let solids: HashSet<(i32, i32)> = solid_query
+        .iter()
+        .map(|(solid, cell)| {
+            cell.map_or_else(|| position_to_cell(solid.translation), |cell| cell.0)
+        })
+        .collect();
+
+    for (boss_cell, mut boss) in boss_query.iter_mut() {
+        boss.attack_timer.tick(time.delta());
+        if !boss.attack_timer.finished() || boss.charge.is_some() {
+            continue;
+        }
+
+        let phase = boss_phase(&boss);
+        let attacks = BOSS_PHASES[phase];
+        let attack = attacks[boss.next_attack % attacks.len()];
+        boss.next_attack += 1;
+        boss.attack_timer = Timer::from_seconds(BOSS_ATTACK_INTERVALS[phase], false);
+
+        let cells = boss_cells(boss_cell.0);
+        match attack {
+            BossAttack::SpawnMinions => {
+                let archetype = match archetypes.list.first() {
+                    Some(archetype) => archetype,
+                    None => continue,
+                };
+                let room = BOSS_MAX_MINIONS.saturating_sub(enemy_query.iter().count());
+                let mut around: Vec<(i32, i32)> = cells
+                    .iter()
+                    .flat_map(|(x, y)| DIRECTIONS.iter().map(move |(dx, dy)| (x + dx, y + dy)))
+                    .filter(|cell| !cells.contains(cell) && !solids.contains(cell))
+                    .collect();
+                around.sort_unstable();
+                around.dedup();
+
+                for (x, y) in around
+                    .choose_multiple(&mut *random, BOSS_MINIONS_PER_WAVE.min(room))
+                {
+                    spawn_enemy(
+                        &mut commands,
+                        0,
+                        archetype,
+                        *x,
+                        *y,
+                        settings.difficulty.enemy_speed(),
+                    );
+                }
+            }
+            BossAttack::PlaceBombs => {
+                if let Some(player) = closest_player(boss_cell.0, &player_query) {
+                    let (x, y) = player.0;
+                    let targets: Vec<(i32, i32)> = std::iter::once((0, 0))
+                        .chain(DIRECTIONS.iter().copied())
+                        .map(|(dx, dy)| (x + dx, y + dy))
+                        .filter(|cell| !solids.contains(cell) && !in_blast_range(*cell, &cells))
+                        .collect();
+
+                    for cell in targets.choose_multiple(&mut *random, BOSS_BOMBS_PER_ATTACK) {
+                        spawn_bomb(
+                            &mut commands,
+                            &textures,
+                            &Transform::from_translation(cell_to_position(*cell)),
+                            None,
+                        );
+                    }
+                }
+            }
+            BossAttack::Charge => {
+                if let Some(player) = closest_player(boss_cell.0, &player_query) {
+                    let (dx, dy) = offset_from_boss(boss_cell.0, player.0);
+                    let direction = if dx.abs() > dy.abs() {
+                        (dx.signum(), 0)
+                    } else {
+                        (0, dy.signum())
+                    };
+                    boss.charge = Some(direction);
+                    boss.move_timer = Timer::from_seconds(1. / BOSS_CHARGE_SPEED, true);
+                }
+            }
+        }
+    }
+}
+
+/// Offset of `cell` from the middle of the boss, doubled so that it stays a whole number.
+fn offset_from_boss(boss: (i32, i32), cell: (i32, i32)) -> (i32, i32) {
+    let reach = BOSS_SIZE - 1;
+    (
+        2 * cell.0 - (2 * boss.0 + reach),
+        2 * cell.1 - (2 * boss.1 + reach),
+    )
+}
+
+fn closest_player<'a>(
+    boss: (i32, i32),
+    player_query: &'a Query<&GridCell, With<Player>>,
+) -> Option<&'a GridCell> {
+    player_query.iter().min_by_key(|player| {
+        let (dx, dy) = offset_from_boss(boss, player.0);
+        dx * dx + dy * dy
+    })
+}
+
+/// Whether a bomb at `cell` could reach any of the boss cells, ignoring walls.
+fn in_blast_range(cell: (i32, i32), boss_cells: &[(i32, i32)]) -> bool {
+    boss_cells.iter().any(|(x, y)| {
+        (cell.0 == *x && (cell.1 - y).abs() <= EXPLOSION_SIZE)
+            || (cell.1 == *y && (cell.0 - x).abs() <= EXPLOSION_SIZE)
+    })
+}
+
+pub fn boss_damage_system(
+    mut commands: Commands,
+    mut field: ResMut<Field>,
+    mut boss_query: Query<(&GridCell, Entity, &mut Boss, &mut Sprite)>,
+    mut player_query: Query<HurtPlayerQuery, (With<Player>, Without<Invulnerable>)>,
+    explosion_query: Query<(&Transform, &Explosion)>,
+    mut scores: ResMut<Scores>,
+    time: Res<SimTime>,
+) {
+    for (boss_cell, entity, mut boss, mut sprite) in boss_query.iter_mut() {
+        let cells = boss_cells(boss_cell.0);
+
+        let hit = explosion_query
+            .iter()
+            .find(|(explosion, _)| cells.contains(&position_to_cell(explosion.translation)));
+        // one explosion lasts EXPLOSION_DURATION, so it only hurts the boss once
+        if let Some((_, explosion)) =
+            hit.filter(|_| time.since(boss.last_hit).as_millis() > EXPLOSION_DURATION)
+        {
+            boss.health -= DAMAGE;
+            boss.last_hit = time.elapsed();
+            sprite.color = Color::RED;
+
+            if boss.health <= 0 {
+                println!("Boss defeated!");
+                field.boss_defeated = true;
+                if let Some(owner) = explosion.owner {
+                    scores.add(owner, BOSS_SCORE);
+                }
+                commands.entity(entity).despawn();
+                continue;
+            }
+        }
+
+        if time.since(boss.last_hit).as_millis() > 200 {
+            sprite.color = Color::rgb(0.8, 0.4, 1.);
+        }
+
+        for mut player in player_query.iter_mut() {
+            if cells.contains(&player.0 .0) && time.since(player.1.last_hit).as_millis() > 150 {
+                player.1.health -= BOSS_DAMAGE;
+                player.1.last_hit = time.elapsed();
+                player.2.color = Color::RED;
+            }
+        }
+    }
+}
+
+pub fn boss_health_bar_system(
+    mut commands: Commands,
+    boss_query: Query<&Boss>,
+    bar_query: Query<Entity, With<BossHealthBar>>,
+    mut fill_query: Query<&mut Style, With<BossHealthFill>>,
+    asset_server: Res<AssetServer>,
+) {
+    match (boss_query.iter().next(), bar_query.iter().next()) {
+        (Some(boss), Some(_)) => {
+            if let Ok(mut style) = fill_query.get_single_mut() {
+                let percent = boss.health.max(0) as f32 / BOSS_HEALTH as f32 * 100.;
+                if style.size.width != Val::Percent(percent) {
+                    style.size.width = Val::Percent(percent);
+                }
+            }
+        }
+        (Some(_), None) => spawn_boss_health_bar(&mut commands, &asset_server),
+        (None, Some(bar)) => commands.entity(bar).despawn_recursive(),
+        (None, None) => {}
+    }
+}
+
+fn spawn_boss_health_bar(commands: &mut Commands, asset_server: &AssetServer) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(HUD_HEIGHT + CELL_OFFSET),
+                    left: Val::Percent(25.),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(50.), Val::Px(CELL_OFFSET)),
+                ..default()
+            },
+            color: Color::rgb(0.2, 0.2, 0.2).into(),
+            ..default()
+        })
+        .insert(BossHealthBar)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                        ..default()
+                    },
+                    color: Color::rgb(0.8, 0.1, 0.1).into(),
+                    ..default()
+                })
+                .insert(BossHealthFill);
+            parent.spawn_bundle(
+                TextBundle::from_section(
+                    "BOSS",
+                    TextStyle {
+                        font: asset_server.load("FiraSans-Regular.ttf"),
+                        font_size: 14.,
+                        color: Color::WHITE,
+                    },
+                )
+                .with_style(Style {
+                    position_type: PositionType::Absolute,
+                    position: UiRect {
+                        left: Val::Px(4.),
+                        ..default()
+                    },
+                    ..default()
+                }),
+            );
+        });
+}
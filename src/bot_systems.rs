@@ -0,0 +1,393 @@
+use std::collections::VecDeque;
+
+use crate::boss_systems::boss_cells;
+use crate::components::*;
+use crate::constants::*;
+use crate::player_systems::MOVES;
+use crate::utils::position_to_cell;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use rand::Rng;
+
+/// Players within this many steps of a bomb are checked for a way out of it by hard bots.
+const TRAP_RANGE: i32 = 4;
+
+/// What a bot sees of the level when it decides.
+struct BotView {
+    /// Stop explosions.
+    walls: HashSet<(i32, i32)>,
+    /// Can't be walked through, including bombs, enemies and bosses.
+    solids: HashSet<(i32, i32)>,
+    /// Breakable walls and spawners.
+    breakables: HashSet<(i32, i32)>,
+    /// Seconds until every cell that is about to burn does, 0 for the ones on fire.
+    danger: HashMap<(i32, i32), f32>,
+    enemies: HashSet<(i32, i32)>,
+    pickups: HashSet<(i32, i32)>,
+    players: Vec<(PlayerId, (i32, i32))>,
+    bombs: Vec<(Option<PlayerId>, (i32, i32))>,
+    mode: GameMode,
+}
+
+impl BotView {
+    /// Enemies in the campaign, the other players in a battle.
+    fn targets(&self, me: PlayerId) -> Vec<(i32, i32)> {
+        match self.mode {
+            GameMode::Campaign => self.enemies.iter().copied().collect(),
+            GameMode::Battle => self.others(me),
+        }
+    }
+
+    /// Players the bot must not blow up, the others in the campaign.
+    fn allies(&self, me: PlayerId) -> Vec<(i32, i32)> {
+        match self.mode {
+            GameMode::Campaign => self.others(me),
+            GameMode::Battle => Vec::new(),
+        }
+    }
+
+    fn others(&self, me: PlayerId) -> Vec<(i32, i32)> {
+        self.players
+            .iter()
+            .filter(|(id, _)| *id != me)
+            .map(|(_, cell)| *cell)
+            .collect()
+    }
+}
+
+type BotPlayerQuery<'a> = (&'a PlayerId, &'a GridCell, &'a Player, &'a Speed);
+type SolidQuery<'a> = (&'a Transform, Option<&'a GridCell>, Option<&'a Wall>);
+type Breakable = Or<(With<BreakableWall>, With<Spawner>)>;
+
+/// The level around the players, which `BotView` is made from.
+#[derive(SystemParam)]
+pub struct BotSurroundings<'w, 's> {
+    danger_map: Res<'w, DangerMap>,
+    solid_query: Query<'w, 's, SolidQuery<'static>, With<Solid>>,
+    breakable_query: Query<'w, 's, &'static Transform, Breakable>,
+    enemy_query: Query<'w, 's, &'static GridCell, With<Enemy>>,
+    boss_query: Query<'w, 's, &'static GridCell, With<Boss>>,
+    pickup_query: Query<'w, 's, &'static Transform, With<SpeedPickup>>,
+    bomb_query: Query<'w, 's, (&'static Bomb, &'static Transform)>,
+}
+
+/// Presses the actions of the players `GameSettings::bots` hands to the computer, the way a
+/// player at the keyboard would.
+pub fn bot_system(
+    state: Res<State<AppState>>,
+    settings: Res<GameSettings>,
+    time: Res<SimTime>,
+    mut bots: ResMut<Bots>,
+    mut actions: ResMut<ActionInput>,
+    player_query: Query<BotPlayerQuery>,
+    surroundings: BotSurroundings,
+) {
+    if settings.bots == 0 {
+        return;
+    }
+    // a bot's actions are its own, whatever is bound to them
+    actions.pressed.retain(|(id, _)| !settings.is_bot(*id));
+    if *state.current() != AppState::Playing {
+        bots.brains.clear();
+        return;
+    }
+
+    let BotSurroundings {
+        danger_map,
+        solid_query,
+        breakable_query,
+        enemy_query,
+        boss_query,
+        pickup_query,
+        bomb_query,
+    } = surroundings;
+    let mut view = BotView {
+        walls: HashSet::default(),
+        solids: HashSet::default(),
+        breakables: breakable_query
+            .iter()
+            .map(|transform| position_to_cell(transform.translation))
+            .collect(),
+        danger: danger_map.cells.clone(),
+        enemies: enemy_query.iter().map(|cell| cell.0).collect(),
+        pickups: pickup_query
+            .iter()
+            .map(|transform| position_to_cell(transform.translation))
+            .collect(),
+        players: player_query
+            .iter()
+            .map(|(id, cell, ..)| (*id, cell.0))
+            .collect(),
+        bombs: bomb_query
+            .iter()
+            .map(|(bomb, transform)| (bomb.owner, position_to_cell(transform.translation)))
+            .collect(),
+        mode: settings.mode,
+    };
+    for (transform, grid_cell, wall) in solid_query.iter() {
+        let cell = grid_cell.map_or_else(|| position_to_cell(transform.translation), |cell| cell.0);
+        view.solids.insert(cell);
+        if wall.is_some() {
+            view.walls.insert(cell);
+        }
+    }
+    for boss in boss_query.iter() {
+        for cell in boss_cells(boss.0) {
+            view.enemies.insert(cell);
+            view.solids.insert(cell);
+        }
+    }
+    // bombs placed since the danger map was last updated are just as dangerous
+    for (bomb, transform) in bomb_query.iter() {
+        let time_left = if bomb.triggered {
+            0.
+        } else {
+            (bomb.fuse.duration() - bomb.fuse.elapsed()).as_secs_f32()
+        };
+        for cell in blast_cells(position_to_cell(transform.translation), &view.walls) {
+            let entry = view.danger.entry(cell).or_insert(time_left);
+            *entry = entry.min(time_left);
+        }
+    }
+
+    let difficulty = settings.bot_difficulty;
+    let Bots { brains, rng } = &mut *bots;
+    for (id, cell, player, speed) in player_query.iter() {
+        if !settings.is_bot(*id) {
+            continue;
+        }
+        let brain = brains.entry(*id).or_default();
+        brain.think.tick(time.delta());
+        if brain.path.front() == Some(&cell.0) {
+            brain.path.pop_front();
+        }
+        let lost = brain
+            .path
+            .front()
+            .is_some_and(|next| direction(cell.0, *next).is_none());
+
+        if brain.think.finished() || lost {
+            brain.think = Timer::from_seconds(difficulty.bot_think_interval(), false);
+            let placed = view
+                .bombs
+                .iter()
+                .filter(|(owner, _)| *owner == Some(*id))
+                .count();
+            let step = speed.step_duration(false).as_secs_f32();
+            let (path, pressed) = if rng.gen_bool(difficulty.bot_blunder_chance()) {
+                (wander(&view, cell.0, rng), Vec::new())
+            } else {
+                think(
+                    &view,
+                    *id,
+                    cell.0,
+                    placed < player.max_bombs,
+                    step,
+                    difficulty,
+                    rng,
+                )
+            };
+            brain.path = path;
+            for action in pressed {
+                actions.press(*id, action);
+            }
+        }
+
+        if let Some(action) = brain.path.front().and_then(|next| direction(cell.0, *next)) {
+            actions.press(*id, action);
+        }
+    }
+}
+
+/// Picks where the bot walks next and what else it presses: out of danger first, then a bomb
+/// it can get away from, then the way to the nearest pickup or cell worth bombing.
+fn think(
+    view: &BotView,
+    me: PlayerId,
+    cell: (i32, i32),
+    can_bomb: bool,
+    step: f32,
+    difficulty: Difficulty,
+    rng: &mut GameRng,
+) -> (VecDeque<(i32, i32)>, Vec<Action>) {
+    if view.danger.contains_key(&cell) {
+        let path = escape_path(cell, step, &view.danger, &view.solids);
+        return (path.unwrap_or_default(), Vec::new());
+    }
+
+    let mut pressed = Vec::new();
+    let targets = view.targets(me);
+    // hard bots set off their bombs as soon as somebody else is in the way
+    if difficulty == Difficulty::Hard {
+        let caught = view
+            .bombs
+            .iter()
+            .filter(|(owner, _)| *owner == Some(me))
+            .flat_map(|(_, bomb)| blast_cells(*bomb, &view.walls))
+            .any(|blast| targets.contains(&blast));
+        if caught {
+            pressed.push(Action::Detonate);
+        }
+    }
+
+    let bomb_here = view.bombs.iter().any(|(_, bomb)| *bomb == cell);
+    if can_bomb && !bomb_here && worth_bombing(view, me, cell, difficulty) {
+        let mut danger = view.danger.clone();
+        for blast in blast_cells(cell, &view.walls) {
+            let entry = danger.entry(blast).or_insert(BOMB_TIMER);
+            *entry = entry.min(BOMB_TIMER);
+        }
+        let mut solids = view.solids.clone();
+        solids.insert(cell);
+        if let Some(path) = escape_path(cell, step, &danger, &solids) {
+            pressed.push(Action::PlaceBomb);
+            return (path, pressed);
+        }
+    }
+
+    let path = search(
+        &view.solids,
+        cell,
+        |next, _| !view.danger.contains_key(&next),
+        |next| {
+            next != cell
+                && (view.pickups.contains(&next) || worth_bombing(view, me, next, difficulty))
+        },
+    );
+    (path.unwrap_or_else(|| wander(view, cell, rng)), pressed)
+}
+
+/// Whether a bomb on the cell breaks a wall or hits a target without hurting an ally. Easy bots
+/// don't go after targets, they only bomb the ones that happen to be in the way.
+fn worth_bombing(view: &BotView, me: PlayerId, cell: (i32, i32), difficulty: Difficulty) -> bool {
+    let blast = blast_cells(cell, &view.walls);
+    if view.allies(me).iter().any(|ally| blast.contains(ally)) {
+        return false;
+    }
+    let targets = view.targets(me);
+    blast.iter().any(|blast| view.breakables.contains(blast))
+        || (difficulty != Difficulty::Easy && targets.iter().any(|target| blast.contains(target)))
+        || (difficulty == Difficulty::Hard && traps(view, me, cell, &blast))
+}
+
+/// Whether a bomb on the cell leaves another player nowhere to run.
+fn traps(view: &BotView, me: PlayerId, cell: (i32, i32), blast: &[(i32, i32)]) -> bool {
+    let nearby: Vec<(i32, i32)> = match view.mode {
+        GameMode::Battle => view
+            .others(me)
+            .into_iter()
+            .filter(|other| (other.0 - cell.0).abs() + (other.1 - cell.1).abs() <= TRAP_RANGE)
+            .collect(),
+        GameMode::Campaign => Vec::new(),
+    };
+    if nearby.is_empty() {
+        return false;
+    }
+
+    let mut danger = view.danger.clone();
+    for blast in blast {
+        danger.entry(*blast).or_insert(BOMB_TIMER);
+    }
+    let mut solids = view.solids.clone();
+    solids.insert(cell);
+
+    let step = 1. / PLAYER_SPEED;
+    nearby
+        .into_iter()
+        .any(|other| escape_path(other, step, &danger, &solids).is_none())
+}
+
+/// The shortest way to a cell no bomb is going to hit, through cells that don't burn before
+/// the bot is past them.
+fn escape_path(
+    cell: (i32, i32),
+    step: f32,
+    danger: &HashMap<(i32, i32), f32>,
+    solids: &HashSet<(i32, i32)>,
+) -> Option<VecDeque<(i32, i32)>> {
+    search(
+        solids,
+        cell,
+        |next, steps| {
+            danger
+                .get(&next)
+                .is_none_or(|time| *time > (steps + 1) as f32 * step)
+        },
+        |next| !danger.contains_key(&next),
+    )
+}
+
+/// Breadth first search for the nearest cell that is a goal, returning the cells to walk
+/// through without the one the search starts on.
+fn search(
+    solids: &HashSet<(i32, i32)>,
+    start: (i32, i32),
+    enter: impl Fn((i32, i32), usize) -> bool,
+    goal: impl Fn((i32, i32)) -> bool,
+) -> Option<VecDeque<(i32, i32)>> {
+    let mut came_from: HashMap<(i32, i32), (i32, i32)> = HashMap::default();
+    let mut queue = VecDeque::from([(start, 0)]);
+    came_from.insert(start, start);
+
+    while let Some((cell, steps)) = queue.pop_front() {
+        if goal(cell) {
+            let mut path = VecDeque::new();
+            let mut current = cell;
+            while current != start {
+                path.push_front(current);
+                current = came_from[&current];
+            }
+            return Some(path);
+        }
+        for (_, (dx, dy), _) in MOVES {
+            let next = (cell.0 + dx, cell.1 + dy);
+            if walkable(solids, next) && !came_from.contains_key(&next) && enter(next, steps) {
+                came_from.insert(next, cell);
+                queue.push_back((next, steps + 1));
+            }
+        }
+    }
+    None
+}
+
+/// A step to a random neighbour that isn't about to burn, or staying put.
+fn wander(view: &BotView, cell: (i32, i32), rng: &mut GameRng) -> VecDeque<(i32, i32)> {
+    let free: Vec<(i32, i32)> = MOVES
+        .iter()
+        .map(|(_, (dx, dy), _)| (cell.0 + dx, cell.1 + dy))
+        .filter(|next| walkable(&view.solids, *next) && !view.danger.contains_key(next))
+        .collect();
+    match free.len() {
+        0 => VecDeque::new(),
+        count => VecDeque::from([free[rng.gen_range(0..count)]]),
+    }
+}
+
+fn walkable(solids: &HashSet<(i32, i32)>, cell: (i32, i32)) -> bool {
+    let size = SIZE_IN_CELLS as i32;
+    (0..size).contains(&cell.0) && (0..size).contains(&cell.1) && !solids.contains(&cell)
+}
+
+/// The cells a bomb on `cell` sets on fire, stopping at walls like `blast_zone`.
+fn blast_cells(cell: (i32, i32), walls: &HashSet<(i32, i32)>) -> Vec<(i32, i32)> {
+    let mut cells = vec![cell];
+    for (_, (dx, dy), _) in MOVES {
+        for distance in 1..=EXPLOSION_SIZE {
+            let next = (cell.0 + dx * distance, cell.1 + dy * distance);
+            if walls.contains(&next) {
+                break;
+            }
+            cells.push(next);
+        }
+    }
+    cells
+}
+
+/// The move action from a cell to a neighbouring one.
+fn direction(from: (i32, i32), to: (i32, i32)) -> Option<Action> {
+    MOVES
+        .iter()
+        .find(|(_, (dx, dy), _)| (from.0 + dx, from.1 + dy) == to)
+        .map(|(action, _, _)| *action)
+}
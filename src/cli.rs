@@ -0,0 +1,206 @@
+use std::str::FromStr;
+
+use crate::components::*;
+use crate::constants::*;
+
+pub const HELP: &str = "\
+Usage: bomberman [options] [host <port> | join <address:port> | connect <address:port> [room]
+                            | spectate <address:port> [room] [delay=<seconds>]]
+
+Options:
+  --level <n|file>            start on level or arena n, or play a level file
+  --pack <directory>          read the levels and arenas from another directory
+  --seed <n>                  play with the random numbers of this seed
+  --mode <campaign|battle>    start a game of this mode right away
+  --players <n>               number of players, 1 to 4
+  --headless                  play without a window, every player a CPU bot
+  --window <width>x<height>   scale the window to fit this size
+  --fullscreen                play in full screen
+  --assets <directory>        read the textures, definitions and levels from another directory
+  -h, --help                  show this help";
+
+/// What the game was started with.
+#[derive(Clone, Debug, Default)]
+pub struct CliOptions {
+    pub level: Option<u32>,
+    pub level_file: Option<String>,
+    pub pack: Option<String>,
+    pub seed: Option<u64>,
+    pub mode: Option<GameMode>,
+    pub players: Option<usize>,
+    pub headless: bool,
+    /// Width and height in pixels.
+    pub window: Option<(f32, f32)>,
+    pub fullscreen: bool,
+    pub assets: Option<String>,
+    pub help: bool,
+    /// The arguments that aren't options, an online game to host, join, connect to or spectate.
+    pub commands: Vec<String>,
+}
+
+impl CliOptions {
+    /// Reads the options given as `--name value` or `--name=value`, ignoring the ones that can't be
+    /// read.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut options = CliOptions::default();
+        let mut args = args.into_iter().peekable();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                options.commands.push(arg);
+                continue;
+            }
+
+            let (name, inline) = match arg.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            // an option missing its value doesn't take the next option for it
+            let mut value = || {
+                inline
+                    .clone()
+                    .or_else(|| args.next_if(|next| !next.starts_with('-')))
+                    .unwrap_or_default()
+            };
+            match name.as_str() {
+                "-h" | "--help" => options.help = true,
+                "--headless" => options.headless = true,
+                "--fullscreen" => options.fullscreen = true,
+                "--level" => {
+                    let value = value();
+                    match value.parse::<u32>() {
+                        Ok(0) => println!("Levels start at 1, ignoring '--level 0'."),
+                        Ok(level) => options.level = Some(level),
+                        Err(_) if value.is_empty() => println!("Give '--level' a level or a file."),
+                        // anything that isn't a number is a level file
+                        Err(_) => options.level_file = Some(value),
+                    }
+                }
+                "--pack" => options.pack = Some(value()),
+                "--assets" => options.assets = Some(value()),
+                "--seed" => options.seed = parse_value(&name, &value()),
+                "--mode" => {
+                    let value = value();
+                    options.mode = GameMode::from_name(&value);
+                    if options.mode.is_none() {
+                        println!("Can't read '--mode {}', ignoring it.", value);
+                    }
+                }
+                "--players" => {
+                    let value = value();
+                    let players: Option<usize> = parse_value(&name, &value);
+                    options.players = players.filter(|players| (1..=MAX_PLAYERS).contains(players));
+                    if players.is_some() && options.players.is_none() {
+                        println!("Between 1 and {} players can play.", MAX_PLAYERS);
+                    }
+                }
+                "--window" => {
+                    let value = value();
+                    options.window = value
+                        .split_once('x')
+                        .and_then(|(width, height)| {
+                            Some((width.parse().ok()?, height.parse().ok()?))
+                        })
+                        .filter(|(width, height): &(f32, f32)| *width > 0. && *height > 0.);
+                    if options.window.is_none() {
+                        println!("Can't read '--window {}', ignoring it.", value);
+                    }
+                }
+                _ => println!("Unknown option '{}', ignoring it, see --help.", arg),
+            }
+        }
+        options
+    }
+
+    /// The levels to play, from the pack and the level file given.
+    pub fn level_pack(&self) -> LevelPack {
+        let mut pack = match &self.pack {
+            Some(dir) => LevelPack::new(dir),
+            None => LevelPack::default(),
+        };
+        pack.file = self.level_file.clone();
+        pack
+    }
+
+    /// Level a new campaign or battle starts on, a level file being level 1.
+    pub fn first_level(&self) -> u32 {
+        match self.level_file {
+            Some(_) => 1,
+            None => self.level.unwrap_or(1),
+        }
+    }
+
+    /// Players in a game of `mode`, at least two in a battle.
+    pub fn player_count(&self, mode: GameMode) -> usize {
+        let players = self.players.unwrap_or(1);
+        match mode {
+            GameMode::Campaign => players,
+            GameMode::Battle => players.max(2),
+        }
+    }
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Option<T> {
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        println!("Can't read '{} {}', ignoring it.", name, value);
+    }
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> CliOptions {
+        CliOptions::parse(args.split_whitespace().map(str::to_string))
+    }
+
+    #[test]
+    fn reads_options_and_commands() {
+        let options = parse(
+            "--level 3 --seed=42 --mode battle --players 3 --headless --window 800x600 \
+             --pack packs/extra host 4000",
+        );
+
+        assert_eq!(options.level, Some(3));
+        assert_eq!(options.seed, Some(42));
+        assert_eq!(options.mode, Some(GameMode::Battle));
+        assert_eq!(options.players, Some(3));
+        assert!(options.headless);
+        assert_eq!(options.window, Some((800., 600.)));
+        assert_eq!(options.pack.as_deref(), Some("packs/extra"));
+        assert_eq!(options.commands, ["host", "4000"]);
+        assert_eq!(options.first_level(), 3);
+    }
+
+    #[test]
+    fn a_level_that_isnt_a_number_is_a_file() {
+        let options = parse("--level=levels/custom.txt --level 4");
+
+        assert_eq!(options.level_file.as_deref(), Some("levels/custom.txt"));
+        assert_eq!(options.level, Some(4));
+        assert_eq!(options.first_level(), 1);
+    }
+
+    #[test]
+    fn an_option_without_a_value_leaves_the_next_option() {
+        let options = parse("--level --seed 3 --mode");
+
+        assert_eq!(options.level, None);
+        assert_eq!(options.level_file, None);
+        assert_eq!(options.seed, Some(3));
+        assert_eq!(options.mode, None);
+    }
+
+    #[test]
+    fn ignores_values_it_cant_read() {
+        let options = parse("--level 0 --players 9 --mode race --window 0x600 --seed x --what");
+
+        assert_eq!(options.level, None);
+        assert_eq!(options.players, None);
+        assert_eq!(options.mode, None);
+        assert_eq!(options.window, None);
+        assert_eq!(options.seed, None);
+        assert_eq!(options.player_count(GameMode::Battle), 2);
+    }
+}
@@ -0,0 +1,531 @@
+use std::f32::consts::FRAC_PI_2;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+use crate::bomb_systems::bomb_pulse;
+use crate::boss_systems::boss_translation;
+use crate::components::*;
+use crate::constants::*;
+use crate::net_systems::{parse_option, read_input};
+use crate::player_systems::PLAYER_COLORS;
+use crate::server_systems::{
+    decode_state, send, ACK, INPUT, JOIN, NO_TICK, ROOM_FULL, SPECTATOR_ID, STATE, WATCH, WELCOME,
+};
+use crate::state_systems::{change_state, report_state_error, Overlays};
+use crate::utils::cell_to_position;
+use bevy::prelude::*;
+use bevy_tweening::lens::TransformPositionLens;
+use bevy_tweening::*;
+
+type SpectatorCamera<'a> = (&'a mut Transform, &'a mut OrthographicProjection);
+type MirroredQuery<'a> = (&'a Transform, Option<&'a mut TextureAtlasSprite>);
+type RemoteEntities = Or<(With<Mirrored>, With<RemoteStatus>)>;
+
+/// Connects to a dedicated server from the `connect <address:port> [room]` arguments, or watches a
+/// room of one from `spectate <address:port> [room] [delay=<seconds>]`.
+pub fn connection_from_args(args: &[String]) -> Option<ServerConnection> {
+    let mut args = args.iter().cloned();
+    let spectator = match args.next().as_deref() {
+        Some("connect") => false,
+        Some("spectate") => true,
+        _ => return None,
+    };
+
+    let address = match args.next() {
+        Some(address) => address,
+        None => {
+            println!(
+                "Use 'connect <address:port> [room]' or 'spectate <address:port> [room] [delay=<seconds>]'."
+            );
+            return None;
+        }
+    };
+    let server = match address
+        .to_socket_addrs()
+        .ok()
+        .and_then(|mut found| found.next())
+    {
+        Some(server) => server,
+        None => {
+            println!("Can't find the server '{}'.", address);
+            return None;
+        }
+    };
+    let mut room = "lobby".to_string();
+    let mut delay = if spectator { SPECTATOR_DELAY } else { 0. };
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("delay", value)) if spectator => {
+                if let Some(seconds) = parse_option::<f32>(&arg, value) {
+                    delay = seconds.max(0.);
+                }
+            }
+            Some(_) => println!("Unknown argument '{}', ignoring it.", arg),
+            None => room = arg,
+        }
+    }
+
+    let socket = match UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(error) => {
+            println!("Can't open a socket: {}", error);
+            return None;
+        }
+    };
+    if spectator {
+        println!(
+            "Watching {}, room '{}', {} seconds behind.",
+            server, room, delay
+        );
+    } else {
+        println!("Connecting to {}, room '{}'.", server, room);
+    }
+    let mut connection = ServerConnection::new(socket, server, room, spectator);
+    connection.delay = (delay * TICK_RATE as f32).round() as u32;
+    Some(connection)
+}
+
+/// Joins the room, reads the states the server sends and gives up on a server that went silent.
+pub fn client_receive_system(
+    mut commands: Commands,
+    connection: Option<ResMut<ServerConnection>>,
+    time: Res<Time>,
+    mut state: ResMut<State<AppState>>,
+) {
+    let mut connection = match connection {
+        Some(connection) => connection,
+        None => return,
+    };
+
+    if !connection.welcomed {
+        connection.hello_timer.tick(time.delta());
+        if connection.hello_timer.just_finished() {
+            let mut join = vec![if connection.spectator { WATCH } else { JOIN }];
+            join.extend(connection.room.as_bytes());
+            send(&connection.socket, connection.server, &join);
+        }
+    }
+
+    let mut buffer = vec![0; u16::MAX as usize];
+    let mut heard = false;
+    while let Ok((length, sender)) = connection.socket.recv_from(&mut buffer) {
+        if sender != connection.server {
+            continue;
+        }
+        heard = true;
+        let packet = &buffer[..length];
+        match packet.first() {
+            Some(&WELCOME) => match packet.get(1) {
+                Some(&SPECTATOR_ID) if connection.spectator => {
+                    if !connection.welcomed {
+                        println!("Watching room '{}'.", connection.room);
+                    }
+                    connection.welcomed = true;
+                }
+                Some(id) if !connection.spectator => {
+                    let id = PlayerId(*id as usize);
+                    if connection.id != Some(id) {
+                        println!("Playing in room '{}' as player {}.", connection.room, id.0);
+                    }
+                    connection.id = Some(id);
+                    connection.welcomed = true;
+                }
+                _ => {}
+            },
+            Some(&ROOM_FULL) => {
+                println!(
+                    "Room '{}' is full, or the server has no room left.",
+                    connection.room
+                );
+                commands.remove_resource::<ServerConnection>();
+                // back to the menu of a local game
+                report_state_error(state.restart());
+                return;
+            }
+            Some(&STATE) => {
+                let newest = connection.states.back().map(|state| state.tick);
+                match decode_state(&packet[1..], &connection.states) {
+                    // states overtaken by a later one on the way are dropped
+                    Some(room) if newest.is_none_or(|newest| room.tick > newest) => {
+                        connection.states.push_back(room);
+                        if connection.states.len() > STATE_HISTORY + connection.delay as usize {
+                            connection.states.pop_front();
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if connection.welcomed && *state.current() == AppState::MainMenu {
+        change_state(&mut state, AppState::Remote);
+    }
+
+    connection.silence = if heard {
+        0.
+    } else {
+        connection.silence + time.delta_seconds()
+    };
+    if connection.silence > PEER_TIMEOUT {
+        println!("Lost the connection to the server.");
+        commands.remove_resource::<ServerConnection>();
+        match state.current() {
+            AppState::MainMenu => report_state_error(state.restart()),
+            _ => change_state(&mut state, AppState::MainMenu),
+        }
+    }
+}
+
+/// Sends the actions of the local player every frame, acknowledging the newest state. Spectators
+/// only acknowledge.
+pub fn client_send_system(connection: Option<ResMut<ServerConnection>>, actions: Res<ActionInput>) {
+    let mut connection = match connection {
+        Some(connection) if connection.welcomed => connection,
+        _ => return,
+    };
+
+    let ack = connection.states.back().map_or(NO_TICK, |state| state.tick);
+    if connection.spectator {
+        let mut packet = vec![ACK];
+        packet.extend(ack.to_le_bytes());
+        send(&connection.socket, connection.server, &packet);
+        return;
+    }
+    let mut packet = vec![INPUT];
+    packet.extend(ack.to_le_bytes());
+    packet.extend(connection.sequence.to_le_bytes());
+    // the local keyboard steers player 1, whatever id the server gave
+    packet.push(read_input(&actions, PlayerId(1)));
+    send(&connection.socket, connection.server, &packet);
+    connection.sequence += 1;
+}
+
+pub fn spawn_remote_status_system(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "",
+                TextStyle {
+                    font: asset_server.load("FiraSans-Regular.ttf"),
+                    font_size: 16.,
+                    color: Color::WHITE,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(CELL_OFFSET),
+                    left: Val::Px(CELL_OFFSET),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(RemoteStatus);
+}
+
+pub fn despawn_remote_system(
+    mut commands: Commands,
+    connection: Option<ResMut<ServerConnection>>,
+    query: Query<Entity, RemoteEntities>,
+    mut camera_query: Query<SpectatorCamera, (With<Camera2d>, Without<Mirrored>)>,
+) {
+    query.for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
+    for (mut transform, mut projection) in camera_query.iter_mut() {
+        reset_camera(&mut transform, &mut projection);
+    }
+    if let Some(mut connection) = connection {
+        connection.mirror.clear();
+        connection.shown = None;
+    }
+}
+
+/// Draws the newest state of the room, or the delayed one of a spectator, spawning, moving and
+/// despawning entities to match it.
+pub fn mirror_system(
+    mut commands: Commands,
+    connection: Option<ResMut<ServerConnection>>,
+    textures: Res<GameTextures>,
+    archetypes: Res<EnemyArchetypes>,
+    mut overlays: Overlays,
+    mut mirror_query: Query<MirroredQuery, With<Mirrored>>,
+    mut status_query: Query<&mut Text, With<RemoteStatus>>,
+) {
+    let mut connection = match connection {
+        Some(connection) => connection,
+        None => return,
+    };
+    let newest = match connection.displayed() {
+        Some(newest) if connection.shown != Some(newest.tick) => newest.clone(),
+        _ => return,
+    };
+    let shown = connection
+        .shown
+        .and_then(|tick| connection.states.iter().find(|state| state.tick == tick))
+        .cloned();
+
+    if shown.as_ref().map(|shown| shown.state) != Some(newest.state) {
+        let text = match newest.state {
+            AppState::MainMenu => Some("Waiting for more players to join..."),
+            AppState::Loading => Some("Get ready!"),
+            AppState::LevelComplete => Some("Round over!"),
+            _ => None,
+        };
+        match text {
+            Some(text) => overlays.replace(text.to_string()),
+            None => overlays.clear(),
+        }
+    }
+
+    let gone: Vec<u64> = connection
+        .mirror
+        .keys()
+        .filter(|id| !newest.entities.contains_key(*id))
+        .copied()
+        .collect();
+    for id in gone {
+        if let Some(entity) = connection.mirror.remove(&id) {
+            commands.entity(entity).despawn();
+        }
+    }
+
+    for (id, net) in newest.entities.iter() {
+        let entity = match connection.mirror.get(id) {
+            Some(entity) => *entity,
+            None => {
+                let entity = spawn_mirrored(&mut commands, &textures, &archetypes, *id, net);
+                connection.mirror.insert(*id, entity);
+                continue;
+            }
+        };
+        let previous = shown.as_ref().and_then(|shown| shown.entities.get(id));
+        if previous == Some(net) {
+            continue;
+        }
+        if let Ok((transform, sprite)) = mirror_query.get_mut(entity) {
+            if let Some(mut sprite) = sprite {
+                sprite.index = net.frame as usize;
+            }
+            let mut end = mirrored_translation(net);
+            end.z = transform.translation.z;
+            if end != transform.translation {
+                commands.entity(entity).insert(Animator::new(Tween::new(
+                    EaseFunction::QuadraticIn,
+                    TweeningType::Once,
+                    Duration::from_secs_f32(MIRROR_MOVE_DURATION),
+                    TransformPositionLens {
+                        start: transform.translation,
+                        end,
+                    },
+                )));
+            }
+        }
+    }
+
+    let mut players: Vec<&NetEntity> = newest
+        .entities
+        .values()
+        .filter(|net| net.kind == NetKind::Player)
+        .collect();
+    players.sort_by_key(|net| net.tag);
+    let mut status = match (connection.spectator, connection.id) {
+        (true, _) => format!(
+            "Room '{}', {:.1}s behind, {}",
+            connection.room,
+            connection.delay as f32 / TICK_RATE as f32,
+            match connection.follow {
+                Some(id) => format!("following player {}", id.0),
+                None => "whole field".to_string(),
+            }
+        ),
+        (false, Some(id)) => format!("Room '{}', you are player {}", connection.room, id.0),
+        (false, None) => format!("Room '{}'", connection.room),
+    };
+    for player in players {
+        status.push_str(&format!("   P{}: {}", player.tag, player.value.max(0)));
+    }
+    for mut text in status_query.iter_mut() {
+        text.sections[0].value = status.clone();
+    }
+
+    connection.shown = Some(newest.tick);
+}
+
+/// Lets a spectator switch between following one of the players and showing the whole field with
+/// the left and right actions.
+pub fn spectator_camera_system(
+    connection: Option<ResMut<ServerConnection>>,
+    actions: Res<ActionInput>,
+    mut camera_query: Query<SpectatorCamera, (With<Camera2d>, Without<Mirrored>)>,
+    mirror_query: Query<&Transform, (With<Mirrored>, Without<Camera2d>)>,
+) {
+    let mut connection = match connection {
+        Some(connection) if connection.spectator => connection,
+        _ => return,
+    };
+
+    let mut players: Vec<(PlayerId, u64)> = connection.displayed().map_or_else(Vec::new, |room| {
+        room.entities
+            .iter()
+            .filter(|(_, net)| net.kind == NetKind::Player)
+            .map(|(id, net)| (PlayerId(net.tag as usize), *id))
+            .collect()
+    });
+    players.sort_by_key(|(id, _)| id.0);
+
+    // the whole field comes before the first and after the last player
+    let step = if actions.just_pressed(PlayerId(1), Action::MoveRight) {
+        Some(1)
+    } else if actions.just_pressed(PlayerId(1), Action::MoveLeft) {
+        Some(players.len())
+    } else {
+        None
+    };
+    if let Some(step) = step {
+        let current = connection
+            .follow
+            .and_then(|follow| players.iter().position(|(id, _)| *id == follow))
+            .map_or(0, |index| index + 1);
+        let next = (current + step) % (players.len() + 1);
+        connection.follow = next.checked_sub(1).map(|index| players[index].0);
+    }
+
+    // players who are out show the whole field until they come back
+    let target = connection
+        .follow
+        .and_then(|follow| players.iter().find(|(id, _)| *id == follow))
+        .and_then(|(_, id)| connection.mirror.get(id))
+        .and_then(|entity| mirror_query.get(*entity).ok());
+    for (mut transform, mut projection) in camera_query.iter_mut() {
+        match target {
+            Some(target) => {
+                transform.translation.x = target.translation.x;
+                transform.translation.y = target.translation.y;
+                projection.scale = SPECTATOR_ZOOM;
+            }
+            None => reset_camera(&mut transform, &mut projection),
+        }
+    }
+}
+
+/// Puts the camera back where `startup_system` placed it, over the whole field.
+fn reset_camera(transform: &mut Transform, projection: &mut OrthographicProjection) {
+    transform.translation.x = 0.;
+    transform.translation.y = HUD_HEIGHT / 2.;
+    projection.scale = 1.;
+}
+
+fn mirrored_translation(net: &NetEntity) -> Vec3 {
+    match net.kind {
+        NetKind::Boss => boss_translation(net.cell),
+        _ => cell_to_position(net.cell),
+    }
+}
+
+/// Draws an entity of the room the way the game spawns it, without any of its gameplay.
+fn spawn_mirrored(
+    commands: &mut Commands,
+    textures: &GameTextures,
+    archetypes: &EnemyArchetypes,
+    id: u64,
+    net: &NetEntity,
+) -> Entity {
+    let mut transform = Transform::from_translation(mirrored_translation(net));
+    let sprite = |texture: &Handle<Image>, color: Color, transform: Transform| SpriteBundle {
+        texture: texture.clone(),
+        sprite: Sprite { color, ..default() },
+        transform,
+        ..default()
+    };
+
+    let mut entity = match net.kind {
+        NetKind::Wall => commands.spawn_bundle(sprite(&textures.wall, Color::WHITE, transform)),
+        NetKind::BreakableWall => {
+            commands.spawn_bundle(sprite(&textures.wood, Color::WHITE, transform))
+        }
+        NetKind::Spawner => commands.spawn_bundle(sprite(
+            &textures.spawner,
+            Color::rgb(0.7, 0.3, 0.9),
+            transform,
+        )),
+        NetKind::SlowTile => {
+            transform.translation.z = 0.;
+            commands.spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgb(0.2, 0.3, 0.6),
+                    custom_size: Some(Vec2::splat(CELL_SIZE)),
+                    ..default()
+                },
+                transform,
+                ..default()
+            })
+        }
+        NetKind::SpeedPickup => {
+            transform.translation.z = 0.5;
+            commands.spawn_bundle(SpriteBundle {
+                sprite: Sprite {
+                    color: Color::rgb(0.3, 0.9, 1.),
+                    custom_size: Some(Vec2::splat(CELL_SIZE / 2.)),
+                    ..default()
+                },
+                transform,
+                ..default()
+            })
+        }
+        NetKind::Bomb => {
+            let mut bomb = commands.spawn_bundle(sprite(&textures.bomb, Color::WHITE, transform));
+            bomb.insert(bomb_pulse(Vec3::ONE));
+            bomb
+        }
+        NetKind::Player => {
+            transform.translation.z = 1.;
+            let color = PLAYER_COLORS[(net.tag as usize).clamp(1, MAX_PLAYERS) - 1];
+            commands.spawn_bundle(SpriteSheetBundle {
+                texture_atlas: textures.player.clone(),
+                sprite: TextureAtlasSprite {
+                    index: net.frame as usize,
+                    color,
+                    ..default()
+                },
+                transform,
+                ..default()
+            })
+        }
+        NetKind::Enemy => match archetypes.list.get(net.tag as usize) {
+            Some(archetype) => {
+                if archetype.pass_breakable_walls {
+                    transform.translation.z = 3.;
+                }
+                commands.spawn_bundle(sprite(&archetype.texture, archetype.tint, transform))
+            }
+            None => commands.spawn_bundle(sprite(&textures.boss, Color::WHITE, transform)),
+        },
+        NetKind::Boss => commands.spawn_bundle(SpriteBundle {
+            texture: textures.boss.clone(),
+            sprite: Sprite {
+                color: Color::rgb(0.8, 0.4, 1.),
+                custom_size: Some(Vec2::splat(CELL_SIZE * BOSS_SIZE as f32)),
+                ..default()
+            },
+            transform,
+            ..default()
+        }),
+        NetKind::Explosion => {
+            transform.rotation = Quat::from_rotation_z(net.tag as f32 * FRAC_PI_2);
+            commands.spawn_bundle(SpriteSheetBundle {
+                texture_atlas: textures.explosion.clone(),
+                sprite: TextureAtlasSprite::new(net.frame as usize),
+                transform,
+                ..default()
+            })
+        }
+    };
+    entity.insert(Mirrored(id)).id()
+}
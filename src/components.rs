@@ -1,52 +1,1306 @@
-use bevy::{prelude::*, utils::Instant};
+use bevy::{ecs::world::EntityMut, prelude::*, utils::{HashMap, HashSet, Instant}};
+use rand::RngCore;
 
-#[derive(Component)]
+use std::collections::VecDeque;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use crate::constants::{
+    BATTLE_PLAYER_HEALTH, CHAIN_WINDOW, ENEMY_CODE, HELLO_INTERVAL, HIGH_SCORE_COUNT, INPUT_DELAY,
+    LEVEL_TIME_LIMIT, MAX_CHAIN_MULTIPLIER, MAX_PLAYERS, MAX_PREDICTION, MAX_SPEED, PLAYER_CODE,
+    PLAYER_HEALTH, PLAYER_LIVES, SIZE_IN_CELLS, SLOW_TILE_FACTOR,
+};
+use crate::utils::assets_dir;
+
+#[derive(Component, Clone)]
 pub struct Player{
     pub health: i32,
-    pub last_hit: Instant,
+    /// `SimTime::elapsed` when the player was last hurt.
+    pub last_hit: Duration,
+    pub max_bombs: usize,
+}
+
+/// Number of a player, from 1 to `MAX_PLAYERS`.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct PlayerId(pub usize);
+
+/// A player who just respawned and can't be hurt until the timer runs out.
+#[derive(Component, Clone)]
+pub struct Invulnerable {
+    pub timer: Timer,
+}
+
+/// A player an explosion can hurt, along with `Without<Invulnerable>`.
+pub type HurtPlayerQuery<'a> = (&'a GridCell, &'a mut Player, &'a mut TextureAtlasSprite);
+
+/// Lives left of every player in the campaign, kept across levels.
+#[derive(Clone)]
+pub struct Lives {
+    /// Indexed by player id - 1.
+    pub left: [u32; MAX_PLAYERS],
+}
+
+impl Default for Lives {
+    fn default() -> Self {
+        Lives {
+            left: [PLAYER_LIVES; MAX_PLAYERS],
+        }
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+pub struct PlayerScore {
+    pub points: u32,
+    /// Kills in the current chain.
+    pub chain: u32,
+    /// Seconds left for the next kill to continue the chain.
+    pub chain_time: f32,
+}
+
+/// Points of every player, kept across levels and reset with the lives.
+#[derive(Default, Clone)]
+pub struct Scores {
+    /// Indexed by player id - 1.
+    pub players: [PlayerScore; MAX_PLAYERS],
+}
+
+impl Scores {
+    pub fn add(&mut self, id: PlayerId, points: u32) {
+        self.players[id.0 - 1].points += points;
+    }
+
+    /// Every kill within `CHAIN_WINDOW` of the previous one is worth twice as much.
+    pub fn add_kill(&mut self, id: PlayerId, points: u32) {
+        let score = &mut self.players[id.0 - 1];
+        score.chain = if score.chain_time > 0. { score.chain + 1 } else { 1 };
+        score.chain_time = CHAIN_WINDOW;
+        let multiplier = 2u32.saturating_pow(score.chain - 1).min(MAX_CHAIN_MULTIPLIER);
+        score.points += points * multiplier;
+    }
+}
+
+pub struct HighScore {
+    pub points: u32,
+    pub name: String,
+}
+
+/// Best scores, highest first.
+#[derive(Default)]
+pub struct HighScores {
+    pub entries: Vec<HighScore>,
+}
+
+impl HighScores {
+    pub fn qualifies(&self, points: u32) -> bool {
+        points > 0
+            && (self.entries.len() < HIGH_SCORE_COUNT
+                || self.entries.last().is_none_or(|lowest| points > lowest.points))
+    }
+
+    pub fn insert(&mut self, entry: HighScore) {
+        let index = self
+            .entries
+            .iter()
+            .position(|other| entry.points > other.points)
+            .unwrap_or(self.entries.len());
+        self.entries.insert(index, entry);
+        self.entries.truncate(HIGH_SCORE_COUNT);
+    }
+}
+
+/// Players typing their name for the high score table on the game over screen.
+#[derive(Default)]
+pub struct NameEntry {
+    /// The first player is typing, the others are waiting for their turn.
+    pub queue: Vec<PlayerId>,
+    pub name: String,
+}
+
+/// Movement input of a player, kept between frames so moves aren't lost while a step is under way.
+#[derive(Component, Clone, Default)]
+pub struct PlayerMovement {
+    /// Held directions in the order they were pressed, the last one wins.
+    pub held: Vec<Action>,
+    /// Direction pressed during the current step, applied as soon as it finishes.
+    pub buffered: Option<Action>,
+    /// Runs for as long as the current step, which the move tween only draws.
+    pub step: Timer,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Action {
+    MoveUp,
+    MoveDown,
+    MoveLeft,
+    MoveRight,
+    PlaceBomb,
+    Detonate,
+    Pause,
+}
+
+impl Action {
+    pub const ALL: [Action; 7] = [
+        Action::MoveUp,
+        Action::MoveDown,
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::PlaceBomb,
+        Action::Detonate,
+        Action::Pause,
+    ];
+
+    /// Name of the action in the bindings file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Action::MoveUp => "move_up",
+            Action::MoveDown => "move_down",
+            Action::MoveLeft => "move_left",
+            Action::MoveRight => "move_right",
+            Action::PlaceBomb => "place_bomb",
+            Action::Detonate => "detonate",
+            Action::Pause => "pause",
+        }
+    }
+}
+
+/// Actions that don't belong to a player.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum GlobalAction {
+    Rebind,
+    StartBattle,
+    DebugKillEnemy,
+    ToggleDebugInfo,
+    SaveGame,
+}
+
+impl GlobalAction {
+    pub const ALL: [GlobalAction; 5] = [
+        GlobalAction::Rebind,
+        GlobalAction::StartBattle,
+        GlobalAction::DebugKillEnemy,
+        GlobalAction::ToggleDebugInfo,
+        GlobalAction::SaveGame,
+    ];
+
+    /// Name of the action in the bindings file.
+    pub fn name(self) -> &'static str {
+        match self {
+            GlobalAction::Rebind => "rebind",
+            GlobalAction::StartBattle => "start_battle",
+            GlobalAction::DebugKillEnemy => "debug_kill_enemy",
+            GlobalAction::ToggleDebugInfo => "toggle_debug_info",
+            GlobalAction::SaveGame => "save_game",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Binding {
+    Key(KeyCode),
+    Button(GamepadButtonType),
+    /// A stick or trigger pushed past `AXIS_THRESHOLD` in the positive or negative direction.
+    Axis(GamepadAxisType, bool),
+}
+
+pub struct PlayerBindings {
+    /// Which of the connected gamepads the player uses, counted in the order they were connected.
+    pub gamepad: usize,
+    pub actions: HashMap<Action, Vec<Binding>>,
+}
+
+pub struct Bindings {
+    /// Indexed by player id - 1.
+    pub players: Vec<PlayerBindings>,
+    pub global: HashMap<GlobalAction, Vec<KeyCode>>,
+}
+
+/// Clock of the gameplay systems, advanced by the frame time in local games and by a fixed tick
+/// online, so that every peer steps the game the same way.
+#[derive(Default, Clone)]
+pub struct SimTime {
+    delta: Duration,
+    elapsed: Duration,
+}
+
+impl SimTime {
+    pub fn delta(&self) -> Duration {
+        self.delta
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta.as_secs_f32()
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Time since `moment`, an earlier `elapsed`.
+    pub fn since(&self, moment: Duration) -> Duration {
+        self.elapsed.saturating_sub(moment)
+    }
+
+    pub fn advance(&mut self, delta: Duration) {
+        self.delta = delta;
+        self.elapsed += delta;
+    }
+}
+
+/// Makes a local game step by one tick every update instead of by the frame time, for headless
+/// games that run as fast as they are updated.
+pub struct FixedTick;
+
+/// Actions held by every player this frame, read by the gameplay systems instead of raw input.
+#[derive(Default)]
+pub struct ActionInput {
+    pub pressed: HashSet<(PlayerId, Action)>,
+    pub previous: HashSet<(PlayerId, Action)>,
+    pub global: HashSet<GlobalAction>,
+}
+
+impl ActionInput {
+    pub fn pressed(&self, id: PlayerId, action: Action) -> bool {
+        self.pressed.contains(&(id, action))
+    }
+
+    pub fn just_pressed(&self, id: PlayerId, action: Action) -> bool {
+        self.pressed(id, action) && !self.previous.contains(&(id, action))
+    }
+
+    pub fn press(&mut self, id: PlayerId, action: Action) {
+        self.pressed.insert((id, action));
+    }
+
+    pub fn global_just_pressed(&self, action: GlobalAction) -> bool {
+        self.global.contains(&action)
+    }
+}
+
+/// State of the key rebinding screen.
+#[derive(Default)]
+pub struct Rebinding {
+    pub open: bool,
+    /// Index of the player being rebound.
+    pub player: usize,
+    /// Index into `Action::ALL`.
+    pub selected: usize,
+    pub listening: bool,
 }
 
 #[derive(Component)]
-pub struct Wall;
+pub struct RebindScreen;
 
 #[derive(Component)]
-pub struct BreakableWall;
+pub struct RebindText;
+
+pub struct GameSettings {
+    /// Players taking part, numbered from 1.
+    pub player_count: usize,
+    pub mode: GameMode,
+    pub difficulty: Difficulty,
+    /// The last this many players are steered by `bot_system`.
+    pub bots: usize,
+    pub bot_difficulty: Difficulty,
+}
+
+impl GameSettings {
+    pub fn is_bot(&self, id: PlayerId) -> bool {
+        id.0 + self.bots > self.player_count
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    pub const ALL: [Difficulty; 3] = [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard];
+
+    /// Multiplier applied to the speed of every enemy.
+    pub fn enemy_speed(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.75,
+            Difficulty::Normal => 1.,
+            Difficulty::Hard => 1.25,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Difficulty::Easy => "easy",
+            Difficulty::Normal => "normal",
+            Difficulty::Hard => "hard",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Difficulty::ALL
+            .into_iter()
+            .find(|difficulty| difficulty.name() == name)
+    }
+
+    /// Seconds a bot keeps to its plan before it looks at the level again.
+    pub fn bot_think_interval(self) -> f32 {
+        match self {
+            Difficulty::Easy => 0.5,
+            Difficulty::Normal => 0.2,
+            Difficulty::Hard => 0.,
+        }
+    }
+
+    /// Chance that a bot wanders off at random instead of thinking.
+    pub fn bot_blunder_chance(self) -> f64 {
+        match self {
+            Difficulty::Easy => 0.2,
+            Difficulty::Normal => 0.05,
+            Difficulty::Hard => 0.,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum GameMode {
+    /// Clearing the numbered levels of enemies.
+    Campaign,
+    /// Players fighting each other on the arena levels.
+    Battle,
+}
+
+impl GameMode {
+    pub fn player_health(self) -> i32 {
+        match self {
+            GameMode::Campaign => PLAYER_HEALTH,
+            GameMode::Battle => BATTLE_PLAYER_HEALTH,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            GameMode::Campaign => "campaign",
+            GameMode::Battle => "battle",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [GameMode::Campaign, GameMode::Battle]
+            .into_iter()
+            .find(|mode| mode.name() == name)
+    }
+}
+
+/// Progress of a best-of-N battle match.
+#[derive(Clone)]
+pub struct Battle {
+    pub best_of: u32,
+    pub round: u32,
+    pub wins: [u32; MAX_PLAYERS],
+    /// Runs once one or no players are left, so that players dying at nearly the same time draw.
+    pub round_end: Option<Timer>,
+    /// Winner of the round that just ended, `None` for a draw.
+    pub round_winner: Option<PlayerId>,
+}
+
+impl Battle {
+    pub fn new(best_of: u32) -> Self {
+        Battle {
+            best_of,
+            round: 1,
+            wins: [0; MAX_PLAYERS],
+            round_end: None,
+            round_winner: None,
+        }
+    }
+
+    pub fn wins_needed(&self) -> u32 {
+        self.best_of / 2 + 1
+    }
+
+    pub fn match_winner(&self) -> Option<PlayerId> {
+        self.wins
+            .iter()
+            .position(|wins| *wins >= self.wins_needed())
+            .map(|index| PlayerId(index + 1))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum AppState {
+    MainMenu,
+    /// Shows the "Level N" screen while the next level is loaded.
+    Loading,
+    Playing,
+    /// Pushed on top of `Playing`, which keeps its entities but stops updating.
+    Paused,
+    /// Shows the completed level or the battle round results before loading the next one.
+    LevelComplete,
+    GameOver,
+    /// Draws the game of a dedicated server room, which runs the level flow by itself.
+    Remote,
+}
+
+/// Times the screens of the states that move on by themselves.
+pub struct StateTimer(pub Timer);
 
+/// Selection on the main menu, an index into `MENU_ITEMS`.
+#[derive(Default)]
+pub struct Menu {
+    pub selected: usize,
+}
+
+/// Full screen text shown by a state, despawned when the state is left.
 #[derive(Component)]
-pub struct Solid;
+pub struct Overlay;
+
+/// Walls dropping in a spiral once a level's time is nearly up.
+#[derive(Default, Clone)]
+pub struct HurryUp {
+    pub started: bool,
+    pub drop_timer: Timer,
+    /// Index of the next cell in the spiral.
+    pub next: usize,
+}
 
 #[derive(Component)]
-pub struct Destructable;
+pub struct HurryUpWarning {
+    pub timer: Timer,
+}
+
+/// A game in progress, written by the save game action or when quitting a level and restored
+/// by "Continue" on the main menu.
+pub struct SaveGame {
+    pub mode: GameMode,
+    pub difficulty: Difficulty,
+    pub player_count: usize,
+    pub bots: usize,
+    pub bot_difficulty: Difficulty,
+    pub level: u32,
+    pub round: u32,
+    pub wins: [u32; MAX_PLAYERS],
+    pub lives: [u32; MAX_PLAYERS],
+    pub scores: [PlayerScore; MAX_PLAYERS],
+    pub time: f32,
+    pub boss_defeated: bool,
+    pub hurry_up_started: bool,
+    pub hurry_up_next: usize,
+    /// Seconds since the last dropped wall.
+    pub hurry_up_elapsed: f32,
+    pub rng: u64,
+    /// Walls, slow tiles and spawn cells, everything that can change is in `entities`.
+    pub array: [[i32; SIZE_IN_CELLS]; SIZE_IN_CELLS],
+    pub entities: Vec<SavedEntity>,
+}
+
+/// One line of a save game describing something left on the field.
+pub enum SavedEntity {
+    BreakableWall((i32, i32)),
+    SpeedPickup((i32, i32)),
+    Spawner {
+        cell: (i32, i32),
+        age: f32,
+        spawn_elapsed: f32,
+        spawned: u32,
+        next_wave: usize,
+        queue: Vec<char>,
+    },
+    Enemy {
+        glyph: char,
+        cell: (i32, i32),
+        health: i32,
+        move_duration: f32,
+        move_elapsed: f32,
+    },
+    Boss {
+        /// Bottom left of the cells the boss covers.
+        cell: (i32, i32),
+        health: i32,
+        next_attack: usize,
+        attack_duration: f32,
+        attack_elapsed: f32,
+    },
+    Bomb {
+        cell: (i32, i32),
+        fuse_elapsed: f32,
+        owner: Option<PlayerId>,
+    },
+    Player {
+        id: PlayerId,
+        cell: (i32, i32),
+        health: i32,
+        max_bombs: usize,
+        speed_bonus: f32,
+    },
+}
+
+/// The save game picked on the main menu, restored while its level loads.
+#[derive(Default)]
+pub struct SavedGame(pub Option<SaveGame>);
+
+/// Set when a level is left for the main menu, so it is saved before being despawned.
+#[derive(Default)]
+pub struct SaveRequest(pub bool);
+
+/// Stage of the gameplay systems, run once a frame in local games and once per tick online.
+#[derive(StageLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SimulationStage;
+
+/// Every gameplay system in the `SimulationStage`.
+#[derive(SystemLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GameplaySystems;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetRole {
+    Host,
+    Join,
+}
+
+/// An online battle between two peers. Each sends its player's input for every tick, and runs
+/// ticks whose remote input hasn't arrived yet on a prediction, rolling back to the snapshot of
+/// the first mispredicted tick once it does.
+pub struct Lockstep {
+    pub socket: UdpSocket,
+    pub role: NetRole,
+    /// The other peer, known to the host once it says hello.
+    pub peer: Option<SocketAddr>,
+    /// Set once the host and the joining peer heard from each other.
+    pub started: bool,
+    /// Seed of the `GameRng`, picked by the host.
+    pub seed: u64,
+    /// The player steered from this window, the host is player 1.
+    pub local_id: PlayerId,
+    /// Ticks run ahead of the remote input, zero waits for it like plain lockstep.
+    pub prediction: u32,
+    /// Next tick to simulate.
+    pub tick: u32,
+    /// Local input of every tick so far, indexed by tick.
+    pub local_inputs: Vec<u8>,
+    /// Input of the other peer for every tick received so far, without gaps.
+    pub remote_inputs: Vec<u8>,
+    /// Remote input every simulated tick was run with, predicted or received.
+    pub played_inputs: Vec<u8>,
+    /// Ticks before this one were run with the received remote input.
+    pub verified: u32,
+    /// Tick to restore the snapshot of before the next tick runs.
+    pub rollback: Option<u32>,
+    /// Ticks before this one are run again after a rollback, right away.
+    pub resimulate_until: u32,
+    /// Snapshots of the ticks that may still be rolled back to, oldest first.
+    pub snapshots: VecDeque<Snapshot>,
+    /// Local inputs the other peer confirmed receiving.
+    pub acked: usize,
+    /// Frame time not simulated yet.
+    pub accumulator: Duration,
+    /// Input read this frame, put aside while the tick inputs are in `ActionInput`.
+    pub stashed: Option<ActionInput>,
+    pub ticks_this_frame: u32,
+    /// State checksums by tick, waiting for the other peer's to compare.
+    pub local_checksums: HashMap<u32, u64>,
+    pub remote_checksums: HashMap<u32, u64>,
+    /// Ticks whose checksums the peers compared, and the ones of them that didn't match.
+    pub checksums_compared: u32,
+    pub desyncs: u32,
+    /// Seconds since the last packet of the other peer.
+    pub silence: f32,
+    pub hello_timer: Timer,
+    pub link: LinkConditions,
+}
+
+impl Lockstep {
+    pub fn new(socket: UdpSocket, role: NetRole, peer: Option<SocketAddr>, seed: u64) -> Self {
+        Lockstep {
+            socket,
+            role,
+            peer,
+            started: false,
+            seed,
+            local_id: match role {
+                NetRole::Host => PlayerId(1),
+                NetRole::Join => PlayerId(2),
+            },
+            prediction: MAX_PREDICTION,
+            tick: 0,
+            // nobody presses anything during the first ticks, before the first inputs arrive
+            local_inputs: vec![0; INPUT_DELAY],
+            remote_inputs: vec![0; INPUT_DELAY],
+            played_inputs: Vec::new(),
+            verified: 0,
+            rollback: None,
+            resimulate_until: 0,
+            snapshots: VecDeque::new(),
+            acked: INPUT_DELAY,
+            accumulator: Duration::ZERO,
+            stashed: None,
+            ticks_this_frame: 0,
+            local_checksums: HashMap::default(),
+            remote_checksums: HashMap::default(),
+            checksums_compared: 0,
+            desyncs: 0,
+            silence: 0.,
+            hello_timer: Timer::from_seconds(HELLO_INTERVAL, true),
+            link: LinkConditions::new(seed),
+        }
+    }
+
+    pub fn remote_id(&self) -> PlayerId {
+        match self.role {
+            NetRole::Host => PlayerId(2),
+            NetRole::Join => PlayerId(1),
+        }
+    }
+}
+
+/// Latency and packet loss added to the packets a peer sends, to try out online play on one
+/// machine.
+pub struct LinkConditions {
+    pub latency: Duration,
+    /// Share of the packets dropped, from 0 to 1.
+    pub loss: f32,
+    /// Packets waiting for their latency to pass.
+    pub queue: VecDeque<(Instant, Vec<u8>)>,
+    /// Decides which packets are lost, apart from the `GameRng` which both peers share.
+    pub random: GameRng,
+}
+
+impl LinkConditions {
+    pub fn new(seed: u64) -> Self {
+        LinkConditions {
+            latency: Duration::ZERO,
+            loss: 0.,
+            queue: VecDeque::new(),
+            random: GameRng::new(seed.rotate_left(32)),
+        }
+    }
+}
+
+/// A component cloned into a snapshot, put back when rolling back to it.
+pub trait SnapshotComponent: Send + Sync {
+    fn insert(&self, entity: &mut EntityMut);
+}
+
+/// The game at the start of an online tick.
+pub struct Snapshot {
+    pub tick: u32,
+    pub checksum: u64,
+    /// The components of every simulated entity, in the order the entities were found.
+    pub entities: Vec<Vec<Box<dyn SnapshotComponent>>>,
+    pub rng: u64,
+    pub sim_time: SimTime,
+    pub field: Field,
+    pub scores: Scores,
+    pub lives: Lives,
+    pub battle: Battle,
+    pub hurry_up: HurryUp,
+    pub danger_map: DangerMap,
+}
 
+/// A state change asked for by a gameplay system. It is made after the tick, online only once
+/// the tick can't be rolled back anymore.
+#[derive(Default, Clone)]
+pub struct PendingState(pub Option<AppState>);
+
+impl PendingState {
+    /// The first change asked for wins, like `State::set`.
+    pub fn set(&mut self, next: AppState) {
+        self.0.get_or_insert(next);
+    }
+}
+
+/// Kinds of entities a dedicated server sends to its clients.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NetKind {
+    Wall,
+    BreakableWall,
+    SlowTile,
+    SpeedPickup,
+    Spawner,
+    Player,
+    Enemy,
+    Boss,
+    Bomb,
+    Explosion,
+}
+
+impl NetKind {
+    pub const ALL: [NetKind; 10] = [
+        NetKind::Wall,
+        NetKind::BreakableWall,
+        NetKind::SlowTile,
+        NetKind::SpeedPickup,
+        NetKind::Spawner,
+        NetKind::Player,
+        NetKind::Enemy,
+        NetKind::Boss,
+        NetKind::Bomb,
+        NetKind::Explosion,
+    ];
+}
+
+/// What a client is shown of an entity of a server room.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct NetEntity {
+    pub kind: NetKind,
+    /// Player id, enemy archetype index or quarter turns of an explosion.
+    pub tag: u8,
+    /// Index into the texture atlas of players and explosions.
+    pub frame: u8,
+    pub cell: (i32, i32),
+    /// Health of players, enemies and the boss.
+    pub value: i32,
+}
+
+/// A room of a dedicated server after one of its ticks, entities are keyed by `Entity::to_bits`.
+#[derive(Clone)]
+pub struct NetState {
+    pub tick: u32,
+    pub state: AppState,
+    pub entities: HashMap<u64, NetEntity>,
+}
+
+/// Inputs of the clients of a server room for the next tick, checked by `validate_inputs_system`
+/// before the gameplay systems see them.
+#[derive(Default)]
+pub struct RoomInputs(pub HashMap<PlayerId, u8>);
+
+/// The connection of a client to a room of a dedicated server.
+pub struct ServerConnection {
+    pub socket: UdpSocket,
+    pub server: SocketAddr,
+    pub room: String,
+    /// Watches the room without a player of its own.
+    pub spectator: bool,
+    /// Whether the server let this client into the room.
+    pub welcomed: bool,
+    /// The player steered from this window, given by the server.
+    pub id: Option<PlayerId>,
+    /// States received lately, kept as the bases of the deltas that follow and until they are
+    /// `delay` ticks old.
+    pub states: VecDeque<NetState>,
+    /// Ticks a spectator lags behind the room.
+    pub delay: u32,
+    /// The player a spectator's camera follows, the whole field is shown without one.
+    pub follow: Option<PlayerId>,
+    /// Tick of the state drawn last.
+    pub shown: Option<u32>,
+    /// Entities drawn for the entities of the room.
+    pub mirror: HashMap<u64, Entity>,
+    /// Number of the next input sent, so the server skips late ones.
+    pub sequence: u32,
+    /// Seconds since the last packet of the server.
+    pub silence: f32,
+    pub hello_timer: Timer,
+}
+
+impl ServerConnection {
+    pub fn new(socket: UdpSocket, server: SocketAddr, room: String, spectator: bool) -> Self {
+        ServerConnection {
+            socket,
+            server,
+            room,
+            spectator,
+            welcomed: false,
+            id: None,
+            states: VecDeque::new(),
+            delay: 0,
+            follow: None,
+            shown: None,
+            mirror: HashMap::default(),
+            sequence: 0,
+            silence: 0.,
+            hello_timer: Timer::from_seconds(HELLO_INTERVAL, true),
+        }
+    }
+
+    /// The newest state that is at least `delay` ticks older than the newest one received.
+    pub fn displayed(&self) -> Option<&NetState> {
+        let newest = self.states.back()?.tick;
+        self.states
+            .iter()
+            .rev()
+            .find(|state| state.tick + self.delay <= newest)
+    }
+}
+
+/// An entity drawn for the server room entity with these `Entity::to_bits`.
 #[derive(Component)]
-pub struct Enemy;
+pub struct Mirrored(pub u64);
 
+/// Status line of a client of a server room.
 #[derive(Component)]
+pub struct RemoteStatus;
+
+/// The plans of the bots, with random numbers of their own so they don't change the ones the
+/// enemies get.
+pub struct Bots {
+    pub brains: HashMap<PlayerId, BotBrain>,
+    pub rng: GameRng,
+}
+
+impl Bots {
+    pub fn new(seed: u64) -> Self {
+        Bots {
+            brains: HashMap::default(),
+            rng: GameRng::new(seed),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct BotBrain {
+    /// Runs out when the bot decides again.
+    pub think: Timer,
+    /// Cells the bot walks through, the next one first.
+    pub path: VecDeque<(i32, i32)>,
+}
+
+/// Grids of an observation of the training environment.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Channel {
+    Wall,
+    /// Walls and spawners that explosions break.
+    BreakableWall,
+    /// Share of the fuse left, from 1 for a bomb just placed down to 0.
+    Bomb,
+    /// Cells on fire.
+    Flame,
+    /// Enemies and the cells covered by bosses.
+    Enemy,
+    Player(PlayerId),
+}
+
+impl Channel {
+    pub fn index(self) -> usize {
+        match self {
+            Channel::Wall => 0,
+            Channel::BreakableWall => 1,
+            Channel::Bomb => 2,
+            Channel::Flame => 3,
+            Channel::Enemy => 4,
+            Channel::Player(id) => 4 + id.0,
+        }
+    }
+}
+
+/// What an agent sees of the training environment after a step.
+#[derive(Clone)]
+pub struct Observation {
+    /// `OBSERVATION_CHANNELS` grids of `SIZE_IN_CELLS` by `SIZE_IN_CELLS` cells, each one column by
+    /// column.
+    pub grid: Vec<f32>,
+    /// Indexed by player id - 1, 0 for players who are out.
+    pub health: [i32; MAX_PLAYERS],
+    /// Indexed by player id - 1.
+    pub points: [u32; MAX_PLAYERS],
+    /// Seconds played of the level.
+    pub time: f32,
+}
+
+impl Observation {
+    pub fn get(&self, channel: Channel, cell: (i32, i32)) -> f32 {
+        match observation_index(channel, cell) {
+            Some(index) => self.grid[index],
+            None => 0.,
+        }
+    }
+
+    pub fn set(&mut self, channel: Channel, cell: (i32, i32), value: f32) {
+        if let Some(index) = observation_index(channel, cell) {
+            self.grid[index] = value;
+        }
+    }
+}
+
+fn observation_index(channel: Channel, cell: (i32, i32)) -> Option<usize> {
+    let size = SIZE_IN_CELLS as i32;
+    if !(0..size).contains(&cell.0) || !(0..size).contains(&cell.1) {
+        return None;
+    }
+    Some((channel.index() * SIZE_IN_CELLS + cell.0 as usize) * SIZE_IN_CELLS + cell.1 as usize)
+}
+
+/// The state a step of the training environment asked for, which ends the episode.
+#[derive(Default)]
+pub struct EnvOutcome(pub Option<AppState>);
+
+/// Where the levels and arenas are read from.
+#[derive(Clone, Debug)]
+pub struct LevelPack {
+    pub dir: String,
+    /// A level file played instead of the directory, as its only level or arena.
+    pub file: Option<String>,
+}
+
+impl LevelPack {
+    pub fn new(dir: &str) -> Self {
+        LevelPack {
+            dir: dir.to_string(),
+            file: None,
+        }
+    }
+}
+
+impl Default for LevelPack {
+    fn default() -> Self {
+        LevelPack::new(assets_dir())
+    }
+}
+
+/// The level entities the gameplay systems change, saved in online snapshots.
+pub type SimulatedEntities = Or<(
+    With<Solid>,
+    With<Player>,
+    With<Explosion>,
+    With<Boss>,
+    With<SlowTile>,
+    With<SpeedPickup>,
+)>;
+
+/// Everything spawned for a level, despawned when leaving it.
+pub type LevelEntities = Or<(
+    With<Solid>,
+    With<Player>,
+    With<Explosion>,
+    With<Boss>,
+    With<SlowTile>,
+    With<SpeedPickup>,
+    With<BossHealthBar>,
+    With<HurryUpWarning>,
+    With<Hud>,
+)>;
+
+#[derive(Component, Clone)]
+pub struct Wall;
+
+#[derive(Component, Clone)]
+pub struct BreakableWall;
+
+#[derive(Component, Clone)]
+pub struct Solid;
+
+#[derive(Component, Clone)]
+pub struct Destructable;
+
+#[derive(Component, Clone)]
+pub struct Enemy {
+    pub archetype: usize,
+    pub health: i32,
+    pub damage: i32,
+    /// `SimTime::elapsed` when the enemy was last hurt.
+    pub last_hit: Duration,
+    pub move_timer: Timer,
+}
+
+/// Movement speed of a player or an enemy in cells per second.
+#[derive(Component, Clone)]
+pub struct Speed {
+    pub base: f32,
+    /// Added by speed pickups.
+    pub bonus: f32,
+}
+
+impl Speed {
+    pub fn new(base: f32) -> Self {
+        Speed { base, bonus: 0. }
+    }
+
+    /// How long one step takes, slowed down when it starts on a slow tile.
+    pub fn step_duration(&self, slowed: bool) -> Duration {
+        let mut speed = (self.base + self.bonus).min(MAX_SPEED);
+        if slowed {
+            speed *= SLOW_TILE_FACTOR;
+        }
+        Duration::from_secs_f32(1. / speed)
+    }
+}
+
+/// The cell a moving entity occupies. It is claimed as soon as a step starts, so collisions
+/// don't depend on how far along its move tween a fast mover is.
+/// A boss's cell is the bottom left one of the cells it covers.
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GridCell(pub (i32, i32));
+
+/// Picked up by walking over it, makes the player faster.
+#[derive(Component, Clone)]
+pub struct SpeedPickup;
+
+/// Floor that slows down every step starting on it.
+#[derive(Component, Clone)]
+pub struct SlowTile;
+
+/// An enemy that walks through breakable walls.
+#[derive(Component, Clone)]
+pub struct Ghost;
+
+#[derive(Component, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum EnemyBehaviour {
+    /// Picks a random free neighbour cell every step.
+    Wander,
+    /// Wanders like `Wander`, but never steps into a blast zone and runs away from one it is standing in.
+    BombAware,
+}
+
+#[derive(Component, Clone)]
 pub struct Bomb{
-    pub spawned: Instant,
+    /// Only ticks while playing, so pausing the game stops the fuse.
+    pub fuse: Timer,
+    /// The player who placed the bomb, `None` for bombs placed by bosses.
+    pub owner: Option<PlayerId>,
+    /// Set by the owner's detonate action to blow the bomb up before its fuse runs out.
+    pub triggered: bool,
+}
+
+#[derive(Component, Clone)]
+pub struct Boss {
+    pub health: i32,
+    /// `SimTime::elapsed` when the boss was last hurt.
+    pub last_hit: Duration,
+    pub move_timer: Timer,
+    pub attack_timer: Timer,
+    pub next_attack: usize,
+    /// Direction of the charge in progress.
+    pub charge: Option<(i32, i32)>,
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BossAttack {
+    SpawnMinions,
+    PlaceBombs,
+    Charge,
+}
+
+#[derive(Component)]
+pub struct BossHealthBar;
+
 #[derive(Component)]
+pub struct BossHealthFill;
+
+#[derive(Component, Clone)]
 pub struct Explosion{
-    pub spawned: Instant,
+    pub timer: Timer,
+    /// Owner of the bomb, who scores what the explosion destroys.
+    pub owner: Option<PlayerId>,
 }
 
+/// Debug text in the bottom left corner, hidden until the toggle debug info action.
 #[derive(Component)]
 pub struct Info;
 
+/// Bar above the field with the player panels, the level name and the time left.
+#[derive(Component)]
+pub struct Hud;
+
+/// Panel of a player in the HUD, hidden while the player isn't in the game.
+#[derive(Component)]
+pub struct PlayerPanel(pub PlayerId);
+
+/// One health point of a player, the first `health` hearts are lit.
+#[derive(Component)]
+pub struct Heart {
+    pub id: PlayerId,
+    pub index: i32,
+}
+
+/// Text under the hearts of a player, one section per `PlayerStat`.
+#[derive(Component)]
+pub struct PlayerStats(pub PlayerId);
+
+/// Sections of the `PlayerStats` text.
+#[derive(Clone, Copy)]
+pub enum PlayerStat {
+    Bombs = 0,
+    Range = 1,
+    Lives = 2,
+    Score = 3,
+}
+
+#[derive(Component)]
+pub struct TimerText;
+
+#[derive(Clone)]
 pub struct Field{
     pub array: [[i32; 30];30],
-    pub loaded: bool,
-    pub spawned: bool,
+    pub properties: LevelProperties,
     pub current_level: u32,
+    /// Level a new campaign or battle starts on.
+    pub first_level: u32,
+    pub boss_defeated: bool,
+    /// Seconds spent playing the current level.
+    pub time: f32,
 }
 
+impl Field {
+    /// The cell where the player with the given id appears.
+    pub fn spawn_cell(&self, id: PlayerId) -> Option<(i32, i32)> {
+        let code = PLAYER_CODE + id.0 as i32;
+        self.array.iter().enumerate().find_map(|(i, column)| {
+            column
+                .iter()
+                .position(|cell| *cell == code)
+                .map(|j| (i as i32, j as i32))
+        })
+    }
+
+    /// Seconds left before the time limit runs out, never below zero.
+    pub fn time_left(&self) -> f32 {
+        (self.properties.time_limit.unwrap_or(LEVEL_TIME_LIMIT) - self.time).max(0.)
+    }
+
+    pub fn is_slow(&self, cell: (i32, i32)) -> bool {
+        let (x, y) = cell;
+        (0..SIZE_IN_CELLS as i32).contains(&x)
+            && (0..SIZE_IN_CELLS as i32).contains(&y)
+            && self.array[x as usize][y as usize] == 7
+    }
+}
+
+/// Settings read from the lines following the field grid in a level file.
+#[derive(Default, Clone)]
+pub struct LevelProperties {
+    pub spawn_every: Option<SpawnerSchedule>,
+    /// Sorted by time.
+    pub waves: Vec<Wave>,
+    /// Seconds, `LEVEL_TIME_LIMIT` when not set.
+    pub time_limit: Option<f32>,
+}
+
+/// Every spawner emits `count` enemies of the `glyph` archetype, one each `interval` seconds.
+#[derive(Clone)]
+pub struct SpawnerSchedule {
+    pub interval: f32,
+    pub glyph: char,
+    pub count: u32,
+}
+
+/// Enemies every spawner emits at once, `time` seconds after the level started.
+#[derive(Clone)]
+pub struct Wave {
+    pub time: f32,
+    pub glyphs: Vec<char>,
+}
+
+#[derive(Component, Clone)]
+pub struct Spawner {
+    /// Seconds since the spawner appeared.
+    pub age: f32,
+    pub spawn_timer: Timer,
+    /// Enemies emitted so far on the `spawn_every` schedule.
+    pub spawned: u32,
+    pub next_wave: usize,
+    /// Glyphs of enemies waiting for a free cell next to the spawner.
+    pub queue: Vec<char>,
+}
+
+impl Spawner {
+    pub fn is_active(&self, properties: &LevelProperties) -> bool {
+        !self.queue.is_empty()
+            || self.next_wave < properties.waves.len()
+            || properties
+                .spawn_every
+                .as_ref()
+                .is_some_and(|schedule| self.spawned < schedule.count)
+    }
+}
+
+/// Random numbers of the game, a xorshift generator so its state can be saved and restored.
+pub struct GameRng {
+    pub state: u64,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> Self {
+        // xorshift never leaves a zero state
+        GameRng {
+            state: if seed == 0 { 0x9E37_79B9_7F4A_7C15 } else { seed },
+        }
+    }
+}
+
+impl RngCore for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let mut x = self.state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.state = x;
+        x
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+/// Cells that will be hit by an explosion, mapped to the seconds left until they are hit.
+#[derive(Default, Clone)]
+pub struct DangerMap {
+    pub cells: HashMap<(i32, i32), f32>,
+}
+
+impl DangerMap {
+    pub fn is_dangerous(&self, cell: (i32, i32)) -> bool {
+        self.cells.contains_key(&cell)
+    }
+
+    pub fn time_to_blast(&self, cell: (i32, i32)) -> Option<f32> {
+        self.cells.get(&cell).copied()
+    }
+}
+
+#[derive(Default)]
 pub struct GameTextures {
     pub wall: Handle<Image>,
     pub wood: Handle<Image>,
     pub bomb: Handle<Image>,
-    pub enemy: Handle<Image>,
+    pub boss: Handle<Image>,
+    pub spawner: Handle<Image>,
     pub player: Handle<TextureAtlas>,
     pub explosion: Handle<TextureAtlas>
 }
 
+
+/// An enemy type described in the enemy definition file.
+pub struct EnemyArchetype {
+    pub name: String,
+    pub glyph: char,
+    pub texture: Handle<Image>,
+    pub tint: Color,
+    /// Cells per second.
+    pub speed: f32,
+    pub hit_points: i32,
+    pub contact_damage: i32,
+    pub behaviour: EnemyBehaviour,
+    pub pass_breakable_walls: bool,
+    pub score: u32,
+}
+
+pub struct EnemyArchetypes {
+    pub list: Vec<EnemyArchetype>,
+}
+
+impl EnemyArchetypes {
+    /// The field code used for the archetype with the given level glyph.
+    pub fn code_for_glyph(&self, glyph: char) -> Option<i32> {
+        self.list
+            .iter()
+            .position(|archetype| archetype.glyph == glyph)
+            .map(|index| ENEMY_CODE + index as i32)
+    }
+
+    pub fn from_code(&self, code: i32) -> Option<(usize, &EnemyArchetype)> {
+        let index = (code - ENEMY_CODE) as usize;
+        self.list.get(index).map(|archetype| (index, archetype))
+    }
+}
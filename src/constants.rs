@@ -1,9 +1,126 @@
 pub const SIZE_IN_CELLS: usize = 30;
 pub const FIELD_SIZE: f32 = 960.;
-pub const HEIGHT: f32 = 960.;
+/// Height of the HUD bar above the field.
+pub const HUD_HEIGHT: f32 = 64.;
+/// Window height, the field and the HUD bar.
+pub const HEIGHT: f32 = FIELD_SIZE + HUD_HEIGHT;
 pub const CELL_SIZE: f32 = FIELD_SIZE / 30.;
-pub const BOMB_TIMER: u64 = 3;
+/// Seconds until a bomb explodes, as long as the fuse always lasted.
+pub const BOMB_TIMER: f32 = 4.;
 pub const EXPLOSION_SIZE: i32 = 3;
 pub const CELL_OFFSET: f32 = CELL_SIZE / 2.;
 pub const FIELD_OFFSET: f32 = FIELD_SIZE / 2.;
-pub const MOVE_ANIMATION_DURATION: u64 = 100;
+pub const EXPLOSION_DURATION: u128 = 250;
+/// Enemy definitions in the assets directory.
+pub const ENEMY_DEFINITIONS_FILE: &str = "enemies.def";
+/// Directory of the textures, fonts, definitions and levels unless another one is given.
+pub const ASSETS_DIR: &str = "assets";
+/// Field codes from this value up are enemies, offset by their archetype index.
+pub const ENEMY_CODE: i32 = 100;
+/// Level glyphs that enemy archetypes can't use.
+pub const RESERVED_GLYPHS: [char; 12] = ['W', 'B', 'S', 'X', 'P', '.', '1', '2', '3', '4', '~', '+'];
+pub const MAX_PLAYERS: usize = 4;
+/// Field code of the spawn cell of player N is this value plus N.
+pub const PLAYER_CODE: i32 = 10;
+pub const PLAYER_HEALTH: i32 = 10;
+/// Respawns of every player in the campaign before they are out.
+pub const PLAYER_LIVES: u32 = 3;
+/// Seconds a respawned player can't be hurt.
+pub const INVULNERABILITY_DURATION: f32 = 2.;
+/// Seconds between a respawned player's sprite showing and hiding.
+pub const BLINK_INTERVAL: f32 = 0.1;
+pub const GHOST_ALPHA: f32 = 0.5;
+/// Width and height of a boss in cells.
+pub const BOSS_SIZE: i32 = 2;
+pub const BOSS_HEALTH: i32 = 12;
+pub const BOSS_DAMAGE: i32 = 2;
+/// Cells per second.
+pub const BOSS_SPEED: f32 = 1.5;
+pub const BOSS_CHARGE_SPEED: f32 = 8.;
+pub const BOSS_MAX_MINIONS: usize = 6;
+pub const BOSS_MINIONS_PER_WAVE: usize = 2;
+pub const BOSS_BOMBS_PER_ATTACK: usize = 3;
+/// Battles are lost on the first hit.
+pub const BATTLE_PLAYER_HEALTH: i32 = 1;
+pub const BATTLE_BEST_OF: u32 = 5;
+/// Seconds to wait after one or no players are left before the round is decided.
+pub const ROUND_END_DELAY: f32 = 1.;
+/// Seconds the standings are shown between rounds.
+pub const RESULTS_DURATION: f32 = 4.;
+/// Seconds the "Level N" screen is shown before a level starts.
+pub const LEVEL_INTRO_DURATION: f32 = 2.;
+/// Seconds the level complete screen is shown.
+pub const LEVEL_COMPLETE_DURATION: f32 = 2.;
+pub const BINDINGS_FILE: &str = "bindings.cfg";
+/// How far a stick has to be pushed to count as a pressed direction.
+pub const AXIS_THRESHOLD: f32 = 0.5;
+/// Cells per second, every step of a player's move tween lasts `1 / speed` seconds.
+pub const PLAYER_SPEED: f32 = 10.;
+/// Speed added by every speed pickup.
+pub const SPEED_PICKUP_BONUS: f32 = 2.;
+pub const MAX_SPEED: f32 = 20.;
+/// Speed multiplier for steps starting on a slow tile.
+pub const SLOW_TILE_FACTOR: f32 = 0.5;
+pub const WALL_SCORE: u32 = 10;
+pub const SPAWNER_SCORE: u32 = 250;
+pub const BOSS_SCORE: u32 = 5000;
+/// Seconds after a kill in which the next one continues the chain.
+pub const CHAIN_WINDOW: f32 = 1.;
+/// Every kill in a chain is worth twice the previous one, up to this multiplier.
+pub const MAX_CHAIN_MULTIPLIER: u32 = 8;
+/// Seconds a level should take, every second left of it is worth `TIME_BONUS_PER_SECOND`.
+pub const LEVEL_PAR_TIME: f32 = 120.;
+pub const TIME_BONUS_PER_SECOND: u32 = 10;
+pub const HIGH_SCORES_FILE: &str = "highscores.txt";
+pub const SAVE_GAME_FILE: &str = "savegame.txt";
+pub const HIGH_SCORE_COUNT: usize = 10;
+pub const MAX_NAME_LENGTH: usize = 12;
+/// Seconds to finish a level that doesn't set its own `time_limit`.
+pub const LEVEL_TIME_LIMIT: f32 = 180.;
+/// Seconds left when the hurry up warning shows and walls start dropping.
+pub const HURRY_UP_TIME: f32 = 30.;
+pub const HURRY_UP_WARNING_DURATION: f32 = 3.;
+/// Seconds between two dropped walls.
+pub const WALL_DROP_INTERVAL: f32 = 0.2;
+/// Width and height of a heart in the HUD.
+pub const HEART_SIZE: f32 = 9.;
+/// Ticks per second of the simulation in online games.
+pub const TICK_RATE: u32 = 60;
+/// Ticks between sampling a local input and simulating it, covering part of the trip to the other
+/// peer, the rest is predicted.
+pub const INPUT_DELAY: usize = 2;
+/// Ticks simulated at most on a predicted remote input, before waiting for the other peer.
+pub const MAX_PREDICTION: u32 = 8;
+/// Ticks caught up at most in one frame after waiting for the other peer.
+pub const MAX_TICKS_PER_FRAME: u32 = 4;
+/// Ticks between two state checksums sent to the other peer.
+pub const CHECKSUM_INTERVAL: u32 = 60;
+/// Seconds between hello packets while joining a game.
+pub const HELLO_INTERVAL: f32 = 0.5;
+/// Seconds without a packet from the other peer before an online game is given up.
+pub const PEER_TIMEOUT: f32 = 10.;
+/// Port a dedicated server listens on unless told otherwise.
+pub const SERVER_PORT: u16 = 7878;
+/// Clients a server room waits for before starting a battle.
+pub const ROOM_MIN_PLAYERS: usize = 2;
+/// States a server keeps for every room as the bases of the deltas it sends, and a client keeps
+/// to apply them to.
+pub const STATE_HISTORY: usize = 64;
+/// Seconds a client takes to move an entity to the cell the server put it in.
+pub const MIRROR_MOVE_DURATION: f32 = 0.1;
+/// Rooms a server runs at most, new ones are turned away until one closes.
+pub const MAX_ROOMS: usize = 32;
+/// Spectators a server room lets in besides its players.
+pub const MAX_SPECTATORS: usize = 16;
+/// Seconds a spectator lags behind the room unless told otherwise.
+pub const SPECTATOR_DELAY: f32 = 2.;
+/// Scale of the camera of a spectator who follows a player, smaller is closer.
+pub const SPECTATOR_ZOOM: f32 = 0.5;
+/// Grids of an observation of the training environment, one per `Channel`.
+pub const OBSERVATION_CHANNELS: usize = 5 + MAX_PLAYERS;
+/// Reward a player of the training environment loses for every point of health it loses.
+pub const HIT_PENALTY: f32 = 100.;
+/// Milliseconds an external bot gets to answer a tick unless told otherwise.
+pub const BOT_DEADLINE_MS: u64 = 100;
+/// Ticks after which a headless match is stopped unless told otherwise, five minutes of play.
+pub const MATCH_TICK_LIMIT: u32 = 5 * 60 * TICK_RATE;
@@ -1,105 +1,288 @@
+use std::collections::VecDeque;
+use std::fs;
 use std::time::Duration;
 
+use crate::boss_systems::boss_cells;
 use crate::components::*;
 use crate::constants::*;
-use crate::utils::is_equal_approximate;
+use crate::player_systems::PLAYER_COLORS;
+use crate::utils::{asset_path, cell_to_position, position_to_cell};
 use bevy::prelude::*;
+use bevy::utils::HashSet;
 use bevy_tweening::lens::TransformPositionLens;
 use bevy_tweening::*;
 use rand::prelude::*;
 
 pub const DAMAGE: i32 = 1;
 
-type EnemyQuery<'a> = (&'a Transform, Entity, Option<&'a Animator<Transform>>);
+type EnemyQuery<'a> = (
+    &'a Transform,
+    Entity,
+    &'a EnemyBehaviour,
+    &'a mut Enemy,
+    Option<&'a Ghost>,
+    &'a Speed,
+    &'a mut GridCell,
+);
+
+type WallQuery<'a> = (
+    &'a Transform,
+    Option<&'a BreakableWall>,
+    Option<&'a GridCell>,
+    Option<&'a Boss>,
+);
+/// What enemies can't walk through.
+type Blocking = (Or<(With<Solid>, With<Boss>)>, Without<Enemy>);
+
+pub const DIRECTIONS: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+
+/// How far an escaping enemy searches for a cell outside of every blast zone.
+const ESCAPE_SEARCH_DEPTH: usize = 8;
+
+/// Cells to search, with the first step towards each and how many steps away it is.
+type EscapeQueue = VecDeque<((i32, i32), (i32, i32), usize)>;
 
 pub fn move_enemy_system(
     mut query: Query<EnemyQuery, With<Enemy>>,
-    wall_query: Query<&Transform, (With<Solid>, Without<Enemy>)>,
+    wall_query: Query<WallQuery, Blocking>,
+    danger_map: Res<DangerMap>,
+    field: Res<Field>,
+    time: Res<SimTime>,
+    mut random: ResMut<GameRng>,
     mut commands: Commands,
 ) {
-    let mut random = rand::thread_rng();
-
-    for (enemy, entity, animator_option) in query.iter_mut() {
-        if animator_option.is_none()
-            || (animator_option.is_some() && animator_option.unwrap().progress() == 1.0)
-        {
-            let mut moved = false;
-            while !moved {
-                let mut end = enemy.translation;
-                match random.gen_range(0..5) {
-                    0 => {
-                        if !wall_query.iter().any(|wall| {
-                            wall.translation.x == enemy.translation.x + CELL_SIZE
-                                && wall.translation.y == enemy.translation.y
-                        }) {
-                            end.x += CELL_SIZE;
-                            moved = true;
-                        }
-                    }
-                    1 => {
-                        if !wall_query.iter().any(|wall| {
-                            wall.translation.x == enemy.translation.x
-                                && wall.translation.y == enemy.translation.y + CELL_SIZE
-                        }) {
-                            end.y += CELL_SIZE;
-                            moved = true;
-                        }
-                    }
-                    2 => {
-                        if !wall_query.iter().any(|wall| {
-                            wall.translation.x == enemy.translation.x - CELL_SIZE
-                                && wall.translation.y == enemy.translation.y
-                        }) {
-                            end.x -= CELL_SIZE;
-                            moved = true;
-                        }
-                    }
-                    3 => {
-                        if !wall_query.iter().any(|wall| {
-                            wall.translation.x == enemy.translation.x
-                                && wall.translation.y == enemy.translation.y - CELL_SIZE
-                        }) {
-                            end.y -= CELL_SIZE;
-                            moved = true;
-                        }
+    let mut solids: HashSet<(i32, i32)> = HashSet::new();
+    let mut ghost_solids: HashSet<(i32, i32)> = HashSet::new();
+    for (wall, breakable, cell, boss) in wall_query.iter() {
+        let cell = cell.map_or_else(|| position_to_cell(wall.translation), |cell| cell.0);
+        // a boss blocks every cell it covers
+        let cells = match boss {
+            Some(_) => boss_cells(cell),
+            None => vec![cell],
+        };
+        if breakable.is_none() {
+            ghost_solids.extend(cells.iter().copied());
+        }
+        solids.extend(cells);
+    }
+    // enemies block each other on the cells they are moving into, not where their sprite is drawn
+    for (.., cell) in query.iter() {
+        solids.insert(cell.0);
+        ghost_solids.insert(cell.0);
+    }
+
+    for (transform, entity, behaviour, mut enemy, ghost, speed, mut cell) in query.iter_mut() {
+        // the move timer lasts as long as a step, the tween only draws it
+        enemy.move_timer.tick(time.delta());
+        if enemy.move_timer.finished() {
+            let blocked = if ghost.is_some() {
+                &ghost_solids
+            } else {
+                &solids
+            };
+            let current = cell.0;
+            let duration = speed.step_duration(field.is_slow(current));
+            // waiting in place takes as long as a step, so slow enemies also pause longer
+            enemy.move_timer = Timer::new(duration, false);
+
+            let step = match behaviour {
+                EnemyBehaviour::Wander => wander_step(current, blocked, |_| true, &mut random),
+                EnemyBehaviour::BombAware => {
+                    if danger_map.is_dangerous(current) {
+                        escape_step(current, blocked, &danger_map)
+                    } else {
+                        wander_step(
+                            current,
+                            blocked,
+                            |next| !danger_map.is_dangerous(next),
+                            &mut random,
+                        )
                     }
-                    _ => moved = true,
                 }
-                if end != enemy.translation {
-                    commands.entity(entity).insert(Animator::new(Tween::new(
-                        EaseFunction::QuadraticIn,
-                        TweeningType::Once,
-                        Duration::from_millis(MOVE_ANIMATION_DURATION),
-                        TransformPositionLens {
-                            start: enemy.translation,
-                            end,
-                        },
-                    )));
+            };
+
+            if let Some((dx, dy)) = step {
+                cell.0 = (current.0 + dx, current.1 + dy);
+                for cells in [&mut solids, &mut ghost_solids] {
+                    cells.remove(&current);
+                    cells.insert(cell.0);
                 }
+
+                let mut end = cell_to_position(cell.0);
+                end.z = transform.translation.z;
+                commands.entity(entity).insert(Animator::new(Tween::new(
+                    EaseFunction::QuadraticIn,
+                    TweeningType::Once,
+                    duration,
+                    TransformPositionLens {
+                        start: transform.translation,
+                        end,
+                    },
+                )));
             }
         }
     }
 }
 
+pub fn spawn_enemy(
+    commands: &mut Commands,
+    index: usize,
+    archetype: &EnemyArchetype,
+    x: i32,
+    y: i32,
+    speed_scale: f32,
+) -> Entity {
+    let mut translation = cell_to_position((x, y));
+    // ghosts are drawn over the walls they walk through
+    translation.z = if archetype.pass_breakable_walls { 3. } else { 2. };
+
+    let mut enemy = commands.spawn_bundle(SpriteBundle {
+        texture: archetype.texture.clone(),
+        sprite: Sprite {
+            color: archetype.tint,
+            ..Default::default()
+        },
+        transform: Transform {
+            translation,
+            ..Default::default()
+        },
+        ..Default::default()
+    });
+    enemy
+        .insert(Enemy {
+            archetype: index,
+            health: archetype.hit_points,
+            damage: archetype.contact_damage,
+            last_hit: Duration::ZERO,
+            move_timer: Timer::from_seconds(0., false),
+        })
+        .insert(Speed::new(archetype.speed * speed_scale))
+        .insert(GridCell((x, y)))
+        .insert(archetype.behaviour)
+        .insert(Destructable)
+        .insert(Solid);
+    if archetype.pass_breakable_walls {
+        enemy.insert(Ghost);
+    }
+    enemy.id()
+}
+
+/// Picks a random free direction, or `None` to stay in place, with every option equally likely.
+fn wander_step<F>(
+    cell: (i32, i32),
+    solids: &HashSet<(i32, i32)>,
+    allowed: F,
+    random: &mut GameRng,
+) -> Option<(i32, i32)>
+where
+    F: Fn((i32, i32)) -> bool,
+{
+    let free: Vec<(i32, i32)> = DIRECTIONS
+        .iter()
+        .copied()
+        .filter(|(dx, dy)| {
+            let next = (cell.0 + dx, cell.1 + dy);
+            !solids.contains(&next) && allowed(next)
+        })
+        .collect();
+
+    free.get(random.gen_range(0..=free.len())).copied()
+}
+
+/// Returns the first step of the shortest path to a cell that no bomb is going to hit.
+/// When there is no way out, steps to the neighbour that is going to be hit last.
+fn escape_step(
+    cell: (i32, i32),
+    solids: &HashSet<(i32, i32)>,
+    danger_map: &DangerMap,
+) -> Option<(i32, i32)> {
+    let mut visited: HashSet<(i32, i32)> = HashSet::default();
+    let mut queue = EscapeQueue::new();
+    visited.insert(cell);
+
+    for (dx, dy) in DIRECTIONS {
+        let next = (cell.0 + dx, cell.1 + dy);
+        if !solids.contains(&next) && visited.insert(next) {
+            queue.push_back((next, (dx, dy), 1));
+        }
+    }
+
+    while let Some((current, first_step, depth)) = queue.pop_front() {
+        if !danger_map.is_dangerous(current) {
+            return Some(first_step);
+        }
+        if depth >= ESCAPE_SEARCH_DEPTH {
+            continue;
+        }
+        for (dx, dy) in DIRECTIONS {
+            let next = (current.0 + dx, current.1 + dy);
+            if !solids.contains(&next) && visited.insert(next) {
+                queue.push_back((next, first_step, depth + 1));
+            }
+        }
+    }
+
+    let current_time = danger_map.time_to_blast(cell).unwrap_or(0.);
+    DIRECTIONS
+        .iter()
+        .copied()
+        .filter(|(dx, dy)| !solids.contains(&(cell.0 + dx, cell.1 + dy)))
+        .map(|(dx, dy)| {
+            let time = danger_map
+                .time_to_blast((cell.0 + dx, cell.1 + dy))
+                .unwrap_or(f32::MAX);
+            ((dx, dy), time)
+        })
+        .filter(|(_, time)| *time > current_time)
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map(|(direction, _)| direction)
+}
+
+/// Fades ghosts out while they are inside a breakable wall.
+pub fn ghost_transparency_system(
+    mut ghost_query: Query<(&Transform, &mut Sprite), With<Ghost>>,
+    wall_query: Query<&Transform, With<BreakableWall>>,
+) {
+    let walls: HashSet<(i32, i32)> = wall_query
+        .iter()
+        .map(|wall| position_to_cell(wall.translation))
+        .collect();
+
+    for (transform, mut sprite) in ghost_query.iter_mut() {
+        let alpha = if walls.contains(&position_to_cell(transform.translation)) {
+            GHOST_ALPHA
+        } else {
+            1.
+        };
+        if sprite.color.a() != alpha {
+            sprite.color.set_a(alpha);
+        }
+    }
+}
+
 pub fn enemy_kill_player_system(
-    mut player_query: Query<(&Transform, &mut Player, &mut TextureAtlasSprite), With<Player>>,
-    enemy_query: Query<&Transform, With<Enemy>>,
-    time: Res<Time>,
+    mut player_query: Query<
+        (&GridCell, &mut Player, &mut TextureAtlasSprite, &PlayerId),
+        Without<Invulnerable>,
+    >,
+    enemy_query: Query<(&GridCell, &Enemy)>,
+    time: Res<SimTime>,
 ) {
-    if let Ok(mut player) = player_query.get_single_mut() {
-        let attacks = enemy_query
+    for mut player in player_query.iter_mut() {
+        let damage: i32 = enemy_query
             .iter()
-            .filter(|enemy| is_equal_approximate(player.0, enemy))
-            .count();
+            .filter(|enemy| enemy.0 == player.0)
+            .map(|enemy| enemy.1.damage)
+            .sum();
 
-        if attacks > 0 && player.1.last_hit.elapsed().as_millis() > 150 {
-            player.1.health -= attacks as i32 * DAMAGE;
-            player.1.last_hit = time.startup() + time.time_since_startup();
+        if damage > 0 && time.since(player.1.last_hit).as_millis() > 150 {
+            player.1.health -= damage;
+            player.1.last_hit = time.elapsed();
             player.2.color = Color::RED;
         }
 
-        if player.1.last_hit.elapsed().as_millis() > 200 {
-            player.2.color = Color::WHITE;
+        if time.since(player.1.last_hit).as_millis() > 200 {
+            player.2.color = PLAYER_COLORS[player.3 .0 - 1];
         }
     }
 }
@@ -107,11 +290,234 @@ pub fn enemy_kill_player_system(
 pub fn debug_kill_enemy(
     mut commands: Commands,
     query: Query<Entity, With<Enemy>>,
-    key: Res<Input<KeyCode>>,
+    actions: Res<ActionInput>,
 ) {
-    if key.just_pressed(KeyCode::K) {
+    if actions.global_just_pressed(GlobalAction::DebugKillEnemy) {
         if let Some(enemy) = query.iter().next() {
             commands.entity(enemy).despawn();
         }
     }
 }
+
+pub fn load_enemy_archetypes(asset_server: &AssetServer) -> EnemyArchetypes {
+    let mut list = match fs::read_to_string(asset_path(ENEMY_DEFINITIONS_FILE)) {
+        Ok(text) => parse_enemy_archetypes(&text, asset_server),
+        Err(_) => {
+            println!("Can't read the enemy definitions.");
+            Vec::new()
+        }
+    };
+
+    if list.is_empty() {
+        println!("No enemy archetypes defined, using the default enemy.");
+        let mut archetype = default_archetype("grunt", asset_server);
+        archetype.glyph = 'E';
+        list.push(archetype);
+    }
+
+    EnemyArchetypes { list }
+}
+
+fn default_archetype(name: &str, asset_server: &AssetServer) -> EnemyArchetype {
+    EnemyArchetype {
+        name: name.to_string(),
+        glyph: '\0',
+        texture: asset_server.load("enemy.png"),
+        tint: Color::WHITE,
+        speed: 4.,
+        hit_points: 1,
+        contact_damage: DAMAGE,
+        behaviour: EnemyBehaviour::BombAware,
+        pass_breakable_walls: false,
+        score: 100,
+    }
+}
+
+fn parse_enemy_archetypes(text: &str, asset_server: &AssetServer) -> Vec<EnemyArchetype> {
+    let mut list: Vec<EnemyArchetype> = Vec::new();
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            list.push(default_archetype(name.trim(), asset_server));
+            continue;
+        }
+
+        let archetype = match list.last_mut() {
+            Some(archetype) => archetype,
+            None => {
+                println!("Enemy property outside of an archetype: {}", line);
+                continue;
+            }
+        };
+
+        match line.split_once('=') {
+            Some((key, value)) => {
+                set_archetype_property(archetype, key.trim(), value.trim(), asset_server)
+            }
+            None => println!("Can't parse enemy definition line: {}", line),
+        }
+    }
+
+    let mut glyphs: Vec<char> = Vec::new();
+    list.retain(|archetype| {
+        if archetype.glyph == '\0' {
+            println!("Enemy archetype [{}] has no glyph.", archetype.name);
+            false
+        } else if RESERVED_GLYPHS.contains(&archetype.glyph) || glyphs.contains(&archetype.glyph) {
+            println!(
+                "Glyph '{}' of enemy archetype [{}] is already in use.",
+                archetype.glyph, archetype.name
+            );
+            false
+        } else {
+            glyphs.push(archetype.glyph);
+            true
+        }
+    });
+
+    list
+}
+
+fn set_archetype_property(
+    archetype: &mut EnemyArchetype,
+    key: &str,
+    value: &str,
+    asset_server: &AssetServer,
+) {
+    let parsed = match key {
+        "glyph" => {
+            let mut chars = value.chars();
+            match (chars.next(), chars.next()) {
+                (Some(glyph), None) => {
+                    archetype.glyph = glyph;
+                    Some(())
+                }
+                _ => None,
+            }
+        }
+        "sprite" => {
+            archetype.texture = asset_server.load(value);
+            Some(())
+        }
+        "tint" => parse_color(value).map(|tint| archetype.tint = tint),
+        "speed" => value
+            .parse::<f32>()
+            .ok()
+            .filter(|speed| *speed > 0.)
+            .map(|speed| archetype.speed = speed),
+        "hit_points" => value
+            .parse::<i32>()
+            .ok()
+            .filter(|hit_points| *hit_points > 0)
+            .map(|hit_points| archetype.hit_points = hit_points),
+        "contact_damage" => value
+            .parse::<i32>()
+            .ok()
+            .filter(|damage| *damage >= 0)
+            .map(|damage| archetype.contact_damage = damage),
+        "behaviour" => parse_behaviour(value).map(|behaviour| archetype.behaviour = behaviour),
+        "pass_breakable_walls" => value
+            .parse::<bool>()
+            .ok()
+            .map(|pass| archetype.pass_breakable_walls = pass),
+        "score" => value
+            .parse::<u32>()
+            .ok()
+            .map(|score| archetype.score = score),
+        _ => {
+            println!("Unknown enemy property '{}' in [{}].", key, archetype.name);
+            return;
+        }
+    };
+
+    if parsed.is_none() {
+        println!(
+            "Invalid value '{}' for '{}' in [{}].",
+            value, key, archetype.name
+        );
+    }
+}
+
+fn parse_color(value: &str) -> Option<Color> {
+    let channels: Vec<f32> = value
+        .split(',')
+        .map(|channel| channel.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    match channels[..] {
+        [r, g, b] => Some(Color::rgb(r, g, b)),
+        _ => None,
+    }
+}
+
+fn parse_behaviour(value: &str) -> Option<EnemyBehaviour> {
+    match value {
+        "wander" => Some(EnemyBehaviour::Wander),
+        "bomb_aware" => Some(EnemyBehaviour::BombAware),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::asset::FileAssetIo;
+    use bevy::tasks::{IoTaskPool, TaskPool};
+
+    fn asset_server() -> AssetServer {
+        IoTaskPool::init(TaskPool::default);
+        AssetServer::new(FileAssetIo::new("assets", false))
+    }
+
+    #[test]
+    fn parses_archetype_sections() {
+        let text = "# comment\n[runner]\nglyph = R\ntint = 1.0, 1.0, 0.5 # yellow\nspeed = 7\n\
+                    hit_points = 2\ncontact_damage = 0\nbehaviour = wander\n\
+                    pass_breakable_walls = true\nscore = 200\n\n[grunt]\nglyph = E\n";
+        let list = parse_enemy_archetypes(text, &asset_server());
+
+        assert_eq!(list.len(), 2);
+        let runner = &list[0];
+        assert_eq!(runner.name, "runner");
+        assert_eq!(runner.glyph, 'R');
+        assert_eq!(runner.tint, Color::rgb(1., 1., 0.5));
+        assert_eq!(runner.speed, 7.);
+        assert_eq!(runner.hit_points, 2);
+        assert_eq!(runner.contact_damage, 0);
+        assert_eq!(runner.behaviour, EnemyBehaviour::Wander);
+        assert!(runner.pass_breakable_walls);
+        assert_eq!(runner.score, 200);
+        assert_eq!(list[1].name, "grunt");
+        assert_eq!(list[1].speed, 4.);
+    }
+
+    #[test]
+    fn keeps_defaults_for_invalid_values() {
+        let text = "[grunt]\nglyph = E\nspeed = 0\nhit_points = 0\ncontact_damage = -1\n\
+                    behaviour = fly\ntint = 1.0, 0.5\nunknown = 3\n";
+        let list = parse_enemy_archetypes(text, &asset_server());
+
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].speed, 4.);
+        assert_eq!(list[0].hit_points, 1);
+        assert_eq!(list[0].contact_damage, DAMAGE);
+        assert_eq!(list[0].behaviour, EnemyBehaviour::BombAware);
+        assert_eq!(list[0].tint, Color::WHITE);
+    }
+
+    #[test]
+    fn drops_archetypes_without_a_free_glyph() {
+        let text = "glyph = Q\n[none]\n[wall]\nglyph = W\n[first]\nglyph = E\n\
+                    [second]\nglyph = E\n[long]\nglyph = EE\n";
+        let list = parse_enemy_archetypes(text, &asset_server());
+
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].name, "first");
+    }
+}
@@ -0,0 +1,226 @@
+use crate::boss_systems::boss_cells;
+use crate::components::*;
+use crate::constants::*;
+use crate::headless_app;
+use crate::state_systems::{apply_pending_state_system, report_state_error};
+use crate::utils::position_to_cell;
+use bevy::prelude::*;
+
+/// A headless game to train agents against, which plays one tick a step as fast as it is
+/// stepped:
+///
+/// ```ignore
+/// let mut env = Environment::new(GameMode::Battle, 2);
+/// let mut observation = env.reset(seed, 1);
+/// let (observation, rewards, done) = env.step(&[Some(Action::PlaceBomb), None]);
+/// ```
+pub struct Environment {
+    app: App,
+    mode: GameMode,
+    players: usize,
+    bots: usize,
+    bot_difficulty: Difficulty,
+    observation: Observation,
+    done: bool,
+}
+
+impl Environment {
+    /// A game of `mode` for `players` players, at most `MAX_PLAYERS`. Nothing is played until the
+    /// first `reset`.
+    pub fn new(mode: GameMode, players: usize) -> Self {
+        let mut app = headless_app();
+        app.insert_resource(FixedTick)
+            .insert_resource(EnvOutcome::default())
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                record_outcome_system.before(apply_pending_state_system),
+            );
+        // runs the startup systems
+        app.update();
+        let observation = observe(&mut app.world);
+
+        Environment {
+            app,
+            mode,
+            players: players.clamp(1, MAX_PLAYERS),
+            bots: 0,
+            bot_difficulty: Difficulty::Normal,
+            observation,
+            done: true,
+        }
+    }
+
+    /// Hands the last `count` players to `bot_system` from the next `reset` on, their actions in
+    /// `step` are ignored.
+    pub fn set_bots(&mut self, count: usize, difficulty: Difficulty) {
+        self.bots = count.min(self.players);
+        self.bot_difficulty = difficulty;
+    }
+
+    /// Reads the levels from `pack` from the next `reset` on.
+    pub fn set_level_pack(&mut self, pack: LevelPack) {
+        self.app.insert_resource(pack);
+    }
+
+    /// Starts `level` over with the random numbers of `seed`, skipping its intro screen. A level
+    /// that can't be loaded gives an empty field that is done right away.
+    pub fn reset(&mut self, seed: u64, level: u32) -> Observation {
+        let world = &mut self.app.world;
+        let mut settings = world.resource_mut::<GameSettings>();
+        settings.mode = self.mode;
+        settings.player_count = self.players;
+        settings.bots = self.bots;
+        settings.bot_difficulty = self.bot_difficulty;
+        world.resource_mut::<Field>().current_level = level;
+        *world.resource_mut::<GameRng>() = GameRng::new(seed);
+        *world.resource_mut::<Bots>() = Bots::new(seed);
+        *world.resource_mut::<Scores>() = Scores::default();
+        *world.resource_mut::<Lives>() = Lives::default();
+        *world.resource_mut::<Battle>() = Battle::new(BATTLE_BEST_OF);
+        *world.resource_mut::<SimTime>() = SimTime::default();
+        *world.resource_mut::<ActionInput>() = ActionInput::default();
+        world.resource_mut::<EnvOutcome>().0 = None;
+
+        // the level is loaded on one update and spawned on the next
+        self.set_state(AppState::Loading);
+        self.app.update();
+        let loaded = self
+            .app
+            .world
+            .resource::<Field>()
+            .array
+            .iter()
+            .flatten()
+            .any(|code| *code != 0);
+        self.set_state(AppState::Playing);
+        self.app.update();
+
+        self.observation = observe(&mut self.app.world);
+        self.done = !loaded;
+        self.observation.clone()
+    }
+
+    /// Plays a tick with the action of every player, indexed by player id - 1. Returns the reward
+    /// of every player, the points it scored less `HIT_PENALTY` for every point of health it
+    /// lost, and whether the level is over. Once it is, steps change nothing until the next
+    /// `reset`.
+    pub fn step(&mut self, actions: &[Option<Action>]) -> (Observation, [f32; MAX_PLAYERS], bool) {
+        let pressed: Vec<Vec<Action>> = actions
+            .iter()
+            .map(|action| action.iter().copied().collect())
+            .collect();
+        self.step_pressed(&pressed)
+    }
+
+    /// Like `step`, with any number of actions pressed by every player.
+    pub fn step_pressed(
+        &mut self,
+        pressed: &[Vec<Action>],
+    ) -> (Observation, [f32; MAX_PLAYERS], bool) {
+        if self.done {
+            return (self.observation.clone(), [0.; MAX_PLAYERS], true);
+        }
+
+        let world = &mut self.app.world;
+        let mut input = ActionInput {
+            previous: std::mem::take(&mut world.resource_mut::<ActionInput>().pressed),
+            ..default()
+        };
+        for (index, actions) in pressed.iter().enumerate().take(self.players) {
+            for action in actions {
+                input.press(PlayerId(index + 1), *action);
+            }
+        }
+        *world.resource_mut::<ActionInput>() = input;
+        self.app.update();
+
+        let observation = observe(&mut self.app.world);
+        let mut rewards = [0.; MAX_PLAYERS];
+        for (index, reward) in rewards.iter_mut().enumerate() {
+            let scored = observation.points[index].saturating_sub(self.observation.points[index]);
+            let lost = (self.observation.health[index] - observation.health[index]).max(0);
+            *reward = scored as f32 - lost as f32 * HIT_PENALTY;
+        }
+        self.done = self.app.world.resource::<EnvOutcome>().0.is_some();
+        self.observation = observation.clone();
+        (observation, rewards, self.done)
+    }
+
+    /// What every player pressed on the last step, the bots included, indexed by player id - 1.
+    pub fn last_pressed(&self) -> Vec<Vec<Action>> {
+        let input = self.app.world.resource::<ActionInput>();
+        (1..=self.players)
+            .map(|id| {
+                Action::ALL
+                    .into_iter()
+                    .filter(|action| input.pressed(PlayerId(id), *action))
+                    .collect()
+            })
+            .collect()
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.app.world
+    }
+
+    fn set_state(&mut self, next: AppState) {
+        let mut state = self.app.world.resource_mut::<State<AppState>>();
+        if *state.current() != next {
+            report_state_error(state.overwrite_set(next));
+        }
+    }
+}
+
+/// Keeps the state change a gameplay system asked for, which ends the episode.
+pub fn record_outcome_system(pending: Res<PendingState>, mut outcome: ResMut<EnvOutcome>) {
+    if pending.0.is_some() {
+        outcome.0 = pending.0;
+    }
+}
+
+fn observe(world: &mut World) -> Observation {
+    let mut observation = Observation {
+        grid: vec![0.; OBSERVATION_CHANNELS * SIZE_IN_CELLS * SIZE_IN_CELLS],
+        health: [0; MAX_PLAYERS],
+        points: [0; MAX_PLAYERS],
+        time: world.resource::<Field>().time,
+    };
+    for (index, score) in world.resource::<Scores>().players.iter().enumerate() {
+        observation.points[index] = score.points;
+    }
+
+    for transform in world.query_filtered::<&Transform, With<Wall>>().iter(world) {
+        observation.set(Channel::Wall, position_to_cell(transform.translation), 1.);
+    }
+    for transform in world
+        .query_filtered::<&Transform, Or<(With<BreakableWall>, With<Spawner>)>>()
+        .iter(world)
+    {
+        let cell = position_to_cell(transform.translation);
+        observation.set(Channel::BreakableWall, cell, 1.);
+    }
+    for (bomb, transform) in world.query::<(&Bomb, &Transform)>().iter(world) {
+        let cell = position_to_cell(transform.translation);
+        observation.set(Channel::Bomb, cell, bomb.fuse.percent_left());
+    }
+    for transform in world
+        .query_filtered::<&Transform, With<Explosion>>()
+        .iter(world)
+    {
+        observation.set(Channel::Flame, position_to_cell(transform.translation), 1.);
+    }
+    for cell in world.query_filtered::<&GridCell, With<Enemy>>().iter(world) {
+        observation.set(Channel::Enemy, cell.0, 1.);
+    }
+    for cell in world.query_filtered::<&GridCell, With<Boss>>().iter(world) {
+        for cell in boss_cells(cell.0) {
+            observation.set(Channel::Enemy, cell, 1.);
+        }
+    }
+    for (id, player, cell) in world.query::<(&PlayerId, &Player, &GridCell)>().iter(world) {
+        observation.set(Channel::Player(*id), cell.0, 1.);
+        observation.health[id.0 - 1] = player.health.max(0);
+    }
+
+    observation
+}
@@ -1,142 +1,259 @@
 use crate::components::*;
 use crate::constants::*;
-use bevy::app::AppExit;
+use crate::boss_systems::spawn_boss;
+use crate::enemy_systems::spawn_enemy;
+use crate::player_systems::spawn_player;
+use crate::save_systems::restore_entities;
+use crate::state_systems::change_state;
+use crate::utils::cell_to_position;
 use bevy::prelude::*;
-use bevy::utils::Instant;
 use std::fs;
+use std::path::Path;
 
-pub fn load_field_system(mut field: ResMut<Field>, mut exit: EventWriter<AppExit>) {
-    if !field.loaded {
-        println!("Loading level...");
-        field.array = load_level(field.current_level).unwrap_or_else(|| {
-            println!("Can't load the level.");
-            exit.send(AppExit);
-            [[0; SIZE_IN_CELLS]; SIZE_IN_CELLS]
-        });
-        field.loaded = true;
-        println!("Level loaded.");
-    }
+/// Reads the current level on entering `AppState::Loading`, going back to the main menu when it can't.
+pub fn load_field_system(
+    mut field: ResMut<Field>,
+    archetypes: Res<EnemyArchetypes>,
+    settings: Res<GameSettings>,
+    pack: Res<LevelPack>,
+    mut hurry_up: ResMut<HurryUp>,
+    mut state: ResMut<State<AppState>>,
+) {
+    println!("Loading level...");
+    let path = level_path(&pack, settings.mode, field.current_level);
+    let (array, properties) = load_level(&path, &archetypes).unwrap_or_else(|| {
+        println!("Can't load the level.");
+        change_state(&mut state, AppState::MainMenu);
+        ([[0; SIZE_IN_CELLS]; SIZE_IN_CELLS], LevelProperties::default())
+    });
+    field.array = array;
+    field.properties = properties;
+    field.boss_defeated = false;
+    field.time = 0.;
+    *hurry_up = HurryUp::default();
+    println!("Level loaded.");
+}
+
+pub fn level_time_system(mut field: ResMut<Field>, time: Res<SimTime>) {
+    field.time += time.delta_seconds();
 }
 
+/// Spawns the loaded level when the "Level N" screen is left.
 pub fn spawn_field_system(
     mut commands: Commands,
-    mut field: ResMut<Field>,
+    field: Res<Field>,
     textures: Res<GameTextures>,
+    archetypes: Res<EnemyArchetypes>,
+    settings: Res<GameSettings>,
+    mut saved_game: ResMut<SavedGame>,
 ) {
-    if !field.spawned && field.loaded {
-        for i in 0..field.array.len() {
-            for j in 0..field.array[0].len() {
-                match field.array[i][j] {
-                    1 => {
-                        commands
-                            .spawn_bundle(SpriteBundle {
-                                texture: textures.wall.clone(),
-
-                                transform: Transform {
-                                    translation: Vec3 {
-                                        x: i as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        y: j as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        z: 2.,
-                                    },
-                                    ..Default::default()
-                                },
-                                ..Default::default()
-                            })
-                            .insert(Wall)
-                            .insert(Solid);
-                    }
-                    2 => {
-                        commands
-                            .spawn_bundle(SpriteBundle {
-                                texture: textures.wood.clone(),
-                                transform: Transform {
-                                    translation: Vec3 {
-                                        x: i as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        y: j as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        z: 2.,
-                                    },
-                                    ..Default::default()
+    for i in 0..field.array.len() {
+        for j in 0..field.array[0].len() {
+            match field.array[i][j] {
+                1 => {
+                    commands
+                        .spawn_bundle(SpriteBundle {
+                            texture: textures.wall.clone(),
+
+                            transform: Transform {
+                                translation: Vec3 {
+                                    x: i as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
+                                    y: j as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
+                                    z: 2.,
                                 },
                                 ..Default::default()
-                            })
-                            .insert(BreakableWall)
-                            .insert(Solid)
-                            .insert(Destructable);
-                    }
-                    3 => {
-                        commands
-                            .spawn_bundle(SpriteBundle {
-                                texture: textures.enemy.clone(),
-                                transform: Transform {
-                                    translation: Vec3 {
-                                        x: i as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        y: j as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        z: 2.,
-                                    },
-                                    ..Default::default()
-                                },
+                            },
+                            ..Default::default()
+                        })
+                        .insert(Wall)
+                        .insert(Solid);
+                }
+                2 => spawn_breakable_wall(&mut commands, &textures, (i as i32, j as i32)),
+                5 => {
+                    spawn_boss(&mut commands, textures.boss.clone(), i as i32, j as i32);
+                }
+                6 => spawn_spawner(
+                    &mut commands,
+                    &textures,
+                    (i as i32, j as i32),
+                    new_spawner(&field.properties),
+                ),
+                7 => {
+                    commands
+                        .spawn_bundle(SpriteBundle {
+                            sprite: Sprite {
+                                color: Color::rgb(0.2, 0.3, 0.6),
+                                custom_size: Some(Vec2::splat(CELL_SIZE)),
                                 ..Default::default()
-                            })
-                            .insert(Enemy)
-                            .insert(Destructable)
-                            .insert(Solid);
-                    }
-                    4 => {
-                        commands
-                            .spawn_bundle(SpriteSheetBundle {
-                                texture_atlas: textures.player.clone(),
-                                transform: Transform {
-                                    translation: Vec3 {
-                                        x: i as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        y: j as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
-                                        z: 1.,
-                                    },
-                                    ..Default::default()
+                            },
+                            transform: Transform {
+                                translation: Vec3 {
+                                    x: i as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
+                                    y: j as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
+                                    z: 0.,
                                 },
                                 ..Default::default()
-                            })
-                            .insert(Player {
-                                health: 10,
-                                last_hit: Instant::now(),
-                            });
+                            },
+                            ..Default::default()
+                        })
+                        .insert(SlowTile);
+                }
+                8 => spawn_speed_pickup(&mut commands, (i as i32, j as i32)),
+                code if code >= ENEMY_CODE => {
+                    if let Some((index, archetype)) = archetypes.from_code(code) {
+                        spawn_enemy(
+                            &mut commands,
+                            index,
+                            archetype,
+                            i as i32,
+                            j as i32,
+                            settings.difficulty.enemy_speed(),
+                        );
                     }
-                    _ => {}
+                }
+                _ => {}
+            }
+        }
+    }
+
+    match saved_game.0.take() {
+        // the saved field only has the cells that never change, the rest comes from the save
+        Some(save) => restore_entities(
+            &mut commands,
+            &save,
+            &textures,
+            &archetypes,
+            &settings,
+            &field.properties,
+        ),
+        None => {
+            for id in (1..=settings.player_count).map(PlayerId) {
+                // players without a spawn cell of their own start on player 1's
+                if let Some(cell) = field.spawn_cell(id).or_else(|| field.spawn_cell(PlayerId(1))) {
+                    spawn_player(&mut commands, &textures, id, cell, settings.mode.player_health());
                 }
             }
         }
-        field.spawned = true;
-        println!("Field spawned");
     }
+
+    println!("Field spawned");
+}
+
+pub fn spawn_breakable_wall(commands: &mut Commands, textures: &GameTextures, cell: (i32, i32)) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture: textures.wood.clone(),
+            transform: Transform::from_translation(cell_to_position(cell)),
+            ..Default::default()
+        })
+        .insert(BreakableWall)
+        .insert(Solid)
+        .insert(Destructable);
+}
+
+/// A spawner that hasn't emitted anything yet.
+pub fn new_spawner(properties: &LevelProperties) -> Spawner {
+    Spawner {
+        age: 0.,
+        spawn_timer: properties
+            .spawn_every
+            .as_ref()
+            .map_or(Timer::default(), |schedule| {
+                Timer::from_seconds(schedule.interval, true)
+            }),
+        spawned: 0,
+        next_wave: 0,
+        queue: Vec::new(),
+    }
+}
+
+pub fn spawn_spawner(
+    commands: &mut Commands,
+    textures: &GameTextures,
+    cell: (i32, i32),
+    spawner: Spawner,
+) {
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture: textures.spawner.clone(),
+            sprite: Sprite {
+                color: Color::rgb(0.7, 0.3, 0.9),
+                ..Default::default()
+            },
+            transform: Transform::from_translation(cell_to_position(cell)),
+            ..Default::default()
+        })
+        .insert(spawner)
+        .insert(Solid)
+        .insert(Destructable);
+}
+
+pub fn spawn_speed_pickup(commands: &mut Commands, cell: (i32, i32)) {
+    let mut translation = cell_to_position(cell);
+    translation.z = 0.5;
+
+    commands
+        .spawn_bundle(SpriteBundle {
+            sprite: Sprite {
+                color: Color::rgb(0.3, 0.9, 1.),
+                custom_size: Some(Vec2::splat(CELL_SIZE / 2.)),
+                ..Default::default()
+            },
+            transform: Transform::from_translation(translation),
+            ..Default::default()
+        })
+        .insert(SpeedPickup);
 }
 
 pub fn complete_level_system(
-    mut field: ResMut<Field>,
+    field: Res<Field>,
     query: Query<&Enemy>,
-    mut commands: Commands,
-    entities: Query<Entity, With<Solid>>,
-    player: Query<Entity, With<Player>>,
+    boss_query: Query<&Boss>,
+    spawner_query: Query<&Spawner>,
+    settings: Res<GameSettings>,
+    mut pending: ResMut<PendingState>,
 ) {
-    if query.is_empty() {
-        field.current_level += 1;
-        field.loaded = false;
-        field.spawned = false;
+    if settings.mode != GameMode::Campaign {
+        return;
+    }
 
-        if let Ok(player_entity) = player.get_single() {
-            commands.entity(player_entity).despawn();
-        }
+    let spawners_active = spawner_query
+        .iter()
+        .any(|spawner| spawner.is_active(&field.properties));
 
-        entities.for_each(|entity| {
-            commands.entity(entity).despawn();
-        })
+    // on boss levels the minions left behind don't keep the level going
+    if boss_query.is_empty() && !spawners_active && (query.is_empty() || field.boss_defeated) {
+        pending.set(AppState::LevelComplete);
     }
 }
 
-fn load_level(index: u32) -> Option<[[i32; SIZE_IN_CELLS]; SIZE_IN_CELLS]> {
-    let level = fs::read_to_string(format!("assets/{}.level", index));
+/// Campaign levels are `<pack>/<index>.level`, battle arenas are `<pack>/arena<index>.level`.
+pub fn level_path(pack: &LevelPack, mode: GameMode, index: u32) -> String {
+    match (&pack.file, mode) {
+        (Some(file), _) => file.clone(),
+        (None, GameMode::Campaign) => format!("{}/{}.level", pack.dir, index),
+        (None, GameMode::Battle) => format!("{}/arena{}.level", pack.dir, index),
+    }
+}
+
+/// Whether the pack goes on to this level, a level file being level 1 and the only one.
+pub fn level_exists(pack: &LevelPack, mode: GameMode, index: u32) -> bool {
+    match pack.file {
+        Some(_) => index == 1,
+        None => Path::new(&level_path(pack, mode, index)).exists(),
+    }
+}
+
+fn load_level(
+    path: &str,
+    archetypes: &EnemyArchetypes,
+) -> Option<([[i32; SIZE_IN_CELLS]; SIZE_IN_CELLS], LevelProperties)> {
+    let level = fs::read_to_string(path);
     match level {
         Ok(text) => {
             println!("File is loaded. ({})", text);
-            Some(create_level_from_string(text))
+            let properties = create_properties_from_string(&text);
+            Some((create_level_from_string(text, archetypes), properties))
         }
         Err(_) => {
             println!("Can't read the file.");
@@ -145,14 +262,16 @@ fn load_level(index: u32) -> Option<[[i32; SIZE_IN_CELLS]; SIZE_IN_CELLS]> {
     }
 }
 
-fn create_level_from_string(level_data: String) -> [[i32; SIZE_IN_CELLS]; SIZE_IN_CELLS] {
+fn create_level_from_string(
+    level_data: String,
+    archetypes: &EnemyArchetypes,
+) -> [[i32; SIZE_IN_CELLS]; SIZE_IN_CELLS] {
     let mut array = [[0; SIZE_IN_CELLS]; SIZE_IN_CELLS];
 
     let level_vec: Vec<Vec<char>> = level_data
         .split('\n')
-        .map(|line| {
-            return line.chars().collect::<Vec<char>>();
-        })
+        .take(SIZE_IN_CELLS)
+        .map(|line| line.trim_end().chars().collect::<Vec<char>>())
         .collect();
 
     if level_vec
@@ -160,27 +279,159 @@ fn create_level_from_string(level_data: String) -> [[i32; SIZE_IN_CELLS]; SIZE_I
         .all(|char_vec| { char_vec.len() == SIZE_IN_CELLS } && level_vec.len() == SIZE_IN_CELLS)
     {
         for (i,char_vec) in level_vec.iter().enumerate().take(SIZE_IN_CELLS) {
-            for j in 0..SIZE_IN_CELLS {
-                    if let Some(char) = char_vec.get(j) {
-                        match char {
-                            'W' => {
-                                array[i][j] = 1; // Wall
-                            }
-                            'B' => {
-                                array[i][j] = 2; // Breakable wall
-                            }
-                            'E' => {
-                                array[i][j] = 3; // Enemy
-                            }
-                            'S' => {
-                                array[i][j] = 4; // Player spawn
-                            }
-                            _ => (),
+            for (j, char) in char_vec.iter().enumerate() {
+                match char {
+                    'W' => {
+                        array[i][j] = 1; // Wall
+                    }
+                    'B' => {
+                        array[i][j] = 2; // Breakable wall
+                    }
+                    'S' | '1' => {
+                        array[i][j] = PLAYER_CODE + 1; // Player spawn
+                    }
+                    '2'..='4' => {
+                        array[i][j] = PLAYER_CODE + *char as i32 - '0' as i32; // Spawn of players 2 to 4
+                    }
+                    'X' => {
+                        array[i][j] = 5; // Boss, covering BOSS_SIZE cells from here
+                    }
+                    'P' => {
+                        array[i][j] = 6; // Enemy spawner
+                    }
+                    '~' => {
+                        array[i][j] = 7; // Slow tile
+                    }
+                    '+' => {
+                        array[i][j] = 8; // Speed pickup
+                    }
+                    glyph => {
+                        if let Some(code) = archetypes.code_for_glyph(*glyph) {
+                            array[i][j] = code; // Enemy
                         }
                     }
+                }
             }
         }
     }
 
     array
 }
+
+/// Reads the `key = value` lines following the field grid.
+fn create_properties_from_string(level_data: &str) -> LevelProperties {
+    let mut properties = LevelProperties::default();
+
+    for line in level_data.split('\n').skip(SIZE_IN_CELLS) {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let parsed = line.split_once('=').and_then(|(key, value)| {
+            let values: Vec<&str> = value.split_whitespace().collect();
+            match key.trim() {
+                "spawn_every" => {
+                    parse_schedule(&values).map(|schedule| properties.spawn_every = Some(schedule))
+                }
+                "wave" => parse_wave(&values).map(|wave| properties.waves.push(wave)),
+                "time_limit" => parse_time_limit(&values)
+                    .map(|time_limit| properties.time_limit = Some(time_limit)),
+                _ => None,
+            }
+        });
+
+        if parsed.is_none() {
+            println!("Can't parse level property: {}", line);
+        }
+    }
+
+    properties
+        .waves
+        .sort_by(|a, b| a.time.total_cmp(&b.time));
+    properties
+}
+
+/// `spawn_every = <seconds> <enemy glyph> <count>`
+fn parse_schedule(values: &[&str]) -> Option<SpawnerSchedule> {
+    match values {
+        [interval, glyph, count] => Some(SpawnerSchedule {
+            interval: interval.parse::<f32>().ok().filter(|interval| *interval > 0.)?,
+            glyph: parse_glyph(glyph)?,
+            count: count.parse::<u32>().ok()?,
+        }),
+        _ => None,
+    }
+}
+
+/// `wave = <seconds since the level started> <enemy glyph>...`
+fn parse_wave(values: &[&str]) -> Option<Wave> {
+    match values.split_first() {
+        Some((time, glyphs)) if !glyphs.is_empty() => Some(Wave {
+            time: time.parse::<f32>().ok()?,
+            glyphs: glyphs
+                .iter()
+                .map(|glyph| parse_glyph(glyph))
+                .collect::<Option<Vec<char>>>()?,
+        }),
+        _ => None,
+    }
+}
+
+/// `time_limit = <seconds>`
+fn parse_time_limit(values: &[&str]) -> Option<f32> {
+    match values {
+        [seconds] => seconds.parse::<f32>().ok().filter(|seconds| *seconds > 0.),
+        _ => None,
+    }
+}
+
+fn parse_glyph(value: &str) -> Option<char> {
+    let mut chars = value.chars();
+    match (chars.next(), chars.next()) {
+        (Some(glyph), None) => Some(glyph),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_with_properties(properties: &str) -> String {
+        let row = ".".repeat(SIZE_IN_CELLS);
+        let grid = vec![row.as_str(); SIZE_IN_CELLS].join("\n");
+        format!("{}\n{}", grid, properties)
+    }
+
+    #[test]
+    fn parses_spawner_schedule_and_waves() {
+        let text = level_with_properties(
+            "spawn_every = 12 E 3 # comment\n\nwave = 60 T\nwave = 30 E R\n",
+        );
+        let properties = create_properties_from_string(&text);
+
+        let schedule = properties.spawn_every.expect("no spawner schedule");
+        assert_eq!(schedule.interval, 12.);
+        assert_eq!(schedule.glyph, 'E');
+        assert_eq!(schedule.count, 3);
+        let waves: Vec<(f32, Vec<char>)> = properties
+            .waves
+            .into_iter()
+            .map(|wave| (wave.time, wave.glyphs))
+            .collect();
+        assert_eq!(waves, vec![(30., vec!['E', 'R']), (60., vec!['T'])]);
+    }
+
+    #[test]
+    fn skips_invalid_properties() {
+        let text = level_with_properties(
+            "spawn_every = 0 E 3\nspawn_every = 5 EE 3\nspawn_every = 5 E\n\
+             wave = 10\nwave = soon E\nwave = 10 E RR\nwaves = 10 E\nwave\n",
+        );
+        let properties = create_properties_from_string(&text);
+
+        assert!(properties.spawn_every.is_none());
+        assert!(properties.waves.is_empty());
+    }
+}
@@ -0,0 +1,289 @@
+use crate::components::*;
+use crate::constants::*;
+use crate::player_systems::PLAYER_COLORS;
+use bevy::prelude::*;
+
+const HEART_COLOR: Color = Color::rgb(0.9, 0.15, 0.2);
+const LOST_HEART_COLOR: Color = Color::rgb(0.25, 0.25, 0.25);
+
+/// Spawns the HUD bar of a level, filled in by the other HUD systems as things change.
+pub fn spawn_hud_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    field: Res<Field>,
+    settings: Res<GameSettings>,
+    battle: Res<Battle>,
+) {
+    let font = asset_server.load("FiraSans-Regular.ttf");
+    let style = |font_size: f32, color: Color| TextStyle {
+        font: font.clone(),
+        font_size,
+        color,
+    };
+    let level_name = match settings.mode {
+        GameMode::Campaign => format!("Level {}", field.current_level),
+        GameMode::Battle => format!("Round {} - Arena {}", battle.round, field.current_level),
+    };
+    let hearts = settings.mode.player_health();
+
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    top: Val::Px(0.),
+                    left: Val::Px(0.),
+                    ..default()
+                },
+                size: Size::new(Val::Percent(100.), Val::Px(HUD_HEIGHT)),
+                justify_content: JustifyContent::SpaceBetween,
+                align_items: AlignItems::Center,
+                padding: UiRect::all(Val::Px(4.)),
+                ..default()
+            },
+            color: Color::rgb(0.1, 0.1, 0.12).into(),
+            ..default()
+        })
+        .insert(Hud)
+        .with_children(|parent| {
+            for id in (1..=MAX_PLAYERS).map(PlayerId) {
+                parent
+                    .spawn_bundle(NodeBundle {
+                        style: Style {
+                            flex_direction: FlexDirection::ColumnReverse,
+                            size: Size::new(Val::Px(FIELD_SIZE / 5.), Val::Auto),
+                            ..default()
+                        },
+                        color: Color::NONE.into(),
+                        visibility: Visibility { is_visible: false },
+                        ..default()
+                    })
+                    .insert(PlayerPanel(id))
+                    .with_children(|panel| {
+                        panel
+                            .spawn_bundle(NodeBundle {
+                                style: Style {
+                                    align_items: AlignItems::Center,
+                                    ..default()
+                                },
+                                color: Color::NONE.into(),
+                                ..default()
+                            })
+                            .with_children(|row| {
+                                row.spawn_bundle(TextBundle::from_section(
+                                    format!("P{} ", id.0),
+                                    style(18., PLAYER_COLORS[id.0 - 1]),
+                                ));
+                                for index in 0..hearts {
+                                    row.spawn_bundle(NodeBundle {
+                                        style: Style {
+                                            size: Size::new(
+                                                Val::Px(HEART_SIZE),
+                                                Val::Px(HEART_SIZE),
+                                            ),
+                                            margin: UiRect::all(Val::Px(1.)),
+                                            ..default()
+                                        },
+                                        color: HEART_COLOR.into(),
+                                        ..default()
+                                    })
+                                    .insert(Heart { id, index });
+                                }
+                            });
+                        panel
+                            .spawn_bundle(TextBundle::from_sections(
+                                ["", "", "", ""]
+                                    .map(|value| TextSection::new(value, style(12., Color::WHITE))),
+                            ))
+                            .insert(PlayerStats(id));
+                    });
+            }
+
+            parent
+                .spawn_bundle(NodeBundle {
+                    style: Style {
+                        flex_direction: FlexDirection::ColumnReverse,
+                        align_items: AlignItems::FlexEnd,
+                        ..default()
+                    },
+                    color: Color::NONE.into(),
+                    ..default()
+                })
+                .with_children(|column| {
+                    column.spawn_bundle(TextBundle::from_section(
+                        level_name,
+                        style(18., Color::WHITE),
+                    ));
+                    column
+                        .spawn_bundle(TextBundle::from_section("", style(18., Color::WHITE)))
+                        .insert(TimerText);
+                });
+        });
+}
+
+/// Shows the panels of the players in the game, whenever one joins or is out.
+pub fn player_panel_system(
+    joined_query: Query<(), Added<Player>>,
+    new_panel_query: Query<(), Added<PlayerPanel>>,
+    removed: RemovedComponents<Player>,
+    player_query: Query<&PlayerId, With<Player>>,
+    mut panel_query: Query<(&PlayerPanel, &mut Visibility)>,
+) {
+    if joined_query.is_empty() && new_panel_query.is_empty() && removed.iter().next().is_none() {
+        return;
+    }
+
+    for (panel, mut visibility) in panel_query.iter_mut() {
+        let playing = player_query.iter().any(|id| *id == panel.0);
+        if visibility.is_visible != playing {
+            visibility.is_visible = playing;
+        }
+    }
+}
+
+/// Lights the hearts of players whose health changed.
+pub fn hud_health_system(
+    player_query: Query<(&Player, &PlayerId), Changed<Player>>,
+    mut heart_query: Query<(&Heart, &mut UiColor)>,
+) {
+    for (player, id) in player_query.iter() {
+        for (heart, mut color) in heart_query.iter_mut().filter(|(heart, _)| heart.id == *id) {
+            let lit = if heart.index < player.health {
+                HEART_COLOR
+            } else {
+                LOST_HEART_COLOR
+            };
+            if color.0 != lit {
+                color.0 = lit;
+            }
+        }
+    }
+}
+
+/// Updates the bombs a player has left whenever a bomb is placed or explodes.
+pub fn hud_bombs_system(
+    placed_query: Query<(), Added<Bomb>>,
+    removed: RemovedComponents<Bomb>,
+    changed_query: Query<(), Changed<Player>>,
+    bomb_query: Query<&Bomb>,
+    player_query: Query<(&Player, &PlayerId)>,
+    mut stats_query: Query<(&PlayerStats, &mut Text)>,
+) {
+    if placed_query.is_empty() && changed_query.is_empty() && removed.iter().next().is_none() {
+        return;
+    }
+
+    for (player, id) in player_query.iter() {
+        let placed = bomb_query
+            .iter()
+            .filter(|bomb| bomb.owner == Some(*id))
+            .count();
+        let left = player.max_bombs.saturating_sub(placed);
+        for (_, mut text) in stats_query.iter_mut().filter(|(stats, _)| stats.0 == *id) {
+            text.sections[PlayerStat::Bombs as usize].value =
+                format!("Bombs {}/{}  ", left, player.max_bombs);
+            text.sections[PlayerStat::Range as usize].value = format!("Range {}\n", EXPLOSION_SIZE);
+        }
+    }
+}
+
+/// Updates the scores and lives when they change and fills in new panels, battle wins only change
+/// between rounds.
+pub fn hud_score_system(
+    scores: Res<Scores>,
+    lives: Res<Lives>,
+    battle: Res<Battle>,
+    settings: Res<GameSettings>,
+    new_stats_query: Query<(), Added<PlayerStats>>,
+    mut stats_query: Query<(&PlayerStats, &mut Text)>,
+) {
+    if !scores.is_changed() && !lives.is_changed() && new_stats_query.is_empty() {
+        return;
+    }
+
+    for (stats, mut text) in stats_query.iter_mut() {
+        let index = stats.0 .0 - 1;
+        text.sections[PlayerStat::Lives as usize].value = match settings.mode {
+            GameMode::Campaign => format!("Lives {}  ", lives.left[index]),
+            GameMode::Battle => format!("Wins {}  ", battle.wins[index]),
+        };
+        text.sections[PlayerStat::Score as usize].value =
+            format!("Score {}", scores.players[index].points);
+    }
+}
+
+/// Counts down the time left, only touching the text when the shown second changes.
+pub fn hud_timer_system(field: Res<Field>, mut query: Query<&mut Text, With<TimerText>>) {
+    let seconds = field.time_left().ceil() as u32;
+    let value = format!("Time {}:{:02}", seconds / 60, seconds % 60);
+    let color = if field.time_left() <= HURRY_UP_TIME {
+        Color::rgb(1., 0.3, 0.2)
+    } else {
+        Color::WHITE
+    };
+
+    for mut text in query.iter_mut() {
+        if text.sections[0].value != value {
+            text.sections[0].value = value.clone();
+            text.sections[0].style.color = color;
+        }
+    }
+}
+
+/// Shows and hides the debug text on the toggle debug info action.
+pub fn toggle_debug_info_system(
+    actions: Res<ActionInput>,
+    mut query: Query<&mut Visibility, With<Info>>,
+) {
+    if actions.global_just_pressed(GlobalAction::ToggleDebugInfo) {
+        for mut visibility in query.iter_mut() {
+            visibility.is_visible = !visibility.is_visible;
+        }
+    }
+}
+
+pub fn update_debug_info_system(
+    mut query: Query<(&mut Text, &Visibility), With<Info>>,
+    player_query: Query<(&Player, &PlayerId, &GridCell, &Speed)>,
+    enemy_query: Query<(), With<Enemy>>,
+    bomb_query: Query<(), With<Bomb>>,
+    field: Res<Field>,
+    danger_map: Res<DangerMap>,
+    time: Res<SimTime>,
+) {
+    let (mut text, visibility) = match query.get_single_mut() {
+        Ok(info) => info,
+        Err(error) => {
+            error!("Error while updating debug info: {}", error.to_string());
+            return;
+        }
+    };
+    if !visibility.is_visible {
+        return;
+    }
+
+    let mut players: Vec<_> = player_query.iter().collect();
+    players.sort_by_key(|(_, id, _, _)| id.0);
+
+    let mut lines: Vec<String> = players
+        .iter()
+        .map(|(player, id, cell, speed)| {
+            format!(
+                "Player {} health: {}, Last hit: {} ms, Cell: {:?}, Speed: {}",
+                id.0,
+                player.health,
+                time.since(player.last_hit).as_millis(),
+                cell.0,
+                speed.base + speed.bonus
+            )
+        })
+        .collect();
+    lines.push(format!(
+        "Level time: {:.1}, Enemies: {}, Bombs: {}, Dangerous cells: {}",
+        field.time,
+        enemy_query.iter().count(),
+        bomb_query.iter().count(),
+        danger_map.cells.len()
+    ));
+    text.sections[0].value = lines.join("\n");
+}
@@ -0,0 +1,168 @@
+use std::time::Duration;
+
+use crate::boss_systems::boss_cells;
+use crate::components::*;
+use crate::constants::*;
+use crate::utils::{cell_to_position, position_to_cell};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy_tweening::lens::TransformScaleLens;
+use bevy_tweening::*;
+
+type DroppedOnQuery<'a> = (Entity, &'a Transform, Option<&'a GridCell>);
+type Crushable = Or<(With<Destructable>, With<Bomb>, With<SpeedPickup>)>;
+
+/// What a dropped wall may land on.
+#[derive(SystemParam)]
+pub struct DroppedOn<'w, 's> {
+    wall_query: Query<'w, 's, &'static Transform, With<Wall>>,
+    crushed_query: Query<'w, 's, DroppedOnQuery<'static>, Crushable>,
+    player_query: Query<'w, 's, (&'static GridCell, &'static mut Player)>,
+    boss_query: Query<'w, 's, (Entity, &'static GridCell), With<Boss>>,
+}
+
+/// Warns the players once the time is nearly up, then drops walls in a spiral from the edges
+/// of the field inward, crushing whatever they land on.
+pub fn hurry_up_system(
+    mut commands: Commands,
+    mut hurry_up: ResMut<HurryUp>,
+    mut field: ResMut<Field>,
+    textures: Res<GameTextures>,
+    asset_server: Res<AssetServer>,
+    dropped_on: DroppedOn,
+    time: Res<SimTime>,
+) {
+    let DroppedOn {
+        wall_query,
+        crushed_query,
+        mut player_query,
+        boss_query,
+    } = dropped_on;
+    if field.time_left() > HURRY_UP_TIME {
+        return;
+    }
+
+    if !hurry_up.started {
+        println!("Hurry up!");
+        hurry_up.started = true;
+        hurry_up.drop_timer = Timer::from_seconds(WALL_DROP_INTERVAL, true);
+        spawn_hurry_up_warning(&mut commands, &asset_server);
+    }
+
+    hurry_up.drop_timer.tick(time.delta());
+    if !hurry_up.drop_timer.just_finished() {
+        return;
+    }
+
+    let spiral = spiral_cells();
+    // cells that are walls already don't take a turn
+    let cell = loop {
+        match spiral.get(hurry_up.next) {
+            Some(cell) => {
+                hurry_up.next += 1;
+                if !wall_query
+                    .iter()
+                    .any(|wall| position_to_cell(wall.translation) == *cell)
+                {
+                    break *cell;
+                }
+            }
+            None => return,
+        }
+    };
+
+    for (entity, transform, grid_cell) in crushed_query.iter() {
+        let position = grid_cell.map_or_else(|| position_to_cell(transform.translation), |cell| cell.0);
+        if position == cell {
+            commands.entity(entity).despawn();
+        }
+    }
+    for (grid_cell, mut player) in player_query.iter_mut() {
+        if grid_cell.0 == cell {
+            player.health = 0;
+        }
+    }
+    for (entity, boss_cell) in boss_query.iter() {
+        if boss_cells(boss_cell.0).contains(&cell) {
+            println!("The boss got crushed!");
+            field.boss_defeated = true;
+            commands.entity(entity).despawn();
+        }
+    }
+
+    field.array[cell.0 as usize][cell.1 as usize] = 1;
+    commands
+        .spawn_bundle(SpriteBundle {
+            texture: textures.wall.clone(),
+            transform: Transform::from_translation(cell_to_position(cell)),
+            ..Default::default()
+        })
+        .insert(Wall)
+        .insert(Solid)
+        .insert(Animator::new(Tween::new(
+            EaseFunction::QuadraticIn,
+            TweeningType::Once,
+            Duration::from_millis(150),
+            TransformScaleLens {
+                start: Vec3::splat(2.),
+                end: Vec3::ONE,
+            },
+        )));
+}
+
+/// Every cell inside the outer wall, ring by ring from the edges to the middle.
+fn spiral_cells() -> Vec<(i32, i32)> {
+    let size = SIZE_IN_CELLS as i32;
+    let mut cells = Vec::new();
+    for ring in 1..size / 2 {
+        let (low, high) = (ring, size - 1 - ring);
+        cells.extend((low..=high).map(|x| (x, high)));
+        cells.extend((low..high).rev().map(|y| (high, y)));
+        cells.extend((low..high).rev().map(|x| (x, low)));
+        cells.extend((low + 1..high).map(|y| (low, y)));
+    }
+    cells
+}
+
+fn spawn_hurry_up_warning(commands: &mut Commands, asset_server: &AssetServer) {
+    commands
+        .spawn_bundle(
+            TextBundle::from_section(
+                "HURRY UP!",
+                TextStyle {
+                    font: asset_server.load("FiraSans-Regular.ttf"),
+                    font_size: 64.,
+                    color: Color::rgb(1., 0.3, 0.2),
+                },
+            )
+            .with_style(Style {
+                align_self: AlignSelf::Center,
+                position_type: PositionType::Absolute,
+                position: UiRect {
+                    left: Val::Percent(35.),
+                    top: Val::Percent(40.),
+                    ..default()
+                },
+                ..default()
+            }),
+        )
+        .insert(HurryUpWarning {
+            timer: Timer::from_seconds(HURRY_UP_WARNING_DURATION, false),
+        });
+}
+
+/// Flashes the hurry up warning, removing it after `HURRY_UP_WARNING_DURATION`.
+pub fn hurry_up_warning_system(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut HurryUpWarning, &mut Visibility)>,
+    time: Res<Time>,
+) {
+    for (entity, mut warning, mut visibility) in query.iter_mut() {
+        warning.timer.tick(time.delta());
+        if warning.timer.finished() {
+            commands.entity(entity).despawn();
+        } else {
+            visibility.is_visible = ((warning.timer.elapsed_secs() / 0.25) as u32).is_multiple_of(2);
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::fs;
+
+use crate::components::*;
+use crate::constants::*;
+use bevy::ecs::system::SystemParam;
+use crate::utils::asset_path;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+/// Pairs every listed variant of an enum with its name.
+macro_rules! named {
+    ($type:ident: $($variant:ident),* $(,)?) => {
+        &[$((stringify!($variant), $type::$variant)),*]
+    };
+}
+
+const KEYS: &[(&str, KeyCode)] = named!(KeyCode:
+    A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, Q, R, S, T, U, V, W, X, Y, Z,
+    Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12,
+    Escape, Insert, Home, Delete, End, PageDown, PageUp, Left, Up, Right, Down,
+    Back, Return, Space, Tab, Comma, Period, Slash, Semicolon, Apostrophe, Minus, Equals,
+    LBracket, RBracket, Backslash, Grave, LAlt, RAlt, LControl, RControl, LShift, RShift,
+    Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+    NumpadAdd, NumpadSubtract, NumpadMultiply, NumpadDivide, NumpadDecimal, NumpadEnter,
+);
+
+const BUTTONS: &[(&str, GamepadButtonType)] = named!(GamepadButtonType:
+    South, East, North, West, C, Z, LeftTrigger, LeftTrigger2, RightTrigger, RightTrigger2,
+    Select, Start, Mode, LeftThumb, RightThumb, DPadUp, DPadDown, DPadLeft, DPadRight,
+);
+
+const AXES: &[(&str, GamepadAxisType)] = named!(GamepadAxisType:
+    LeftStickX, LeftStickY, LeftZ, RightStickX, RightStickY, RightZ,
+);
+
+pub fn update_actions_system(
+    mut actions: ResMut<ActionInput>,
+    bindings: Res<Bindings>,
+    rebinding: Res<Rebinding>,
+    keys: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+    axes: Res<Axis<GamepadAxis>>,
+) {
+    let pressed = std::mem::take(&mut actions.pressed);
+    actions.previous = pressed;
+    actions.global.clear();
+
+    // the rebinding screen reads raw input itself
+    if rebinding.open {
+        return;
+    }
+
+    for (index, player) in bindings.players.iter().enumerate() {
+        let id = PlayerId(index + 1);
+        let gamepad = connected_gamepad(&gamepads, player.gamepad);
+
+        for (action, list) in player.actions.iter() {
+            let active = list.iter().any(|binding| match (binding, gamepad) {
+                (Binding::Key(key), _) => keys.pressed(*key),
+                (Binding::Button(button), Some(gamepad)) => {
+                    buttons.pressed(GamepadButton::new(gamepad, *button))
+                }
+                (Binding::Axis(axis, positive), Some(gamepad)) => axes
+                    .get(GamepadAxis::new(gamepad, *axis))
+                    .is_some_and(|value| {
+                        if *positive {
+                            value > AXIS_THRESHOLD
+                        } else {
+                            value < -AXIS_THRESHOLD
+                        }
+                    }),
+                // the player's gamepad isn't connected
+                (_, None) => false,
+            });
+            if active {
+                actions.press(id, *action);
+            }
+        }
+    }
+
+    for (action, list) in bindings.global.iter() {
+        if list.iter().any(|key| keys.just_pressed(*key)) {
+            actions.global.insert(*action);
+        }
+    }
+}
+
+/// The `slot`th of the connected gamepads, in the order they were connected. Bevy numbers
+/// gamepads as they appear and doesn't reuse the number of one that was unplugged.
+fn connected_gamepad(gamepads: &Gamepads, slot: usize) -> Option<Gamepad> {
+    let mut connected: Vec<Gamepad> = gamepads.iter().copied().collect();
+    connected.sort_by_key(|gamepad| gamepad.id);
+    connected.get(slot).copied()
+}
+
+/// What the rebinding screen is built and updated with.
+#[derive(SystemParam)]
+pub struct RebindUi<'w, 's> {
+    commands: Commands<'w, 's>,
+    asset_server: Res<'w, AssetServer>,
+    screen_query: Query<'w, 's, Entity, With<RebindScreen>>,
+    text_query: Query<'w, 's, &'static mut Text, With<RebindText>>,
+}
+
+pub fn rebind_system(
+    mut rebinding: ResMut<Rebinding>,
+    mut bindings: ResMut<Bindings>,
+    actions: Res<ActionInput>,
+    keys: Res<Input<KeyCode>>,
+    gamepads: Res<Gamepads>,
+    buttons: Res<Input<GamepadButton>>,
+    ui: RebindUi,
+) {
+    let RebindUi {
+        mut commands,
+        asset_server,
+        screen_query,
+        mut text_query,
+    } = ui;
+    if !rebinding.open {
+        if actions.global_just_pressed(GlobalAction::Rebind) {
+            rebinding.open = true;
+            rebinding.listening = false;
+            spawn_rebind_screen(&mut commands, &asset_server);
+        }
+        return;
+    }
+
+    let action = Action::ALL[rebinding.selected];
+    if rebinding.listening {
+        let player = &mut bindings.players[rebinding.player];
+        let list = player.actions.entry(action).or_default();
+
+        if keys.just_pressed(KeyCode::Escape) {
+            rebinding.listening = false;
+        } else if let Some(key) = keys.get_just_pressed().next() {
+            replace_binding(list, Binding::Key(*key));
+            rebinding.listening = false;
+        } else if let Some(button) = buttons
+            .get_just_pressed()
+            .find(|button| Some(button.gamepad) == connected_gamepad(&gamepads, player.gamepad))
+        {
+            replace_binding(list, Binding::Button(button.button_type));
+            rebinding.listening = false;
+        }
+    } else {
+        let close_keys = bindings
+            .global
+            .get(&GlobalAction::Rebind)
+            .cloned()
+            .unwrap_or_default();
+
+        if keys.just_pressed(KeyCode::Escape) || close_keys.iter().any(|key| keys.just_pressed(*key)) {
+            rebinding.open = false;
+            save_bindings(&bindings);
+            screen_query.for_each(|entity| {
+                commands.entity(entity).despawn_recursive();
+            });
+            return;
+        } else if keys.just_pressed(KeyCode::Up) {
+            rebinding.selected = (rebinding.selected + Action::ALL.len() - 1) % Action::ALL.len();
+        } else if keys.just_pressed(KeyCode::Down) {
+            rebinding.selected = (rebinding.selected + 1) % Action::ALL.len();
+        } else if keys.just_pressed(KeyCode::Tab) {
+            rebinding.player = (rebinding.player + 1) % bindings.players.len();
+        } else if keys.just_pressed(KeyCode::Return) {
+            rebinding.listening = true;
+        } else if keys.just_pressed(KeyCode::Delete) {
+            bindings.players[rebinding.player].actions.remove(&action);
+        }
+    }
+
+    if let Ok(mut text) = text_query.get_single_mut() {
+        text.sections[0].value = rebind_screen_text(&rebinding, &bindings);
+    }
+}
+
+/// Puts the new binding first and drops the old bindings of the same kind.
+fn replace_binding(list: &mut Vec<Binding>, binding: Binding) {
+    list.retain(|old| {
+        !matches!(
+            (old, &binding),
+            (Binding::Key(_), Binding::Key(_)) | (Binding::Button(_), Binding::Button(_))
+        )
+    });
+    list.insert(0, binding);
+}
+
+fn rebind_screen_text(rebinding: &Rebinding, bindings: &Bindings) -> String {
+    let player = &bindings.players[rebinding.player];
+    let mut lines = vec![
+        format!(
+            "Key bindings - Player {} (gamepad {})",
+            rebinding.player + 1,
+            player.gamepad
+        ),
+        "Tab: next player, Up/Down: choose, Enter: rebind, Delete: clear, Esc: save and close"
+            .to_string(),
+        String::new(),
+    ];
+
+    for (index, action) in Action::ALL.iter().enumerate() {
+        let marker = if index == rebinding.selected { ">" } else { " " };
+        lines.push(format!(
+            "{} {}: {}",
+            marker,
+            action.name(),
+            binding_names(player.actions.get(action))
+        ));
+    }
+
+    if rebinding.listening {
+        lines.push(String::new());
+        lines.push(format!(
+            "Press a key or a gamepad button for {} (Esc cancels)",
+            Action::ALL[rebinding.selected].name()
+        ));
+    }
+
+    lines.join("\n")
+}
+
+fn spawn_rebind_screen(commands: &mut Commands, asset_server: &AssetServer) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: Color::rgba(0., 0., 0., 0.85).into(),
+            ..default()
+        })
+        .insert(RebindScreen)
+        .with_children(|parent| {
+            parent
+                .spawn_bundle(TextBundle::from_section(
+                    "",
+                    TextStyle {
+                        font: asset_server.load("FiraSans-Regular.ttf"),
+                        font_size: 24.,
+                        color: Color::WHITE,
+                    },
+                ))
+                .insert(RebindText);
+        });
+}
+
+pub fn load_bindings() -> Bindings {
+    match fs::read_to_string(asset_path(BINDINGS_FILE)) {
+        Ok(text) => parse_bindings(&text),
+        Err(_) => {
+            println!("Can't read the bindings, using the default ones.");
+            default_bindings()
+        }
+    }
+}
+
+/// Reads bindings.cfg, keeping the default binding of everything it doesn't set.
+fn parse_bindings(text: &str) -> Bindings {
+    let mut bindings = default_bindings();
+
+    let mut section: Option<String> = None;
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        if let Some(name) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+            section = Some(name.trim().to_string());
+            continue;
+        }
+
+        let parsed = match (section.as_deref(), line.split_once('=')) {
+            (Some("global"), Some((key, value))) => GlobalAction::ALL
+                .iter()
+                .find(|action| action.name() == key.trim())
+                .and_then(|action| {
+                    let keys = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(key_from_name)
+                        .collect::<Option<Vec<KeyCode>>>()?;
+                    bindings.global.insert(*action, keys);
+                    Some(())
+                }),
+            (Some(section), Some((key, value))) => section
+                .strip_prefix("player")
+                .and_then(|number| number.parse::<usize>().ok())
+                .filter(|number| (1..=MAX_PLAYERS).contains(number))
+                .and_then(|number| {
+                    let player = &mut bindings.players[number - 1];
+                    let key = key.trim();
+                    if key == "gamepad" {
+                        player.gamepad = value.trim().parse::<usize>().ok()?;
+                        return Some(());
+                    }
+
+                    let action = Action::ALL.iter().find(|action| action.name() == key)?;
+                    let list = value
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|name| !name.is_empty())
+                        .map(binding_from_name)
+                        .collect::<Option<Vec<Binding>>>()?;
+                    player.actions.insert(*action, list);
+                    Some(())
+                }),
+            _ => None,
+        };
+
+        if parsed.is_none() {
+            println!("Can't parse binding: {}", line);
+        }
+    }
+
+    bindings
+}
+
+pub fn save_bindings(bindings: &Bindings) {
+    if let Err(error) = fs::write(asset_path(BINDINGS_FILE), bindings_text(bindings)) {
+        println!("Can't save the bindings: {}", error);
+    }
+}
+
+fn bindings_text(bindings: &Bindings) -> String {
+    let mut lines = vec![
+        "# Key bindings, rewritten by the rebinding screen (F1 by default).".to_string(),
+        "# Keys use Bevy KeyCode names, gamepad buttons are pad:<GamepadButtonType> and".to_string(),
+        "# stick directions are pad:<GamepadAxisType>+ or pad:<GamepadAxisType>-.".to_string(),
+    ];
+
+    for (index, player) in bindings.players.iter().enumerate() {
+        lines.push(String::new());
+        lines.push(format!("[player{}]", index + 1));
+        lines.push(format!("gamepad = {}", player.gamepad));
+        for action in Action::ALL {
+            lines.push(format!(
+                "{} = {}",
+                action.name(),
+                binding_names(player.actions.get(&action))
+            ));
+        }
+    }
+
+    lines.push(String::new());
+    lines.push("[global]".to_string());
+    for action in GlobalAction::ALL {
+        let keys: Vec<&str> = bindings
+            .global
+            .get(&action)
+            .map(|keys| keys.iter().filter_map(|key| key_name(*key)).collect())
+            .unwrap_or_default();
+        lines.push(format!("{} = {}", action.name(), keys.join(", ")));
+    }
+    lines.push(String::new());
+
+    lines.join("\n")
+}
+
+fn default_bindings() -> Bindings {
+    let keyboards = [
+        [KeyCode::W, KeyCode::S, KeyCode::A, KeyCode::D, KeyCode::Space, KeyCode::E, KeyCode::Escape],
+        [KeyCode::Up, KeyCode::Down, KeyCode::Left, KeyCode::Right, KeyCode::Return, KeyCode::RShift, KeyCode::Back],
+        [KeyCode::T, KeyCode::G, KeyCode::F, KeyCode::H, KeyCode::Y, KeyCode::R, KeyCode::P],
+        [
+            KeyCode::Numpad8,
+            KeyCode::Numpad5,
+            KeyCode::Numpad4,
+            KeyCode::Numpad6,
+            KeyCode::Numpad0,
+            KeyCode::NumpadDecimal,
+            KeyCode::NumpadEnter,
+        ],
+    ];
+    let pad = [
+        vec![
+            Binding::Button(GamepadButtonType::DPadUp),
+            Binding::Axis(GamepadAxisType::LeftStickY, true),
+        ],
+        vec![
+            Binding::Button(GamepadButtonType::DPadDown),
+            Binding::Axis(GamepadAxisType::LeftStickY, false),
+        ],
+        vec![
+            Binding::Button(GamepadButtonType::DPadLeft),
+            Binding::Axis(GamepadAxisType::LeftStickX, false),
+        ],
+        vec![
+            Binding::Button(GamepadButtonType::DPadRight),
+            Binding::Axis(GamepadAxisType::LeftStickX, true),
+        ],
+        vec![Binding::Button(GamepadButtonType::South)],
+        vec![Binding::Button(GamepadButtonType::East)],
+        vec![Binding::Button(GamepadButtonType::Start)],
+    ];
+
+    let players = keyboards
+        .iter()
+        .enumerate()
+        .map(|(index, keys)| PlayerBindings {
+            gamepad: index,
+            actions: Action::ALL
+                .iter()
+                .zip(keys.iter().zip(pad.iter()))
+                .map(|(action, (key, pad))| {
+                    let mut list = vec![Binding::Key(*key)];
+                    list.extend(pad.iter().copied());
+                    (*action, list)
+                })
+                .collect(),
+        })
+        .collect();
+
+    let mut global = HashMap::default();
+    global.insert(GlobalAction::Rebind, vec![KeyCode::F1]);
+    global.insert(GlobalAction::StartBattle, vec![KeyCode::F2]);
+    global.insert(GlobalAction::DebugKillEnemy, vec![KeyCode::K]);
+    global.insert(GlobalAction::ToggleDebugInfo, vec![KeyCode::F3]);
+    global.insert(GlobalAction::SaveGame, vec![KeyCode::F5]);
+
+    Bindings { players, global }
+}
+
+fn binding_names(list: Option<&Vec<Binding>>) -> String {
+    list.map(|list| {
+        list.iter()
+            .filter_map(binding_name)
+            .collect::<Vec<String>>()
+            .join(", ")
+    })
+    .unwrap_or_default()
+}
+
+fn binding_name(binding: &Binding) -> Option<String> {
+    match binding {
+        Binding::Key(key) => key_name(*key).map(str::to_string),
+        Binding::Button(button) => BUTTONS
+            .iter()
+            .find(|(_, value)| value == button)
+            .map(|(name, _)| format!("pad:{}", name)),
+        Binding::Axis(axis, positive) => AXES
+            .iter()
+            .find(|(_, value)| value == axis)
+            .map(|(name, _)| format!("pad:{}{}", name, if *positive { "+" } else { "-" })),
+    }
+}
+
+fn binding_from_name(name: &str) -> Option<Binding> {
+    match name.strip_prefix("pad:") {
+        Some(pad) => {
+            if let Some(axis) = pad.strip_suffix('+') {
+                axis_from_name(axis).map(|axis| Binding::Axis(axis, true))
+            } else if let Some(axis) = pad.strip_suffix('-') {
+                axis_from_name(axis).map(|axis| Binding::Axis(axis, false))
+            } else {
+                BUTTONS
+                    .iter()
+                    .find(|(button, _)| *button == pad)
+                    .map(|(_, button)| Binding::Button(*button))
+            }
+        }
+        None => key_from_name(name).map(Binding::Key),
+    }
+}
+
+fn axis_from_name(name: &str) -> Option<GamepadAxisType> {
+    AXES.iter()
+        .find(|(axis, _)| *axis == name)
+        .map(|(_, axis)| *axis)
+}
+
+fn key_from_name(name: &str) -> Option<KeyCode> {
+    KEYS.iter().find(|(key, _)| *key == name).map(|(_, key)| *key)
+}
+
+fn key_name(key: KeyCode) -> Option<&'static str> {
+    KEYS.iter().find(|(_, value)| *value == key).map(|(name, _)| *name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn saved_bindings_read_back_the_same() {
+        let mut bindings = default_bindings();
+        bindings.players[1].gamepad = 3;
+        bindings.players[1].actions.insert(
+            Action::PlaceBomb,
+            vec![
+                Binding::Key(KeyCode::LControl),
+                Binding::Axis(GamepadAxisType::RightZ, false),
+            ],
+        );
+        bindings.players[2].actions.remove(&Action::Pause);
+        bindings.global.insert(GlobalAction::Rebind, vec![KeyCode::F5, KeyCode::F6]);
+
+        let text = bindings_text(&bindings);
+        let parsed = parse_bindings(&text);
+
+        assert_eq!(bindings_text(&parsed), text);
+        assert_eq!(parsed.players[1].gamepad, 3);
+        assert_eq!(
+            parsed.players[1].actions.get(&Action::PlaceBomb),
+            bindings.players[1].actions.get(&Action::PlaceBomb)
+        );
+        assert_eq!(parsed.players[2].actions.get(&Action::Pause), Some(&Vec::new()));
+        assert_eq!(
+            parsed.global.get(&GlobalAction::Rebind),
+            Some(&vec![KeyCode::F5, KeyCode::F6])
+        );
+    }
+
+    #[test]
+    fn keeps_defaults_for_lines_it_cant_parse() {
+        let text = "move_up = I\n[player1]\nmove_up = NotAKey\nfly = W\ngamepad = first\n\
+                    [player5]\nmove_up = I\n[global]\nrebind = pad:South\n\
+                    [player2]\nmove_down = K, pad:LeftStickY- # comment\n";
+        let bindings = parse_bindings(text);
+        let defaults = default_bindings();
+
+        assert_eq!(
+            bindings.players[0].actions.get(&Action::MoveUp),
+            defaults.players[0].actions.get(&Action::MoveUp)
+        );
+        assert_eq!(bindings.players[0].gamepad, 0);
+        assert_eq!(bindings.global.get(&GlobalAction::Rebind), Some(&vec![KeyCode::F1]));
+        assert_eq!(
+            bindings.players[1].actions.get(&Action::MoveDown),
+            Some(&vec![
+                Binding::Key(KeyCode::K),
+                Binding::Axis(GamepadAxisType::LeftStickY, false)
+            ])
+        );
+    }
+}
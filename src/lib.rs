@@ -0,0 +1,165 @@
+use battle_systems::*;
+use bevy::asset::AssetPlugin;
+use bevy::prelude::*;
+use bomb_systems::*;
+use bot_systems::*;
+use boss_systems::*;
+use components::*;
+use constants::*;
+use enemy_systems::*;
+use field_systems::*;
+use hurry_up_systems::*;
+use player_systems::*;
+use rollback_systems::*;
+use save_systems::*;
+use score_systems::*;
+use simulation_systems::*;
+use spawner_systems::*;
+use state_systems::*;
+use utils::random_seed;
+
+pub mod battle_systems;
+pub mod bomb_systems;
+pub mod bot_systems;
+pub mod boss_systems;
+pub mod cli;
+pub mod client_systems;
+pub mod components;
+pub mod constants;
+pub mod enemy_systems;
+pub mod env_systems;
+pub mod field_systems;
+pub mod hud_systems;
+pub mod hurry_up_systems;
+pub mod input_systems;
+pub mod net_systems;
+pub mod player_systems;
+pub mod protocol_systems;
+pub mod rollback_systems;
+pub mod save_systems;
+pub mod score_systems;
+pub mod server_systems;
+pub mod simulation_systems;
+pub mod spawner_systems;
+pub mod state_systems;
+pub mod tournament_systems;
+pub mod utils;
+
+/// The level flow and the gameplay systems with their resources, shared by the game and the
+/// rooms of the dedicated server.
+pub struct GameplayPlugin;
+
+impl Plugin for GameplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Field {
+            array: [[0; 30]; 30],
+            properties: LevelProperties::default(),
+            current_level: 1,
+            first_level: 1,
+            boss_defeated: false,
+            time: 0.,
+        })
+        .insert_resource(DangerMap::default())
+        .insert_resource(GameSettings {
+            player_count: 1,
+            mode: GameMode::Campaign,
+            difficulty: Difficulty::Normal,
+            bots: 0,
+            bot_difficulty: Difficulty::Normal,
+        })
+        .insert_resource(Lives::default())
+        .insert_resource(Scores::default())
+        .insert_resource(HurryUp::default())
+        .insert_resource(StateTimer(Timer::default()))
+        .insert_resource(GameRng::new(random_seed()))
+        .insert_resource(SimTime::default())
+        .insert_resource(PendingState::default())
+        .insert_resource(SavedGame::default())
+        .insert_resource(Battle::new(BATTLE_BEST_OF))
+        .insert_resource(ActionInput::default())
+        .insert_resource(Bots::new(random_seed()))
+        .insert_resource(LevelPack::default())
+        .add_state(AppState::MainMenu)
+        // after whatever fills in the actions of the other players
+        .add_system_to_stage(CoreStage::PreUpdate, bot_system)
+        .add_system_set(SystemSet::on_enter(AppState::MainMenu).with_system(despawn_level_system))
+        .add_system_set(
+            SystemSet::on_enter(AppState::Loading)
+                .with_system(despawn_level_system)
+                .with_system(load_field_system)
+                .with_system(restore_game_system.after(load_field_system))
+                .with_system(spawn_level_intro_system),
+        )
+        .add_system_set(SystemSet::on_update(AppState::Loading).with_system(level_intro_system))
+        .add_system_set(
+            SystemSet::on_exit(AppState::Loading)
+                .with_system(despawn_overlay_system)
+                .with_system(spawn_field_system),
+        )
+        // the gameplay systems chained one after the other, so that they run in the same order on
+        // both peers of an online game and in every replay
+        .add_stage_after(
+            CoreStage::Update,
+            SimulationStage,
+            SystemStage::single_threaded().with_run_criteria(simulation_criteria),
+        )
+        .add_system_to_stage(
+            SimulationStage,
+            snapshot_system.exclusive_system().at_start(),
+        )
+        .add_system_set_to_stage(
+            SimulationStage,
+            SystemSet::new()
+                .label(GameplaySystems)
+                .with_system(join_player_system)
+                .with_system(move_player_system.after(join_player_system))
+                .with_system(speed_pickup_system.after(move_player_system))
+                .with_system(spawn_bomb_system.after(speed_pickup_system))
+                .with_system(update_danger_map_system.after(spawn_bomb_system))
+                .with_system(detonate_bomb_system.after(update_danger_map_system))
+                .with_system(move_enemy_system.after(detonate_bomb_system))
+                .with_system(spawner_system.after(move_enemy_system))
+                .with_system(boss_movement_system.after(spawner_system))
+                .with_system(boss_attack_system.after(boss_movement_system))
+                .with_system(boss_damage_system.after(boss_attack_system))
+                .with_system(complete_level_system.after(boss_damage_system))
+                .with_system(remove_explosion_system.after(complete_level_system))
+                .with_system(explosion_destruction_system.after(remove_explosion_system))
+                .with_system(enemy_kill_player_system.after(explosion_destruction_system))
+                .with_system(debug_kill_enemy.after(enemy_kill_player_system))
+                .with_system(player_health_system.after(debug_kill_enemy))
+                .with_system(invulnerability_system.after(player_health_system))
+                .with_system(battle_round_system.after(invulnerability_system))
+                .with_system(level_time_system.after(battle_round_system))
+                .with_system(score_chain_system.after(level_time_system))
+                .with_system(hurry_up_system.after(score_chain_system)),
+        )
+        .add_system_to_stage(CoreStage::PostUpdate, apply_pending_state_system)
+        .add_system_set(
+            SystemSet::on_enter(AppState::LevelComplete).with_system(spawn_level_complete_system),
+        )
+        .add_system_set(
+            SystemSet::on_update(AppState::LevelComplete).with_system(level_complete_system),
+        )
+        .add_system_set(
+            SystemSet::on_exit(AppState::LevelComplete).with_system(despawn_overlay_system),
+        );
+    }
+}
+
+/// The gameplay without window, rendering or keyboard, for the rooms of the dedicated server and
+/// the training environment.
+pub fn headless_app() -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins)
+        .add_plugin(AssetPlugin)
+        .add_plugin(GameplayPlugin)
+        // nothing is drawn, the entities only need some handle
+        .insert_resource(GameTextures::default())
+        .add_startup_system(headless_startup_system);
+    app
+}
+
+fn headless_startup_system(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(load_enemy_archetypes(&asset_server));
+}
@@ -1,77 +1,262 @@
-use bevy::{prelude::*, time::FixedTimestep};
+use bevy::{input::InputSystem, prelude::*, time::FixedTimestep, ui::UiSystem};
+use bevy::app::AppExit;
+use bevy::asset::AssetServerSettings;
+use bevy::window::WindowMode;
 use bevy_tweening::*;
-use bomb_systems::*;
-use components::*;
-use constants::*;
-use enemy_systems::*;
-use field_systems::*;
-use player_systems::*;
-
-pub mod bomb_systems;
-pub mod components;
-pub mod constants;
-pub mod enemy_systems;
-pub mod field_systems;
-pub mod player_systems;
-pub mod utils;
+use bomberman::boss_systems::boss_health_bar_system;
+use bomberman::bot_systems::bot_system;
+use bomberman::cli::{CliOptions, HELP};
+use bomberman::client_systems::*;
+use bomberman::components::*;
+use bomberman::constants::*;
+use bomberman::enemy_systems::{ghost_transparency_system, load_enemy_archetypes};
+use bomberman::hud_systems::*;
+use bomberman::hurry_up_systems::hurry_up_warning_system;
+use bomberman::input_systems::*;
+use bomberman::net_systems::*;
+use bomberman::save_systems::*;
+use bomberman::score_systems::load_high_scores;
+use bomberman::state_systems::*;
+use bomberman::utils::{assets_dir, random_seed, set_assets_dir};
+use bomberman::{headless_app, GameplayPlugin};
 
 fn main() {
+    let options = CliOptions::parse(std::env::args().skip(1));
+    if options.help {
+        println!("{}", HELP);
+        return;
+    }
+    if let Some(dir) = &options.assets {
+        set_assets_dir(dir);
+    }
+    if options.headless {
+        run_headless(&options);
+        return;
+    }
+
+    // the window keeps the shape of the field and the HUD, scaled to fit the size asked for
+    let scale = options
+        .window
+        .map(|(width, height)| (width / FIELD_SIZE).min(height / HEIGHT) as f64);
     App::new()
         .insert_resource(ClearColor(Color::BLACK))
         .insert_resource(WindowDescriptor {
             title: "Bon'berman".to_string(),
             width: FIELD_SIZE,
-            height: FIELD_SIZE,
+            height: HEIGHT,
+            scale_factor_override: scale,
+            mode: if options.fullscreen {
+                WindowMode::BorderlessFullscreen
+            } else {
+                WindowMode::Windowed
+            },
+            ..Default::default()
+        })
+        .insert_resource(AssetServerSettings {
+            asset_folder: assets_dir().to_string(),
             ..Default::default()
         })
         .add_plugins(DefaultPlugins)
         .add_plugin(TweeningPlugin)
+        .add_plugin(GameplayPlugin)
+        .insert_resource(options.level_pack())
+        .insert_resource(options)
         .add_startup_system(startup_system)
-        .add_system(load_field_system)
-        .add_system(spawn_field_system)
-        .add_system(move_player_system)
-        .add_system(spawn_bomb_system)
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            update_actions_system.after(InputSystem).before(bot_system),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            net_receive_system.after(update_actions_system),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            client_receive_system.after(update_actions_system),
+        )
+        .add_system_to_stage(CoreStage::PostUpdate, net_send_system)
+        .add_system_to_stage(CoreStage::PostUpdate, client_send_system)
+        .add_system(pause_system)
+        // after paused_system, so a game left for the main menu is saved before it is despawned
+        .add_system(save_game_system.after(paused_system))
+        .add_system(toggle_debug_info_system)
+        .add_system_set(SystemSet::on_enter(AppState::MainMenu).with_system(spawn_main_menu_system))
         .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(0.5))
-                .with_system(detonate_bomb_system),
+            SystemSet::on_update(AppState::MainMenu)
+                .with_system(main_menu_system)
+                .with_system(rebind_system),
         )
+        .add_system_set(SystemSet::on_exit(AppState::MainMenu).with_system(despawn_overlay_system))
+        .add_system_set(SystemSet::on_exit(AppState::Loading).with_system(spawn_hud_system))
         .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(0.25))
-                .with_system(move_enemy_system),
+            SystemSet::on_update(AppState::Playing)
+                .with_system(ghost_transparency_system)
+                .with_system(boss_health_bar_system)
+                .with_system(hurry_up_warning_system),
         )
         .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(0.25))
-                .with_system(update_info_system),
+            SystemSet::on_enter(AppState::Paused)
+                .with_system(pause_animators_system)
+                .with_system(spawn_pause_screen_system),
         )
+        .add_system_set(SystemSet::on_update(AppState::Paused).with_system(paused_system))
         .add_system_set(
+            SystemSet::on_exit(AppState::Paused)
+                .with_system(resume_animators_system)
+                .with_system(despawn_overlay_system),
+        )
+        .add_system_set(SystemSet::on_enter(AppState::GameOver).with_system(spawn_game_over_system))
+        .add_system_set(SystemSet::on_update(AppState::GameOver).with_system(game_over_system))
+        .add_system_set(SystemSet::on_exit(AppState::GameOver).with_system(despawn_overlay_system))
+        .add_system_set(
+            SystemSet::on_enter(AppState::Remote).with_system(spawn_remote_status_system),
+        )
+        .add_system_set(
+            SystemSet::on_update(AppState::Remote)
+                .with_system(mirror_system)
+                .with_system(spectator_camera_system.after(mirror_system)),
+        )
+        .add_system_set(
+            SystemSet::on_exit(AppState::Remote)
+                .with_system(despawn_remote_system)
+                .with_system(despawn_overlay_system),
+        )
+        // after the gameplay commands are applied, so despawned players and bombs are seen
+        .add_system_set_to_stage(
+            CoreStage::PostUpdate,
             SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(0.1))
-                .with_system(update_info_system),
+                .before(UiSystem::Flex)
+                .with_system(player_panel_system)
+                .with_system(hud_health_system)
+                .with_system(hud_bombs_system)
+                .with_system(hud_score_system)
+                .with_system(hud_timer_system),
         )
         .add_system_set(
             SystemSet::new()
-                .with_run_criteria(FixedTimestep::step(1.))
-                .with_system(complete_level_system),
+                .with_run_criteria(FixedTimestep::step(0.1))
+                .with_system(update_debug_info_system),
         )
-        .add_system(remove_explosion_system)
-        .add_system(explosion_destruction_system)
-        .add_system(enemy_kill_player_system)
-        .add_system(debug_kill_enemy)
-        .add_system(player_health_system)
         .run();
 }
 
-fn startup_system(mut commands: Commands, asset_server: Res<AssetServer>, mut texture_atlases: ResMut<Assets<TextureAtlas>>) {
-    commands.spawn_bundle(Camera2dBundle::default());
-    commands.insert_resource(Field {
-        array: [[0; 30]; 30],
-        loaded: false,
-        spawned: false,
-        current_level: 1,
-    });
+/// Plays without a window, every player a CPU bot, until the campaign or the battle is over.
+fn run_headless(options: &CliOptions) {
+    let mode = options.mode.unwrap_or(GameMode::Campaign);
+    let players = options.player_count(mode);
+    let seed = options.seed.unwrap_or_else(random_seed);
+    let mut app = headless_app();
+    app.insert_resource(FixedTick)
+        .insert_resource(GameRng::new(seed))
+        .insert_resource(Bots::new(seed))
+        .insert_resource(options.level_pack())
+        .add_system_to_stage(CoreStage::Last, headless_exit_system);
+
+    let mut settings = app.world.resource_mut::<GameSettings>();
+    settings.mode = mode;
+    settings.player_count = players;
+    settings.bots = players;
+    let mut field = app.world.resource_mut::<Field>();
+    field.first_level = options.first_level();
+    field.current_level = field.first_level;
+    println!(
+        "Playing a headless {} with {} CPU players, seed {}.",
+        mode.name(),
+        players,
+        seed
+    );
+
+    // runs the startup systems before skipping the main menu
+    app.update();
+    let mut state = app.world.resource_mut::<State<AppState>>();
+    report_state_error(state.overwrite_set(AppState::Loading));
+    app.run();
+}
+
+/// Prints how a headless game went and quits once it is over.
+fn headless_exit_system(
+    mut started: Local<bool>,
+    state: Res<State<AppState>>,
+    settings: Res<GameSettings>,
+    field: Res<Field>,
+    scores: Res<Scores>,
+    battle: Res<Battle>,
+    mut exit: EventWriter<AppExit>,
+) {
+    // the game is on the main menu until the first level loads, and back on it once it's over
+    let over = match state.current() {
+        AppState::MainMenu => *started,
+        AppState::GameOver => true,
+        AppState::LevelComplete => battle.match_winner().is_some(),
+        _ => {
+            *started = true;
+            false
+        }
+    };
+    if !over {
+        return;
+    }
+
+    match settings.mode {
+        GameMode::Campaign => println!("The campaign ended on level {}.", field.current_level),
+        GameMode::Battle => match battle.match_winner() {
+            Some(winner) => println!(
+                "Player {} won the battle in {} rounds.",
+                winner.0, battle.round
+            ),
+            None => println!("The battle ended undecided."),
+        },
+    }
+    for (index, score) in scores.players.iter().take(settings.player_count).enumerate() {
+        println!("Player {}: {} points", index + 1, score.points);
+    }
+    exit.send(AppExit);
+}
+
+fn startup_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    options: Res<CliOptions>,
+    mut settings: ResMut<GameSettings>,
+    mut field: ResMut<Field>,
+    mut state: ResMut<State<AppState>>,
+) {
+    // the field sits below the HUD bar
+    let mut camera = Camera2dBundle::default();
+    camera.transform.translation.y = HUD_HEIGHT / 2.;
+    commands.spawn_bundle(camera);
+    commands.insert_resource(load_high_scores());
+    commands.insert_resource(NameEntry::default());
+    commands.insert_resource(Menu::default());
+    let seed = options.seed.unwrap_or_else(random_seed);
+    if options.seed.is_some() {
+        commands.insert_resource(GameRng::new(seed));
+        commands.insert_resource(Bots::new(seed));
+    }
+    if let Some(players) = options.players {
+        settings.player_count = players;
+    }
+    field.first_level = options.first_level();
+    let lockstep = lockstep_from_args(&options.commands, seed);
+    let connection = connection_from_args(&options.commands);
+    // online games start by themselves
+    if let (Some(mode), None, None) = (options.mode, &lockstep, &connection) {
+        settings.mode = mode;
+        settings.player_count = options.player_count(mode);
+        field.current_level = field.first_level;
+        report_state_error(state.overwrite_set(AppState::Loading));
+    }
+    if let Some(lockstep) = lockstep {
+        commands.insert_resource(lockstep);
+    }
+    if let Some(connection) = connection {
+        commands.insert_resource(connection);
+    }
+    commands.insert_resource(SaveRequest::default());
+    commands.insert_resource(load_bindings());
+    commands.insert_resource(Rebinding::default());
+    commands.insert_resource(load_enemy_archetypes(&asset_server));
 
     let player_handle = asset_server.load("player.png");
     let texture_atlas = TextureAtlas::from_grid(player_handle, Vec2 { x: 32.0, y: 32.0 }, 4, 1);
@@ -83,14 +268,15 @@ fn startup_system(mut commands: Commands, asset_server: Res<AssetServer>, mut te
         wall: asset_server.load("wall.png"),
         wood: asset_server.load("wood.png"),
         bomb: asset_server.load("bomb.png"),
-        enemy: asset_server.load("enemy.png"),
+        boss: asset_server.load("enemy.png"),
+        spawner: asset_server.load("wall.png"),
         player: texture_atlases.add(texture_atlas),
         explosion: texture_atlases.add(explosion_atlas),    
     });
     commands
         .spawn_bundle(
             TextBundle::from_section(
-                "value",
+                "",
                 TextStyle {
                     font: asset_server.load("FiraSans-Regular.ttf"),
                     font_size: 16.,
@@ -109,30 +295,6 @@ fn startup_system(mut commands: Commands, asset_server: Res<AssetServer>, mut te
                 ..Default::default()
             }),
         )
+        .insert(Visibility { is_visible: false })
         .insert(Info);
 }
-
-fn update_info_system(
-    mut query: Query<&mut Text, With<Info>>,
-    player_query: Query<&Player>,
-    level: Res<Field>,
-) {
-    let player_info = match player_query.get_single() {
-        Ok(player) => format!(
-            "Health: {}, Last hit: {}",
-            player.health,
-            player.last_hit.elapsed().as_millis()
-        ),
-        Err(_) => "Can't get player info.".to_string(),
-    };
-
-    let level_info = format!(
-        "Level: {}",
-        level.current_level
-    );
-
-    match query.get_single_mut() {
-        Ok(mut text) => text.sections[0].value = format!("{}\n{}", player_info, level_info),
-        Err(error) => error!("Error while updating debug info: {}", error.to_string()),
-    }
-}
@@ -0,0 +1,503 @@
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::components::*;
+use crate::constants::*;
+use crate::state_systems::change_state;
+use bevy::prelude::*;
+use bevy::utils::Instant;
+use rand::Rng;
+
+const HELLO: u8 = 0;
+const WELCOME: u8 = 1;
+const INPUTS: u8 = 2;
+const CHECKSUM: u8 = 3;
+
+/// Most tick inputs sent in one packet, the rest follow once these are acknowledged.
+const MAX_INPUTS_PER_PACKET: usize = 256;
+
+/// Sets up an online game from the `host <port>` or `join <address:port>` arguments, optionally
+/// followed by `prediction=<ticks>`, `latency=<milliseconds>` and `loss=<percent>`. The latency
+/// and loss are added to the sent packets, to try out a bad connection on one machine.
+pub fn lockstep_from_args(args: &[String], seed: u64) -> Option<Lockstep> {
+    let mut args = args.iter().cloned();
+    let (role, bind, peer) = match (args.next().as_deref(), args.next()) {
+        (Some("host"), Some(port)) => match port.parse::<u16>() {
+            Ok(port) => (NetRole::Host, format!("0.0.0.0:{}", port), None),
+            Err(_) => {
+                println!("Can't host on port '{}'.", port);
+                return None;
+            }
+        },
+        (Some("join"), Some(address)) => {
+            match address
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut found| found.next())
+            {
+                Some(peer) => (NetRole::Join, "0.0.0.0:0".to_string(), Some(peer)),
+                None => {
+                    println!("Can't find the host '{}'.", address);
+                    return None;
+                }
+            }
+        }
+        // joining or watching a dedicated server, see `connection_from_args`
+        (None, _) | (Some("connect" | "spectate"), _) => return None,
+        _ => {
+            println!(
+                "Unknown arguments, use 'host <port>', 'join <address:port>', 'connect <address:port> [room]' or 'spectate <address:port> [room]', or see --help."
+            );
+            return None;
+        }
+    };
+
+    let socket = match UdpSocket::bind(&bind).and_then(|socket| {
+        socket.set_nonblocking(true)?;
+        Ok(socket)
+    }) {
+        Ok(socket) => socket,
+        Err(error) => {
+            println!("Can't open a socket on {}: {}", bind, error);
+            return None;
+        }
+    };
+    match peer {
+        Some(peer) => println!("Joining {}.", peer),
+        None => println!("Hosting on {}, waiting for the other player.", bind),
+    }
+
+    let mut lockstep = Lockstep::new(socket, role, peer, seed);
+    for arg in args {
+        match arg.split_once('=') {
+            Some(("prediction", value)) => {
+                if let Some(ticks) = parse_option(&arg, value) {
+                    lockstep.prediction = ticks;
+                }
+            }
+            Some(("latency", value)) => {
+                if let Some(millis) = parse_option(&arg, value) {
+                    lockstep.link.latency = Duration::from_millis(millis);
+                }
+            }
+            Some(("loss", value)) => {
+                if let Some(percent) = parse_option::<f32>(&arg, value) {
+                    lockstep.link.loss = (percent / 100.).clamp(0., 1.);
+                }
+            }
+            _ => println!("Unknown argument '{}', ignoring it.", arg),
+        }
+    }
+    Some(lockstep)
+}
+
+pub fn parse_option<T: FromStr>(arg: &str, value: &str) -> Option<T> {
+    let parsed = value.parse().ok();
+    if parsed.is_none() {
+        println!("Can't read '{}', ignoring it.", arg);
+    }
+    parsed
+}
+
+/// Bits of the actions a player holds, everything but pause which isn't played online.
+pub fn read_input(actions: &ActionInput, id: PlayerId) -> u8 {
+    Action::ALL
+        .iter()
+        .enumerate()
+        .filter(|(_, action)| **action != Action::Pause && actions.pressed(id, **action))
+        .fold(0, |bits, (index, _)| bits | 1 << index)
+}
+
+pub fn press_input(actions: &mut ActionInput, id: PlayerId, bits: u8) {
+    for (index, action) in Action::ALL.iter().enumerate() {
+        if bits & 1 << index != 0 {
+            actions.press(id, *action);
+        }
+    }
+}
+
+/// Records the state checksum of a tick and sends it to the other peer to compare.
+pub fn send_checksum(lockstep: &mut Lockstep, tick: u32, checksum: u64) {
+    let mut packet = vec![CHECKSUM];
+    packet.extend(tick.to_le_bytes());
+    packet.extend(checksum.to_le_bytes());
+    send(lockstep, &packet);
+
+    lockstep.local_checksums.insert(tick, checksum);
+    compare_checksums(lockstep);
+}
+
+fn compare_checksums(lockstep: &mut Lockstep) {
+    let matched: Vec<u32> = lockstep
+        .local_checksums
+        .keys()
+        .filter(|tick| lockstep.remote_checksums.contains_key(*tick))
+        .copied()
+        .collect();
+    for tick in matched {
+        let local = lockstep.local_checksums.remove(&tick);
+        lockstep.checksums_compared += 1;
+        if local != lockstep.remote_checksums.remove(&tick) {
+            lockstep.desyncs += 1;
+            println!(
+                "Desync detected at tick {}, the games no longer match.",
+                tick
+            );
+        }
+    }
+
+    // checksums whose counterpart got lost are given up after a while
+    let oldest = lockstep.tick.saturating_sub(CHECKSUM_INTERVAL * 10);
+    lockstep.local_checksums.retain(|tick, _| *tick >= oldest);
+    lockstep.remote_checksums.retain(|tick, _| *tick >= oldest);
+}
+
+/// Queues a packet until the added latency passed, unless the added loss drops it.
+fn send(lockstep: &mut Lockstep, packet: &[u8]) {
+    let link = &mut lockstep.link;
+    if link.loss > 0. && link.random.gen::<f32>() < link.loss {
+        return;
+    }
+    link.queue
+        .push_back((Instant::now() + link.latency, packet.to_vec()));
+    flush_packets(lockstep);
+}
+
+fn flush_packets(lockstep: &mut Lockstep) {
+    let now = Instant::now();
+    while let Some((due, _)) = lockstep.link.queue.front() {
+        if *due > now {
+            break;
+        }
+        let (_, packet) = lockstep.link.queue.pop_front().unwrap();
+        if let Some(peer) = lockstep.peer {
+            if let Err(error) = lockstep.socket.send_to(&packet, peer) {
+                println!("Can't send to the other player: {}", error);
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(..4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(..8)?.try_into().ok()?))
+}
+
+/// What both peers set the same way when their battle starts.
+type BattleStart<'w> = (
+    ResMut<'w, GameSettings>,
+    ResMut<'w, GameRng>,
+    ResMut<'w, SimTime>,
+    ResMut<'w, Field>,
+    ResMut<'w, Battle>,
+    ResMut<'w, Scores>,
+);
+
+/// Reads the packets of the other peer, starts the battle once the peers found each other and
+/// gives up on a peer that went silent.
+pub fn net_receive_system(
+    mut commands: Commands,
+    lockstep: Option<ResMut<Lockstep>>,
+    time: Res<Time>,
+    start: BattleStart,
+    mut state: ResMut<State<AppState>>,
+) {
+    let mut lockstep = match lockstep {
+        Some(lockstep) => lockstep,
+        None => return,
+    };
+
+    if lockstep.role == NetRole::Join && !lockstep.started {
+        lockstep.hello_timer.tick(time.delta());
+        if lockstep.hello_timer.just_finished() {
+            send(&mut lockstep, &[HELLO]);
+        }
+    }
+
+    let mut buffer = [0; 1024];
+    let mut heard = false;
+    while let Ok((length, sender)) = lockstep.socket.recv_from(&mut buffer) {
+        if lockstep.peer.is_some_and(|peer| peer != sender) {
+            continue;
+        }
+        heard = true;
+        let packet = &buffer[..length];
+        match packet.first() {
+            Some(&HELLO) if lockstep.role == NetRole::Host => {
+                lockstep.peer = Some(sender);
+                // sent for every hello, in case the last welcome got lost
+                let mut welcome = vec![WELCOME];
+                welcome.extend(lockstep.seed.to_le_bytes());
+                send(&mut lockstep, &welcome);
+                lockstep.started = true;
+            }
+            Some(&WELCOME) if lockstep.role == NetRole::Join => {
+                if let Some(seed) = read_u64(&packet[1..]) {
+                    lockstep.seed = seed;
+                    lockstep.started = true;
+                }
+            }
+            Some(&INPUTS) => receive_inputs(&mut lockstep, &packet[1..]),
+            Some(&CHECKSUM) => {
+                if let (Some(tick), Some(checksum)) =
+                    (read_u32(&packet[1..]), packet.get(5..).and_then(read_u64))
+                {
+                    lockstep.remote_checksums.insert(tick, checksum);
+                    compare_checksums(&mut lockstep);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if !lockstep.started {
+        return;
+    }
+    if *state.current() == AppState::MainMenu {
+        let (mut settings, mut rng, mut sim_time, mut field, mut battle, mut scores) = start;
+        println!("Connected, player {} is yours.", lockstep.local_id.0);
+        settings.mode = GameMode::Battle;
+        settings.player_count = 2;
+        settings.difficulty = Difficulty::Normal;
+        settings.bots = 0;
+        *rng = GameRng::new(lockstep.seed);
+        *sim_time = SimTime::default();
+        *battle = Battle::new(BATTLE_BEST_OF);
+        *scores = Scores::default();
+        field.current_level = 1;
+        change_state(&mut state, AppState::Loading);
+    }
+
+    lockstep.silence = if heard {
+        0.
+    } else {
+        lockstep.silence + time.delta_seconds()
+    };
+    if lockstep.silence > PEER_TIMEOUT {
+        println!("Lost the connection to the other player.");
+        commands.remove_resource::<Lockstep>();
+        change_state(&mut state, AppState::MainMenu);
+    }
+}
+
+fn receive_inputs(lockstep: &mut Lockstep, bytes: &[u8]) {
+    let (ack, first) = match (read_u32(bytes), bytes.get(4..).and_then(read_u32)) {
+        (Some(ack), Some(first)) => (ack as usize, first as usize),
+        _ => return,
+    };
+    lockstep.acked = lockstep.acked.max(ack.min(lockstep.local_inputs.len()));
+
+    // ticks already received are skipped, a gap waits for the lost packet to be sent again
+    for (offset, bits) in bytes[8..].iter().enumerate() {
+        if first + offset == lockstep.remote_inputs.len() {
+            lockstep.remote_inputs.push(*bits);
+        }
+    }
+}
+
+/// Sends the local inputs the other peer hasn't acknowledged yet, every frame so that lost
+/// packets are made up for, along with the queued packets whose latency passed.
+pub fn net_send_system(lockstep: Option<ResMut<Lockstep>>) {
+    let mut lockstep = match lockstep {
+        Some(lockstep) => lockstep,
+        None => return,
+    };
+    flush_packets(&mut lockstep);
+    if !lockstep.started {
+        return;
+    }
+
+    let end = lockstep
+        .local_inputs
+        .len()
+        .min(lockstep.acked + MAX_INPUTS_PER_PACKET);
+    let mut packet = vec![INPUTS];
+    packet.extend((lockstep.remote_inputs.len() as u32).to_le_bytes());
+    packet.extend((lockstep.acked as u32).to_le_bytes());
+    packet.extend(&lockstep.local_inputs[lockstep.acked..end]);
+    send(&mut lockstep, &packet);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rollback_systems::snapshot_system;
+    use crate::simulation_systems::simulation_criteria;
+    use crate::utils::cell_to_position;
+    use std::thread;
+
+    /// One peer waiting in the main menu, without window and without the level systems.
+    fn peer_app(lockstep: Lockstep) -> App {
+        let mut app = App::new();
+        app.add_plugins(MinimalPlugins)
+            .add_state(AppState::MainMenu)
+            .insert_resource(GameSettings {
+                player_count: 1,
+                mode: GameMode::Campaign,
+                difficulty: Difficulty::Normal,
+                bots: 0,
+                bot_difficulty: Difficulty::Normal,
+            })
+            .insert_resource(GameRng::new(0))
+            .insert_resource(SimTime::default())
+            .insert_resource(Field {
+                array: [[0; 30]; 30],
+                properties: LevelProperties::default(),
+                current_level: 3,
+                first_level: 1,
+                boss_defeated: false,
+                time: 0.,
+            })
+            .insert_resource(Battle::new(BATTLE_BEST_OF))
+            .insert_resource(Scores::default())
+            .insert_resource(lockstep)
+            .add_system_to_stage(CoreStage::PreUpdate, net_receive_system)
+            .add_system_to_stage(CoreStage::PostUpdate, net_send_system);
+        app
+    }
+
+    /// One peer of an online battle whose level is only the two players, stepping where their
+    /// peer presses a move that changes every few frames.
+    fn battle_app(lockstep: Lockstep) -> App {
+        let mut app = peer_app(lockstep);
+        app.insert_resource(Lives::default())
+            .insert_resource(HurryUp::default())
+            .insert_resource(DangerMap::default())
+            .insert_resource(PendingState::default())
+            .insert_resource(ActionInput::default())
+            .add_system_to_stage(CoreStage::PreUpdate, press_system)
+            .add_system_set(SystemSet::on_update(AppState::Loading).with_system(start_system))
+            .add_stage_after(
+                CoreStage::Update,
+                SimulationStage,
+                SystemStage::single_threaded().with_run_criteria(simulation_criteria),
+            )
+            .add_system_to_stage(SimulationStage, snapshot_system.exclusive_system().at_start())
+            .add_system_to_stage(SimulationStage, step_system);
+        app
+    }
+
+    fn press_system(
+        lockstep: Res<Lockstep>,
+        mut actions: ResMut<ActionInput>,
+        mut frame: Local<usize>,
+    ) {
+        *frame += 1;
+        *actions = ActionInput::default();
+        // the keyboard of either peer steers player 1's bindings
+        let action = Action::ALL[(*frame / 5 + lockstep.local_id.0) % 5];
+        actions.press(PlayerId(1), action);
+    }
+
+    fn start_system(mut commands: Commands, mut state: ResMut<State<AppState>>) {
+        for (id, cell) in [(PlayerId(1), (1, 1)), (PlayerId(2), (9, 9))] {
+            commands
+                .spawn()
+                .insert(Transform::from_translation(cell_to_position(cell)))
+                .insert(Player {
+                    health: 1,
+                    last_hit: Duration::ZERO,
+                    max_bombs: 1,
+                })
+                .insert(id)
+                .insert(Speed::new(1.))
+                .insert(GridCell(cell));
+        }
+        change_state(&mut state, AppState::Playing);
+    }
+
+    /// Steps every player a cell towards the move it holds, counting its bombs in its health.
+    fn step_system(
+        actions: Res<ActionInput>,
+        mut player_query: Query<(&PlayerId, &mut Player, &mut GridCell)>,
+    ) {
+        let moves = [
+            (Action::MoveUp, (0, 1)),
+            (Action::MoveDown, (0, -1)),
+            (Action::MoveLeft, (-1, 0)),
+            (Action::MoveRight, (1, 0)),
+        ];
+        for (id, mut player, mut cell) in player_query.iter_mut() {
+            for (action, (x, y)) in moves {
+                if actions.pressed(*id, action) {
+                    let size = SIZE_IN_CELLS as i32;
+                    cell.0 = ((cell.0 .0 + x).rem_euclid(size), (cell.0 .1 + y).rem_euclid(size));
+                }
+            }
+            if actions.just_pressed(*id, Action::PlaceBomb) {
+                player.health += 1;
+            }
+        }
+    }
+
+    fn localhost() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_nonblocking(true).unwrap();
+        socket
+    }
+
+    /// Updates both apps until `done` holds for both, returning them.
+    fn run_peers(mut apps: [App; 2], done: impl Fn(&App) -> bool) -> [App; 2] {
+        let started = Instant::now();
+        while !apps.iter().all(&done) {
+            assert!(
+                started.elapsed() < Duration::from_secs(60),
+                "the peers didn't get there"
+            );
+            for app in apps.iter_mut() {
+                app.update();
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+        apps
+    }
+
+    #[test]
+    fn host_and_join_over_localhost() {
+        let args = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let host = lockstep_from_args(&args(&["host", "0"]), 9).unwrap();
+        let address = format!("127.0.0.1:{}", host.socket.local_addr().unwrap().port());
+        let join = lockstep_from_args(&args(&["join", &address]), 0).unwrap();
+        let apps = [peer_app(host), peer_app(join)];
+
+        let apps = run_peers(apps, |app| {
+            *app.world.resource::<State<AppState>>().current() == AppState::Loading
+        });
+        for (app, id) in apps.iter().zip([PlayerId(1), PlayerId(2)]) {
+            let lockstep = app.world.resource::<Lockstep>();
+            assert_eq!(lockstep.seed, 9);
+            assert_eq!(lockstep.local_id, id);
+            assert_eq!(app.world.resource::<GameRng>().state, GameRng::new(9).state);
+            assert_eq!(app.world.resource::<GameSettings>().mode, GameMode::Battle);
+            assert_eq!(app.world.resource::<Field>().current_level, 1);
+        }
+    }
+
+    #[test]
+    fn lossy_peers_agree_on_checksums() {
+        let (host, join) = (localhost(), localhost());
+        let (host_address, join_address) = (host.local_addr().unwrap(), join.local_addr().unwrap());
+        let lossy = |mut lockstep: Lockstep| {
+            lockstep.link.latency = Duration::from_millis(30);
+            lockstep.link.loss = 0.2;
+            lockstep
+        };
+        let apps = [
+            battle_app(lossy(Lockstep::new(host, NetRole::Host, Some(join_address), 5))),
+            battle_app(lossy(Lockstep::new(join, NetRole::Join, Some(host_address), 0))),
+        ];
+
+        let apps = run_peers(apps, |app| {
+            app.world.resource::<Lockstep>().verified >= 4 * CHECKSUM_INTERVAL
+        });
+        for app in apps {
+            let lockstep = app.world.resource::<Lockstep>();
+            assert!(lockstep.checksums_compared > 0);
+            assert_eq!(lockstep.desyncs, 0);
+        }
+    }
+}
@@ -2,92 +2,256 @@ use std::time::Duration;
 
 use crate::components::*;
 use crate::constants::*;
-use bevy::app::AppExit;
+use crate::utils::{cell_to_position, position_to_cell};
 use bevy::prelude::*;
+use bevy::utils::HashSet;
 use bevy_tweening::lens::TransformPositionLens;
 use bevy_tweening::*;
 
 pub const OFFSET: f32 = CELL_SIZE / 2.;
 
-type PlayerQuery<'a> = (&'a mut Transform, &'a mut TextureAtlasSprite, Entity, Option<&'a Animator<Transform>>);
+pub const PLAYER_COLORS: [Color; MAX_PLAYERS] = [
+    Color::WHITE,
+    Color::rgb(0.6, 0.8, 1.),
+    Color::rgb(0.6, 1., 0.6),
+    Color::rgb(1., 0.9, 0.5),
+];
+
+pub fn spawn_player(
+    commands: &mut Commands,
+    textures: &GameTextures,
+    id: PlayerId,
+    cell: (i32, i32),
+    health: i32,
+) -> Entity {
+    let mut translation = cell_to_position(cell);
+    translation.z = 1.;
+
+    commands
+        .spawn_bundle(SpriteSheetBundle {
+            texture_atlas: textures.player.clone(),
+            sprite: TextureAtlasSprite {
+                color: PLAYER_COLORS[id.0 - 1],
+                ..Default::default()
+            },
+            transform: Transform {
+                translation,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .insert(Player {
+            health,
+            last_hit: Duration::ZERO,
+            max_bombs: 1,
+        })
+        .insert(PlayerMovement::default())
+        .insert(Speed::new(PLAYER_SPEED))
+        .insert(GridCell(cell))
+        .insert(id)
+        .id()
+}
+
+type PlayerQuery<'a> = (
+    &'a Transform,
+    &'a mut TextureAtlasSprite,
+    Entity,
+    &'a PlayerId,
+    &'a mut PlayerMovement,
+    &'a Speed,
+    &'a mut GridCell,
+);
+
+/// Direction actions with their cell offset and sprite index, earlier ones win when pressed on the same frame.
+pub const MOVES: [(Action, (i32, i32), usize); 4] = [
+    (Action::MoveUp, (0, 1), 1),
+    (Action::MoveDown, (0, -1), 0),
+    (Action::MoveRight, (1, 0), 2),
+    (Action::MoveLeft, (-1, 0), 3),
+];
 
 pub fn move_player_system(
-    mut query: Query<
-        PlayerQuery,
-        (With<Player>, Without<Solid>),
-    >,
-    walls_query: Query<&Transform, With<Solid>>,
-    key: Res<Input<KeyCode>>,
+    mut query: Query<PlayerQuery, (With<Player>, Without<Solid>)>,
+    walls_query: Query<(&Transform, Option<&GridCell>), With<Solid>>,
+    actions: Res<ActionInput>,
+    field: Res<Field>,
+    time: Res<SimTime>,
+    mut commands: Commands,
+) {
+    let solids: HashSet<(i32, i32)> = walls_query
+        .iter()
+        .map(|(wall, cell)| cell.map_or_else(|| position_to_cell(wall.translation), |cell| cell.0))
+        .collect();
+
+    for (transform, mut texture, entity, id, mut movement, speed, mut cell) in query.iter_mut() {
+        movement.held.retain(|action| actions.pressed(*id, *action));
+        for (action, _, _) in MOVES.iter().rev().copied() {
+            if actions.just_pressed(*id, action) {
+                movement.held.retain(|held| *held != action);
+                movement.held.push(action);
+                // only the latest press is kept, the buffer holds a single move
+                movement.buffered = Some(action);
+            }
+        }
+
+        movement.step.tick(time.delta());
+        if !movement.step.finished() {
+            continue;
+        }
+
+        // a buffered tap goes first, otherwise keep walking in the most recently held direction
+        let action = match movement.buffered.take() {
+            Some(action) => action,
+            None => match movement.held.last() {
+                Some(action) => *action,
+                None => continue,
+            },
+        };
+        let (_, (dx, dy), index) = match MOVES.iter().find(|(direction, _, _)| *direction == action) {
+            Some(direction) => *direction,
+            None => continue,
+        };
+
+        texture.index = index;
+        let (x, y) = cell.0;
+        if solids.contains(&(x + dx, y + dy)) {
+            continue;
+        }
+
+        let duration = speed.step_duration(field.is_slow(cell.0));
+        movement.step = Timer::new(duration, false);
+        cell.0 = (x + dx, y + dy);
+        let mut end = cell_to_position(cell.0);
+        end.z = transform.translation.z;
+        commands.entity(entity).insert(Animator::new(Tween::new(
+            EaseFunction::QuadraticIn,
+            TweeningType::Once,
+            duration,
+            TransformPositionLens {
+                start: transform.translation,
+                end,
+            },
+        )));
+    }
+}
+
+/// Speeds up players walking over a speed pickup.
+pub fn speed_pickup_system(
+    mut commands: Commands,
+    mut player_query: Query<(&GridCell, &mut Speed), With<Player>>,
+    pickup_query: Query<(Entity, &Transform), With<SpeedPickup>>,
+) {
+    for (entity, transform) in pickup_query.iter() {
+        let cell = position_to_cell(transform.translation);
+        if let Some((_, mut speed)) = player_query
+            .iter_mut()
+            .find(|(player_cell, _)| player_cell.0 == cell)
+        {
+            speed.bonus = (speed.bonus + SPEED_PICKUP_BONUS).min(MAX_SPEED - speed.base);
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
+/// Lets players 2 to 4 drop into the game by pressing their place bomb action.
+pub fn join_player_system(
+    mut commands: Commands,
+    mut settings: ResMut<GameSettings>,
+    field: Res<Field>,
+    textures: Res<GameTextures>,
+    actions: Res<ActionInput>,
+) {
+    if settings.player_count >= MAX_PLAYERS {
+        return;
+    }
+
+    let id = PlayerId(settings.player_count + 1);
+    if actions.just_pressed(id, Action::PlaceBomb) {
+        settings.player_count = id.0;
+        println!("Player {} joined.", id.0);
+
+        if let Some(cell) = field.spawn_cell(id).or_else(|| field.spawn_cell(PlayerId(1))) {
+            spawn_player(&mut commands, &textures, id, cell, settings.mode.player_health());
+        }
+    }
+}
+
+type RespawnQuery<'a> = (
+    Entity,
+    &'a mut Player,
+    &'a PlayerId,
+    &'a mut Transform,
+    &'a mut GridCell,
+    &'a mut PlayerMovement,
+    &'a mut TextureAtlasSprite,
+);
+
+/// Respawns campaign players who have lives left and ends the game once nobody is left.
+pub fn player_health_system(
     mut commands: Commands,
+    mut player_query: Query<RespawnQuery>,
+    settings: Res<GameSettings>,
+    field: Res<Field>,
+    mut lives: ResMut<Lives>,
+    mut pending: ResMut<PendingState>,
 ) {
-    if let Ok((transform, mut texture, entity, animator)) = query.get_single_mut() {
-            if animator.is_none() || (animator.is_some() && animator.unwrap().progress() == 1.0) {
-                let mut end = transform.translation;
-                let mut closest_walls: Vec<&Transform> = Vec::new();
-
-                for wall in walls_query.iter() {
-                    if ((wall.translation.x - CELL_SIZE)..(wall.translation.x + CELL_SIZE * 2.))
-                        .contains(&transform.translation.x)
-                        && ((wall.translation.y - CELL_SIZE)..(wall.translation.y + CELL_SIZE * 2.))
-                            .contains(&transform.translation.y)
-                    {
-                        closest_walls.push(wall);
-                    }
-                }
-
-                if key.just_pressed(KeyCode::W) && !closest_walls.iter().any(|wall| {
-                        wall.translation.x as i32 == transform.translation.x as i32
-                            && wall.translation.y as i32
-                                == transform.translation.y as i32 + CELL_SIZE as i32
-                    }) {
-                    end.y += CELL_SIZE;
-                    texture.index = 1;
-                }
-                if key.just_pressed(KeyCode::S) && !closest_walls.iter().any(|wall| {
-                        wall.translation.x as i32 == transform.translation.x as i32
-                            && wall.translation.y as i32
-                                == transform.translation.y as i32 - CELL_SIZE as i32
-                    }) {
-                    end.y -= CELL_SIZE;
-                    texture.index = 0;
-                }
-                if key.just_pressed(KeyCode::D) && !closest_walls.iter().any(|wall| {
-                        wall.translation.x as i32
-                            == transform.translation.x as i32 + CELL_SIZE as i32
-                            && wall.translation.y as i32 == transform.translation.y as i32
-                    }) {
-                    end.x += CELL_SIZE;
-                    texture.index = 2;
-                }
-                if key.just_pressed(KeyCode::A) && !closest_walls.iter().any(|wall| {
-                        wall.translation.x as i32
-                            == transform.translation.x as i32 - CELL_SIZE as i32
-                            && wall.translation.y as i32 == transform.translation.y as i32
-                    }) {
-                    end.x -= CELL_SIZE;
-                    texture.index = 3;
-                }
-
-                if end != transform.translation {
-                    commands.entity(entity).insert(Animator::new(Tween::new(
-                        EaseFunction::QuadraticIn,
-                        TweeningType::Once,
-                        Duration::from_millis(MOVE_ANIMATION_DURATION),
-                        TransformPositionLens {
-                            start: transform.translation,
-                            end,
-                        },
-                    )));
-                }
+    let mut remaining = 0;
+    for (entity, mut player, id, mut transform, mut cell, mut movement, mut sprite) in
+        player_query.iter_mut()
+    {
+        if player.health > 0 {
+            remaining += 1;
+            continue;
+        }
+
+        // battle rounds are decided by battle_round_system, nobody respawns there
+        let spawn = field.spawn_cell(*id).or_else(|| field.spawn_cell(PlayerId(1)));
+        match spawn {
+            Some(spawn) if settings.mode == GameMode::Campaign && lives.left[id.0 - 1] > 0 => {
+                lives.left[id.0 - 1] -= 1;
+                println!("Player {} respawns, {} lives left.", id.0, lives.left[id.0 - 1]);
+
+                player.health = settings.mode.player_health();
+                transform.translation = cell_to_position(spawn);
+                transform.translation.z = 1.;
+                cell.0 = spawn;
+                *movement = PlayerMovement::default();
+                sprite.color = PLAYER_COLORS[id.0 - 1];
+                commands
+                    .entity(entity)
+                    .remove::<Animator<Transform>>()
+                    .insert(Invulnerable {
+                        timer: Timer::from_seconds(INVULNERABILITY_DURATION, false),
+                    });
+                remaining += 1;
+            }
+            _ => {
+                println!("Player {} is out!", id.0);
+                commands.entity(entity).despawn();
             }
+        }
+    }
+
+    if settings.mode == GameMode::Campaign && !player_query.is_empty() && remaining == 0 {
+        println!("Game Over!");
+        pending.set(AppState::GameOver);
     }
 }
 
-pub fn player_health_system(player_query: Query<&Player>, mut exit: EventWriter<AppExit>) {
-    if let Ok(player) =  player_query.get_single() {
-        if player.health <= 0 {
-            println!("Game Over!");
-            exit.send(AppExit);
+/// Blinks respawned players while they can't be hurt.
+pub fn invulnerability_system(
+    mut commands: Commands,
+    mut query: Query<(Entity, &mut Invulnerable, &mut Visibility)>,
+    time: Res<SimTime>,
+) {
+    for (entity, mut invulnerable, mut visibility) in query.iter_mut() {
+        invulnerable.timer.tick(time.delta());
+        if invulnerable.timer.finished() {
+            visibility.is_visible = true;
+            commands.entity(entity).remove::<Invulnerable>();
+        } else {
+            let blinks = (invulnerable.timer.elapsed_secs() / BLINK_INTERVAL) as u32;
+            visibility.is_visible = blinks.is_multiple_of(2);
         }
     }
 }
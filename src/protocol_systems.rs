@@ -0,0 +1,589 @@
+//! Bots in any language play headless matches over JSON lines. The game sends every bot one
+//! line when the match starts, one per tick and one once it is over:
+//!
+//! ```text
+//! {"hello":"bon'berman","you":1,"players":2,"mode":"battle","deadline_ms":100}
+//! {"you":1,"tick":0,"time":0.02,"grid":["WWW...",...],
+//!  "bombs":[{"x":3,"y":4,"fuse":2.5,"owner":1}],"explosions":[{"x":3,"y":5}],
+//!  "players":[{"id":1,"x":3,"y":4,"health":3,"max_bombs":1}],
+//!  "enemies":[{"x":7,"y":9,"health":2,"boss":false}]}
+//! {"done":true,"ticks":812,"winner":1}
+//! ```
+//!
+//! `grid[y]` is the row of cells at height `y`, from the bottom, with the glyphs of the level
+//! files: `W` walls, `B` breakable walls, `P` spawners, `~` slow tiles, `+` speed pickups and `.`
+//! for the rest. A bot answers every tick with `{"tick":0,"action":"place_bomb"}`, the action
+//! being one of `move_up`, `move_down`, `move_left`, `move_right`, `place_bomb`, `detonate` or
+//! `none`. Answers that miss the deadline count as `none`.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::components::*;
+use crate::constants::*;
+use crate::env_systems::Environment;
+use crate::utils::position_to_cell;
+use bevy::prelude::*;
+
+/// Who plays a player of a headless match.
+#[derive(Clone, Debug)]
+pub enum PlayerSpec {
+    /// A program started with this command line, or reached at `tcp:<address:port>`.
+    External(String),
+    /// A player steered by `bot_system`.
+    Cpu,
+}
+
+#[derive(Clone, Debug)]
+pub struct MatchConfig {
+    pub mode: GameMode,
+    pub pack: LevelPack,
+    pub level: u32,
+    pub seed: u64,
+    /// Time the external bots get to answer a tick.
+    pub deadline: Duration,
+    /// Ticks after which the match is stopped undecided.
+    pub max_ticks: u32,
+    pub bot_difficulty: Difficulty,
+    /// CPU players play after the external ones, whatever the order here.
+    pub players: Vec<PlayerSpec>,
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct MatchResult {
+    pub ticks: u32,
+    /// Indexed by player id - 1.
+    pub health: [i32; MAX_PLAYERS],
+    /// Indexed by player id - 1.
+    pub points: [u32; MAX_PLAYERS],
+    /// The last player standing of a battle, or the healthiest one once time ran out. Nobody
+    /// wins a tie or the campaign.
+    pub winner: Option<PlayerId>,
+    /// What every player pressed every tick, the CPU players included, enough to replay the
+    /// match.
+    pub actions: Vec<Vec<Vec<Action>>>,
+}
+
+/// A program playing over its standard input and output, or over a TCP connection.
+pub struct ExternalBot {
+    pub spec: String,
+    writer: Box<dyn Write + Send>,
+    lines: Receiver<String>,
+    child: Option<Child>,
+    /// Set once writing to the bot failed, it isn't sent anything after that.
+    gone: bool,
+}
+
+impl ExternalBot {
+    pub fn start(spec: &str) -> io::Result<Self> {
+        match spec.strip_prefix("tcp:") {
+            Some(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                let reader = stream.try_clone()?;
+                Ok(ExternalBot {
+                    spec: spec.to_string(),
+                    writer: Box::new(stream),
+                    lines: read_lines(reader),
+                    child: None,
+                    gone: false,
+                })
+            }
+            None => {
+                let mut words = spec.split_whitespace();
+                let program = words.next().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "the command is empty")
+                })?;
+                let mut child = Command::new(program)
+                    .args(words)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .spawn()?;
+                let (stdin, stdout) = match (child.stdin.take(), child.stdout.take()) {
+                    (Some(stdin), Some(stdout)) => (stdin, stdout),
+                    _ => {
+                        let _ = child.kill();
+                        return Err(io::Error::new(
+                            io::ErrorKind::BrokenPipe,
+                            "the bot's standard streams aren't there",
+                        ));
+                    }
+                };
+                Ok(ExternalBot {
+                    spec: spec.to_string(),
+                    writer: Box::new(stdin),
+                    lines: read_lines(stdout),
+                    child: Some(child),
+                    gone: false,
+                })
+            }
+        }
+    }
+
+    pub fn send(&mut self, line: &str) {
+        if self.gone {
+            return;
+        }
+        let sent = writeln!(self.writer, "{}", line).and_then(|_| self.writer.flush());
+        if let Err(error) = sent {
+            println!("Can't reach the bot '{}' anymore: {}", self.spec, error);
+            self.gone = true;
+        }
+    }
+
+    /// The action the bot answered for `tick` with, skipping the late answers to earlier ticks.
+    /// Nothing once the deadline passed.
+    pub fn receive(&mut self, tick: u32, deadline: Instant) -> Option<Action> {
+        loop {
+            let wait = deadline.saturating_duration_since(Instant::now());
+            let line = self.lines.recv_timeout(wait).ok()?;
+            match parse_answer(&line) {
+                Some((answer, action)) if answer == tick => return action,
+                Some((answer, _)) if answer < tick => continue,
+                _ => println!(
+                    "Can't read the answer '{}' of the bot '{}'.",
+                    line, self.spec
+                ),
+            }
+        }
+    }
+}
+
+impl Drop for ExternalBot {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.child {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+/// Hands the lines read on a thread of their own to a channel, so they can be waited for with
+/// a deadline.
+fn read_lines<R: io::Read + Send + 'static>(reader: R) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in BufReader::new(reader).lines() {
+            // stops once the bot closed its end or the match is over
+            let sent = match line {
+                Ok(line) => sender.send(line).is_ok(),
+                Err(_) => false,
+            };
+            if !sent {
+                break;
+            }
+        }
+    });
+    receiver
+}
+
+/// Plays a headless match, asking the external bots for their actions every tick.
+pub fn run_match(config: &MatchConfig) -> MatchResult {
+    let mut specs: Vec<&PlayerSpec> = config.players.iter().collect();
+    specs.sort_by_key(|spec| matches!(spec, PlayerSpec::Cpu));
+    let players = specs.len().clamp(1, MAX_PLAYERS);
+
+    // bots that can't be started still take their place, they just never do anything
+    let mut bots: Vec<Option<ExternalBot>> = specs
+        .iter()
+        .take(players)
+        .filter_map(|spec| match spec {
+            PlayerSpec::External(command) => Some(command),
+            PlayerSpec::Cpu => None,
+        })
+        .map(|command| match ExternalBot::start(command) {
+            Ok(bot) => Some(bot),
+            Err(error) => {
+                println!("Can't start the bot '{}': {}", command, error);
+                None
+            }
+        })
+        .collect();
+
+    let (mut env, mut observation) = start_match(config, players, bots.len());
+    for (index, bot) in bots.iter_mut().enumerate() {
+        if let Some(bot) = bot {
+            bot.send(&format!(
+                concat!(
+                    "{{\"hello\":\"bon'berman\",\"you\":{},\"players\":{},\"mode\":\"{}\",",
+                    "\"deadline_ms\":{}}}"
+                ),
+                index + 1,
+                players,
+                config.mode.name(),
+                config.deadline.as_millis()
+            ));
+        }
+    }
+
+    let mut ticks = 0;
+    let mut recorded = Vec::new();
+    while ticks < config.max_ticks {
+        let state = state_json(env.world_mut(), ticks);
+        for (index, bot) in bots.iter_mut().enumerate() {
+            if let Some(bot) = bot {
+                bot.send(&format!("{{\"you\":{},{}", index + 1, &state[1..]));
+            }
+        }
+
+        // every bot thinks at the same time, against the same deadline
+        let deadline = Instant::now() + config.deadline;
+        let actions: Vec<Option<Action>> = bots
+            .iter_mut()
+            .map(|bot| bot.as_mut().and_then(|bot| bot.receive(ticks, deadline)))
+            .collect();
+
+        let (next, _, done) = env.step(&actions);
+        observation = next;
+        recorded.push(env.last_pressed());
+        ticks += 1;
+        if done {
+            break;
+        }
+    }
+
+    let result = match_result(config, players, &observation, recorded);
+    let winner = result
+        .winner
+        .map_or("null".to_string(), |id| id.0.to_string());
+    for bot in bots.iter_mut().flatten() {
+        bot.send(&format!(
+            "{{\"done\":true,\"ticks\":{},\"winner\":{}}}",
+            ticks, winner
+        ));
+    }
+    result
+}
+
+/// Plays a match again with what every player pressed, the CPU players being played back like
+/// the external bots rather than thinking again.
+pub fn replay_match(config: &MatchConfig, actions: &[Vec<Vec<Action>>]) -> MatchResult {
+    let players = config.players.len().clamp(1, MAX_PLAYERS);
+    let (mut env, mut observation) = start_match(config, players, players);
+
+    let mut recorded = Vec::new();
+    for tick_actions in actions.iter().take(config.max_ticks as usize) {
+        let (next, _, done) = env.step_pressed(tick_actions);
+        observation = next;
+        recorded.push(tick_actions.clone());
+        if done {
+            break;
+        }
+    }
+    match_result(config, players, &observation, recorded)
+}
+
+/// The environment of a match with its level loaded, the players after the `external` ones being
+/// CPU players.
+fn start_match(
+    config: &MatchConfig,
+    players: usize,
+    external: usize,
+) -> (Environment, Observation) {
+    let mut env = Environment::new(config.mode, players);
+    env.set_bots(players - external, config.bot_difficulty);
+    env.set_level_pack(config.pack.clone());
+    let observation = env.reset(config.seed, config.level);
+    (env, observation)
+}
+
+fn match_result(
+    config: &MatchConfig,
+    players: usize,
+    observation: &Observation,
+    actions: Vec<Vec<Vec<Action>>>,
+) -> MatchResult {
+    MatchResult {
+        ticks: actions.len() as u32,
+        health: observation.health,
+        points: observation.points,
+        winner: match config.mode {
+            GameMode::Battle => winner(&observation.health[..players]),
+            GameMode::Campaign => None,
+        },
+        actions,
+    }
+}
+
+/// The player with the most health left, if nobody else has as much.
+fn winner(health: &[i32]) -> Option<PlayerId> {
+    let best = *health.iter().max()?;
+    let mut leaders = health.iter().enumerate().filter(|(_, left)| **left == best);
+    match (leaders.next(), leaders.next()) {
+        (Some((index, _)), None) if best > 0 => Some(PlayerId(index + 1)),
+        _ => None,
+    }
+}
+
+/// The line every bot is sent for a tick, without the `you` that tells them apart.
+pub fn state_json(world: &mut World, tick: u32) -> String {
+    let size = SIZE_IN_CELLS;
+    let mut grid = vec![vec!['.'; size]; size];
+    let mut mark = |cell: (i32, i32), glyph: char| {
+        if (0..size as i32).contains(&cell.0) && (0..size as i32).contains(&cell.1) {
+            grid[cell.1 as usize][cell.0 as usize] = glyph;
+        }
+    };
+    for transform in world
+        .query_filtered::<&Transform, With<SlowTile>>()
+        .iter(world)
+    {
+        mark(position_to_cell(transform.translation), '~');
+    }
+    for transform in world
+        .query_filtered::<&Transform, With<SpeedPickup>>()
+        .iter(world)
+    {
+        mark(position_to_cell(transform.translation), '+');
+    }
+    for (transform, wall, breakable) in world
+        .query::<(&Transform, Option<&Wall>, Option<&BreakableWall>)>()
+        .iter(world)
+    {
+        let cell = position_to_cell(transform.translation);
+        match (wall, breakable) {
+            (Some(_), _) => mark(cell, 'W'),
+            (_, Some(_)) => mark(cell, 'B'),
+            _ => {}
+        }
+    }
+    for transform in world
+        .query_filtered::<&Transform, With<Spawner>>()
+        .iter(world)
+    {
+        mark(position_to_cell(transform.translation), 'P');
+    }
+    let rows: Vec<String> = grid
+        .iter()
+        .map(|row| format!("\"{}\"", row.iter().collect::<String>()))
+        .collect();
+
+    let bombs: Vec<String> = world
+        .query::<(&Bomb, &Transform)>()
+        .iter(world)
+        .map(|(bomb, transform)| {
+            let (x, y) = position_to_cell(transform.translation);
+            let fuse = if bomb.triggered {
+                0.
+            } else {
+                (bomb.fuse.duration() - bomb.fuse.elapsed()).as_secs_f32()
+            };
+            let owner = bomb.owner.map_or("null".to_string(), |id| id.0.to_string());
+            format!(
+                "{{\"x\":{},\"y\":{},\"fuse\":{:.3},\"owner\":{}}}",
+                x, y, fuse, owner
+            )
+        })
+        .collect();
+    let explosions: Vec<String> = world
+        .query_filtered::<&Transform, With<Explosion>>()
+        .iter(world)
+        .map(|transform| {
+            let (x, y) = position_to_cell(transform.translation);
+            format!("{{\"x\":{},\"y\":{}}}", x, y)
+        })
+        .collect();
+    let mut players: Vec<(usize, String)> = world
+        .query::<(&PlayerId, &Player, &GridCell)>()
+        .iter(world)
+        .map(|(id, player, cell)| {
+            let line = format!(
+                "{{\"id\":{},\"x\":{},\"y\":{},\"health\":{},\"max_bombs\":{}}}",
+                id.0, cell.0 .0, cell.0 .1, player.health, player.max_bombs
+            );
+            (id.0, line)
+        })
+        .collect();
+    players.sort();
+    let mut enemies: Vec<String> = world
+        .query::<(&Enemy, &GridCell)>()
+        .iter(world)
+        .map(|(enemy, cell)| {
+            format!(
+                "{{\"x\":{},\"y\":{},\"health\":{},\"boss\":false}}",
+                cell.0 .0, cell.0 .1, enemy.health
+            )
+        })
+        .collect();
+    enemies.extend(
+        world
+            .query::<(&Boss, &GridCell)>()
+            .iter(world)
+            .map(|(boss, cell)| {
+                format!(
+                    "{{\"x\":{},\"y\":{},\"health\":{},\"boss\":true}}",
+                    cell.0 .0, cell.0 .1, boss.health
+                )
+            }),
+    );
+
+    format!(
+        concat!(
+            "{{\"tick\":{},\"time\":{:.3},\"grid\":[{}],\"bombs\":[{}],\"explosions\":[{}],",
+            "\"players\":[{}],\"enemies\":[{}]}}"
+        ),
+        tick,
+        world.resource::<Field>().time,
+        rows.join(","),
+        bombs.join(","),
+        explosions.join(","),
+        players
+            .into_iter()
+            .map(|(_, line)| line)
+            .collect::<Vec<String>>()
+            .join(","),
+        enemies.join(",")
+    )
+}
+
+/// A replay file of a match, which `replay_match` plays again from what every player pressed, the
+/// actions a player pressed at once joined by `+`.
+pub fn replay_text(config: &MatchConfig, result: &MatchResult) -> String {
+    let mut lines = vec![
+        "# Bon'berman replay, played again by bomberman-match replay=<file>.".to_string(),
+        format!("mode = {}", config.mode.name()),
+        format!("pack = {}", config.pack.dir),
+        format!("level = {}", config.level),
+        format!("seed = {}", config.seed),
+        format!("ticks = {}", config.max_ticks),
+        format!("bot_level = {}", config.bot_difficulty.name()),
+    ];
+    if let Some(file) = &config.pack.file {
+        lines.push(format!("level_file = {}", file));
+    }
+    for spec in config.players.iter() {
+        lines.push(match spec {
+            PlayerSpec::External(command) => format!("bot = {}", command),
+            PlayerSpec::Cpu => "cpu =".to_string(),
+        });
+    }
+    for actions in result.actions.iter() {
+        let names: Vec<String> = actions
+            .iter()
+            .map(|pressed| {
+                let names: Vec<&str> = pressed.iter().map(|action| action.name()).collect();
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join("+")
+                }
+            })
+            .collect();
+        lines.push(format!("tick = {}", names.join(" ")).trim_end().to_string());
+    }
+    lines.join("\n") + "\n"
+}
+
+/// The match of a replay file, with what every player pressed every tick.
+pub fn parse_replay(text: &str) -> Option<(MatchConfig, Vec<Vec<Vec<Action>>>)> {
+    let mut config = MatchConfig {
+        mode: GameMode::Battle,
+        pack: LevelPack::default(),
+        level: 1,
+        seed: 0,
+        deadline: Duration::from_millis(BOT_DEADLINE_MS),
+        max_ticks: MATCH_TICK_LIMIT,
+        bot_difficulty: Difficulty::Normal,
+        players: Vec::new(),
+    };
+    let mut actions = Vec::new();
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = line.split_once('=').and_then(|(key, value)| {
+            let value = value.trim();
+            match key.trim() {
+                "mode" => config.mode = GameMode::from_name(value)?,
+                "pack" => config.pack.dir = value.to_string(),
+                "level_file" => config.pack.file = Some(value.to_string()),
+                "level" => config.level = value.parse().ok()?,
+                "seed" => config.seed = value.parse().ok()?,
+                "ticks" => config.max_ticks = value.parse().ok()?,
+                "bot_level" => config.bot_difficulty = Difficulty::from_name(value)?,
+                "bot" => config.players.push(PlayerSpec::External(value.to_string())),
+                "cpu" => config.players.push(PlayerSpec::Cpu),
+                "tick" => actions.push(
+                    value
+                        .split_whitespace()
+                        .map(pressed_named)
+                        .collect::<Option<Vec<Vec<Action>>>>()?,
+                ),
+                _ => return None,
+            }
+            Some(())
+        });
+
+        if parsed.is_none() {
+            println!("Can't parse replay line: {}", line);
+            return None;
+        }
+    }
+    Some((config, actions))
+}
+
+/// Reads `{"tick":N,"action":"name"}`, the tick and the action, which is `None` for `none`.
+fn parse_answer(line: &str) -> Option<(u32, Option<Action>)> {
+    let tick = json_value(line, "tick")?.parse().ok()?;
+    let action = action_named(json_value(line, "action")?.trim_matches('"'))?;
+    Some((tick, action))
+}
+
+/// The action a bot can take by this name, `None` for `none`. Bots can't pause.
+fn action_named(name: &str) -> Option<Option<Action>> {
+    if name == "none" {
+        return Some(None);
+    }
+    let action = Action::ALL
+        .into_iter()
+        .find(|action| *action != Action::Pause && action.name() == name)?;
+    Some(Some(action))
+}
+
+/// The actions of a replay joined by `+`, none for `none`.
+fn pressed_named(names: &str) -> Option<Vec<Action>> {
+    let mut pressed = Vec::new();
+    for name in names.split('+') {
+        pressed.extend(action_named(name)?);
+    }
+    Some(pressed)
+}
+
+/// The text of a number or string value of a flat JSON object.
+fn json_value<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\"", key))? + key.len() + 2;
+    let rest = line[start..].trim_start().strip_prefix(':')?.trim_start();
+    let end = match rest.strip_prefix('"') {
+        Some(string) => string.find('"')? + 2,
+        None => rest.find([',', '}']).unwrap_or(rest.len()),
+    };
+    Some(rest[..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_gives_the_recorded_result() {
+        let config = MatchConfig {
+            mode: GameMode::Campaign,
+            pack: LevelPack::default(),
+            level: 1,
+            seed: 3,
+            deadline: Duration::from_millis(BOT_DEADLINE_MS),
+            max_ticks: 1500,
+            bot_difficulty: Difficulty::Normal,
+            players: vec![PlayerSpec::Cpu, PlayerSpec::Cpu],
+        };
+        let result = run_match(&config);
+        let (replayed_config, actions) = parse_replay(&replay_text(&config, &result)).unwrap();
+        assert_eq!(replay_match(&replayed_config, &actions), result);
+    }
+}
@@ -0,0 +1,292 @@
+use crate::bomb_systems::bomb_pulse;
+use crate::boss_systems::boss_translation;
+use crate::components::*;
+use crate::constants::*;
+use crate::net_systems::send_checksum;
+use crate::utils::{cell_to_position, position_to_cell};
+use bevy::ecs::world::{EntityMut, EntityRef};
+use bevy::prelude::*;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+type SaveComponent = fn(&EntityRef) -> Option<Box<dyn SnapshotComponent>>;
+
+/// Everything a simulated entity may carry, apart from its tweens which only draw it.
+const SAVED_COMPONENTS: &[SaveComponent] = &[
+    save::<Transform>,
+    save::<Visibility>,
+    save::<Sprite>,
+    save::<Handle<Image>>,
+    save::<TextureAtlasSprite>,
+    save::<Handle<TextureAtlas>>,
+    save::<Wall>,
+    save::<BreakableWall>,
+    save::<Solid>,
+    save::<Destructable>,
+    save::<SpeedPickup>,
+    save::<SlowTile>,
+    save::<Spawner>,
+    save::<Player>,
+    save::<PlayerId>,
+    save::<PlayerMovement>,
+    save::<Invulnerable>,
+    save::<Speed>,
+    save::<GridCell>,
+    save::<Enemy>,
+    save::<EnemyBehaviour>,
+    save::<Ghost>,
+    save::<Boss>,
+    save::<Bomb>,
+    save::<Explosion>,
+];
+
+struct Saved<T>(T);
+
+impl<T: Component + Clone> SnapshotComponent for Saved<T> {
+    fn insert(&self, entity: &mut EntityMut) {
+        entity.insert(self.0.clone());
+    }
+}
+
+fn save<T: Component + Clone>(entity: &EntityRef) -> Option<Box<dyn SnapshotComponent>> {
+    entity
+        .get::<T>()
+        .map(|component| Box::new(Saved(component.clone())) as Box<dyn SnapshotComponent>)
+}
+
+/// Saves the game at the start of every online tick, and rolls back to the snapshot of the first
+/// mispredicted tick once the remote input of it arrived.
+pub fn snapshot_system(world: &mut World) {
+    if !world.contains_resource::<Lockstep>() {
+        return;
+    }
+
+    world.resource_scope(|world, mut lockstep: Mut<Lockstep>| {
+        // the criteria already counted the tick that is about to run
+        let tick = lockstep.tick - 1;
+        match lockstep.rollback.take() {
+            Some(rollback) => {
+                lockstep
+                    .snapshots
+                    .retain(|snapshot| snapshot.tick <= rollback);
+                match lockstep.snapshots.back() {
+                    Some(snapshot) if snapshot.tick == rollback => {
+                        restore_snapshot(world, snapshot)
+                    }
+                    _ => println!(
+                        "Can't roll back to tick {}, its snapshot is gone.",
+                        rollback
+                    ),
+                }
+            }
+            None => {
+                // snapshots of a future that got rolled back are replaced
+                while lockstep
+                    .snapshots
+                    .back()
+                    .is_some_and(|snapshot| snapshot.tick >= tick)
+                {
+                    lockstep.snapshots.pop_back();
+                }
+                let snapshot = take_snapshot(world, tick);
+                lockstep.snapshots.push_back(snapshot);
+            }
+        }
+        confirm_snapshots(&mut lockstep);
+    });
+}
+
+/// Drops the snapshots of ticks that can't be rolled back to anymore, sending the checksum of
+/// every `CHECKSUM_INTERVAL`th one.
+pub fn confirm_snapshots(lockstep: &mut Lockstep) {
+    while let Some(snapshot) = lockstep.snapshots.front() {
+        if snapshot.tick >= lockstep.verified {
+            break;
+        }
+        let (tick, checksum) = (snapshot.tick, snapshot.checksum);
+        lockstep.snapshots.pop_front();
+        if tick % CHECKSUM_INTERVAL == 0 {
+            send_checksum(lockstep, tick, checksum);
+        }
+    }
+}
+
+fn take_snapshot(world: &mut World, tick: u32) -> Snapshot {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, SimulatedEntities>()
+        .iter(world)
+        .collect();
+    let entities = entities
+        .into_iter()
+        .map(|entity| {
+            let entity = world.entity(entity);
+            SAVED_COMPONENTS
+                .iter()
+                .filter_map(|save| save(&entity))
+                .collect()
+        })
+        .collect();
+
+    Snapshot {
+        tick,
+        checksum: state_checksum(world),
+        entities,
+        rng: world.resource::<GameRng>().state,
+        sim_time: world.resource::<SimTime>().clone(),
+        field: world.resource::<Field>().clone(),
+        scores: world.resource::<Scores>().clone(),
+        lives: world.resource::<Lives>().clone(),
+        battle: world.resource::<Battle>().clone(),
+        hurry_up: world.resource::<HurryUp>().clone(),
+        danger_map: world.resource::<DangerMap>().clone(),
+    }
+}
+
+/// Puts the simulated entities and resources back the way they were in the snapshot.
+fn restore_snapshot(world: &mut World, snapshot: &Snapshot) {
+    let entities: Vec<Entity> = world
+        .query_filtered::<Entity, SimulatedEntities>()
+        .iter(world)
+        .collect();
+    for entity in entities {
+        world.despawn(entity);
+    }
+
+    // spawned in the order they were saved, so the queries go through them in the same order
+    for components in &snapshot.entities {
+        let mut entity = world.spawn();
+        for component in components {
+            component.insert(&mut entity);
+        }
+        entity
+            .insert(GlobalTransform::default())
+            .insert(ComputedVisibility::default());
+
+        // the tweens are gone, whatever was halfway through a step or a pulse is set straight
+        let cell = entity.get::<GridCell>().copied();
+        let boss = entity.contains::<Boss>();
+        if let Some(mut transform) = entity.get_mut::<Transform>() {
+            if let Some(cell) = cell {
+                let z = transform.translation.z;
+                transform.translation = if boss {
+                    boss_translation(cell.0)
+                } else {
+                    cell_to_position(cell.0)
+                };
+                transform.translation.z = z;
+            }
+            transform.scale = Vec3::ONE;
+        }
+        if entity.contains::<Bomb>() {
+            entity.insert(bomb_pulse(Vec3::ONE));
+        }
+    }
+
+    world.resource_mut::<GameRng>().state = snapshot.rng;
+    *world.resource_mut::<SimTime>() = snapshot.sim_time.clone();
+    *world.resource_mut::<Field>() = snapshot.field.clone();
+    *world.resource_mut::<Scores>() = snapshot.scores.clone();
+    *world.resource_mut::<Lives>() = snapshot.lives.clone();
+    *world.resource_mut::<Battle>() = snapshot.battle.clone();
+    *world.resource_mut::<HurryUp>() = snapshot.hurry_up.clone();
+    *world.resource_mut::<DangerMap>() = snapshot.danger_map.clone();
+}
+
+/// Hash of what both peers of an online game must agree on.
+fn state_checksum(world: &mut World) -> u64 {
+    let mut values = vec![
+        world.resource::<GameRng>().state,
+        world.resource::<Field>().time.to_bits() as u64,
+    ];
+    values.extend(
+        world
+            .resource::<Scores>()
+            .players
+            .iter()
+            .map(|score| score.points as u64),
+    );
+    // sorted by player or cell, the query order being up to each peer's world
+    let mut players: Vec<[u64; 5]> = world
+        .query::<(&PlayerId, &Player, &GridCell, &Speed)>()
+        .iter(world)
+        .map(|(id, player, cell, speed)| {
+            let [x, y] = cell_values(cell.0);
+            let bonus = speed.bonus.to_bits() as u64;
+            [id.0 as u64, player.health as u64, bonus, x, y]
+        })
+        .collect();
+    players.sort_unstable();
+    let mut enemies: Vec<[u64; 3]> = world
+        .query::<(&Enemy, &GridCell)>()
+        .iter(world)
+        .map(|(enemy, cell)| {
+            let [x, y] = cell_values(cell.0);
+            [x, y, enemy.health as u64]
+        })
+        .collect();
+    enemies.sort_unstable();
+    let mut bosses: Vec<[u64; 3]> = world
+        .query::<(&Boss, &GridCell)>()
+        .iter(world)
+        .map(|(boss, cell)| {
+            let [x, y] = cell_values(cell.0);
+            [x, y, boss.health as u64]
+        })
+        .collect();
+    bosses.sort_unstable();
+    let mut bombs: Vec<[u64; 3]> = world
+        .query::<(&Bomb, &Transform)>()
+        .iter(world)
+        .map(|(bomb, transform)| {
+            let [x, y] = cell_values(position_to_cell(transform.translation));
+            [x, y, bomb.fuse.elapsed().as_nanos() as u64]
+        })
+        .collect();
+    bombs.sort_unstable();
+    values.extend(players.iter().flatten());
+    values.extend(enemies.iter().chain(&bosses).chain(&bombs).flatten());
+    values.push(
+        world
+            .query_filtered::<(), With<Destructable>>()
+            .iter(world)
+            .count() as u64,
+    );
+
+    fnv_hash(&values)
+}
+
+fn cell_values(cell: (i32, i32)) -> [u64; 2] {
+    [cell.0 as u64, cell.1 as u64]
+}
+
+fn fnv_hash(values: &[u64]) -> u64 {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .fold(FNV_OFFSET, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(FNV_PRIME)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env_systems::Environment;
+
+    #[test]
+    fn same_seed_gives_same_checksums() {
+        let checksums = || {
+            let mut env = Environment::new(GameMode::Battle, 2);
+            env.set_bots(2, Difficulty::Normal);
+            env.reset(7, 1);
+            (0..600)
+                .map(|_| {
+                    env.step(&[]);
+                    state_checksum(env.world_mut())
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(checksums(), checksums());
+    }
+}
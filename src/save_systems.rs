@@ -0,0 +1,603 @@
+use std::fs;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::bomb_systems::spawn_bomb;
+use crate::boss_systems::spawn_boss;
+use crate::components::*;
+use crate::constants::*;
+use crate::enemy_systems::spawn_enemy;
+use crate::field_systems::{spawn_breakable_wall, spawn_spawner, spawn_speed_pickup};
+use crate::player_systems::spawn_player;
+use crate::utils::{asset_path, cell_to_position, position_to_cell};
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::window::WindowCloseRequested;
+
+/// The level being played, as far as a save game needs it.
+#[derive(SystemParam)]
+pub struct LevelState<'w, 's> {
+    field: Res<'w, Field>,
+    hurry_up: Res<'w, HurryUp>,
+    archetypes: Res<'w, EnemyArchetypes>,
+    breakable_query: Query<'w, 's, &'static Transform, With<BreakableWall>>,
+    pickup_query: Query<'w, 's, &'static Transform, With<SpeedPickup>>,
+    spawner_query: Query<'w, 's, (&'static Transform, &'static Spawner)>,
+    enemy_query: Query<'w, 's, (&'static Enemy, &'static GridCell)>,
+    boss_query: Query<'w, 's, (&'static GridCell, &'static Boss)>,
+    bomb_query: Query<'w, 's, (&'static Transform, &'static Bomb)>,
+    player_query: Query<
+        'w,
+        's,
+        (
+            &'static Player,
+            &'static PlayerId,
+            &'static GridCell,
+            &'static Speed,
+        ),
+    >,
+}
+
+/// How far the players got, as far as a save game needs it.
+type GameProgress<'w> = (
+    Res<'w, GameSettings>,
+    Res<'w, Lives>,
+    Res<'w, Scores>,
+    Res<'w, Battle>,
+    Res<'w, GameRng>,
+);
+
+/// Saves the level being played on the save game action, when it is left for the main menu and
+/// when the window is closed.
+pub fn save_game_system(
+    actions: Res<ActionInput>,
+    mut close_events: EventReader<WindowCloseRequested>,
+    mut request: ResMut<SaveRequest>,
+    state: Res<State<AppState>>,
+    progress: GameProgress,
+    lockstep: Option<Res<Lockstep>>,
+    level: LevelState,
+) {
+    let mut requested = actions.global_just_pressed(GlobalAction::SaveGame);
+    requested |= close_events.iter().last().is_some();
+    if request.0 {
+        request.0 = false;
+        requested = true;
+    }
+    // online battles can't be continued alone
+    if !requested
+        || lockstep.is_some()
+        || !matches!(state.current(), AppState::Playing | AppState::Paused)
+    {
+        return;
+    }
+
+    let (settings, lives, scores, battle, rng) = progress;
+    let text = save_game_text(&settings, &lives, &scores, &battle, &rng, &level);
+    match fs::write(asset_path(SAVE_GAME_FILE), text) {
+        Ok(()) => println!("Game saved."),
+        Err(_) => println!("Can't save the game."),
+    }
+}
+
+fn save_game_text(
+    settings: &GameSettings,
+    lives: &Lives,
+    scores: &Scores,
+    battle: &Battle,
+    rng: &GameRng,
+    level: &LevelState,
+) -> String {
+    let field = &level.field;
+    let per_player = |values: &[u32; MAX_PLAYERS]| {
+        values
+            .iter()
+            .map(u32::to_string)
+            .collect::<Vec<String>>()
+            .join(" ")
+    };
+
+    let mut lines = vec![
+        "# Bon'berman save game, restored by Continue on the main menu.".to_string(),
+        format!("mode = {}", settings.mode.name()),
+        format!("difficulty = {}", settings.difficulty.name()),
+        format!("players = {}", settings.player_count),
+        format!("bots = {}", settings.bots),
+        format!("bot_difficulty = {}", settings.bot_difficulty.name()),
+        format!("level = {}", field.current_level),
+        format!("round = {}", battle.round),
+        format!("wins = {}", per_player(&battle.wins)),
+        format!("lives = {}", per_player(&lives.left)),
+    ];
+    for (index, score) in scores.players.iter().enumerate() {
+        lines.push(format!(
+            "score = {} {} {} {}",
+            index + 1,
+            score.points,
+            score.chain,
+            score.chain_time
+        ));
+    }
+    lines.push(format!("time = {}", field.time));
+    lines.push(format!("boss_defeated = {}", field.boss_defeated));
+    lines.push(format!(
+        "hurry_up = {} {} {}",
+        level.hurry_up.started,
+        level.hurry_up.next,
+        level.hurry_up.drop_timer.elapsed_secs()
+    ));
+    lines.push(format!("rng = {}", rng.state));
+
+    for row in field.array.iter() {
+        lines.push(format!(
+            "row = {}",
+            row.iter()
+                .map(|code| static_glyph(*code))
+                .collect::<String>()
+        ));
+    }
+
+    for transform in level.breakable_query.iter() {
+        let (x, y) = position_to_cell(transform.translation);
+        lines.push(format!("breakable = {} {}", x, y));
+    }
+    for transform in level.pickup_query.iter() {
+        let (x, y) = position_to_cell(transform.translation);
+        lines.push(format!("pickup = {} {}", x, y));
+    }
+    for (transform, spawner) in level.spawner_query.iter() {
+        let (x, y) = position_to_cell(transform.translation);
+        let queue: Vec<String> = spawner.queue.iter().map(char::to_string).collect();
+        lines.push(
+            format!(
+                "spawner = {} {} {} {} {} {} {}",
+                x,
+                y,
+                spawner.age,
+                spawner.spawn_timer.elapsed_secs(),
+                spawner.spawned,
+                spawner.next_wave,
+                queue.join(" ")
+            )
+            .trim_end()
+            .to_string(),
+        );
+    }
+    for (enemy, cell) in level.enemy_query.iter() {
+        if let Some(archetype) = level.archetypes.list.get(enemy.archetype) {
+            lines.push(format!(
+                "enemy = {} {} {} {} {} {}",
+                archetype.glyph,
+                cell.0 .0,
+                cell.0 .1,
+                enemy.health,
+                enemy.move_timer.duration().as_secs_f32(),
+                enemy.move_timer.elapsed_secs()
+            ));
+        }
+    }
+    for (cell, boss) in level.boss_query.iter() {
+        let (x, y) = cell.0;
+        lines.push(format!(
+            "boss = {} {} {} {} {} {}",
+            x,
+            y,
+            boss.health,
+            boss.next_attack,
+            boss.attack_timer.duration().as_secs_f32(),
+            boss.attack_timer.elapsed_secs()
+        ));
+    }
+    for (transform, bomb) in level.bomb_query.iter() {
+        let (x, y) = position_to_cell(transform.translation);
+        let owner = bomb.owner.map_or(0, |id| id.0);
+        lines.push(format!(
+            "bomb = {} {} {} {}",
+            x,
+            y,
+            bomb.fuse.elapsed_secs(),
+            owner
+        ));
+    }
+    for (player, id, cell, speed) in level.player_query.iter() {
+        lines.push(format!(
+            "player = {} {} {} {} {} {}",
+            id.0, cell.0 .0, cell.0 .1, player.health, player.max_bombs, speed.bonus
+        ));
+    }
+
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// The level glyph of the cells that never change, the rest is saved as entities.
+fn static_glyph(code: i32) -> char {
+    match code {
+        1 => 'W',
+        7 => '~',
+        code if (PLAYER_CODE + 1..=PLAYER_CODE + MAX_PLAYERS as i32).contains(&code) => {
+            char::from_digit((code - PLAYER_CODE) as u32, 10).unwrap_or('.')
+        }
+        _ => '.',
+    }
+}
+
+fn static_code(glyph: char) -> Option<i32> {
+    match glyph {
+        'W' => Some(1),
+        '~' => Some(7),
+        '1'..='4' => Some(PLAYER_CODE + glyph as i32 - '0' as i32),
+        '.' => Some(0),
+        _ => None,
+    }
+}
+
+pub fn load_save_game() -> Option<SaveGame> {
+    match fs::read_to_string(asset_path(SAVE_GAME_FILE)) {
+        Ok(text) => parse_save_game(&text),
+        Err(_) => {
+            println!("There is no saved game.");
+            None
+        }
+    }
+}
+
+fn parse_save_game(text: &str) -> Option<SaveGame> {
+    let mut save = SaveGame {
+        mode: GameMode::Campaign,
+        difficulty: Difficulty::Normal,
+        player_count: 1,
+        bots: 0,
+        bot_difficulty: Difficulty::Normal,
+        level: 1,
+        round: 1,
+        wins: [0; MAX_PLAYERS],
+        lives: [PLAYER_LIVES; MAX_PLAYERS],
+        scores: [PlayerScore::default(); MAX_PLAYERS],
+        time: 0.,
+        boss_defeated: false,
+        hurry_up_started: false,
+        hurry_up_next: 0,
+        hurry_up_elapsed: 0.,
+        rng: 0,
+        array: [[0; SIZE_IN_CELLS]; SIZE_IN_CELLS],
+        entities: Vec::new(),
+    };
+    let mut rows = 0;
+
+    for line in text.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let parsed = line.split_once('=').and_then(|(key, value)| {
+            let values: Vec<&str> = value.split_whitespace().collect();
+            if key.trim() == "row" {
+                let row = save.array.get_mut(rows)?;
+                rows += 1;
+                return parse_row(&values, row);
+            }
+            parse_save_line(&mut save, key.trim(), &values)
+        });
+
+        if parsed.is_none() {
+            println!("Can't parse save game line: {}", line);
+        }
+    }
+
+    if rows != SIZE_IN_CELLS {
+        println!("The saved field is incomplete.");
+        return None;
+    }
+    Some(save)
+}
+
+fn parse_row(values: &[&str], row: &mut [i32; SIZE_IN_CELLS]) -> Option<()> {
+    let glyphs: Vec<char> = match values {
+        [glyphs] => glyphs.chars().collect(),
+        _ => return None,
+    };
+    if glyphs.len() != SIZE_IN_CELLS {
+        return None;
+    }
+    for (cell, glyph) in row.iter_mut().zip(glyphs) {
+        *cell = static_code(glyph)?;
+    }
+    Some(())
+}
+
+fn parse_save_line(save: &mut SaveGame, key: &str, values: &[&str]) -> Option<()> {
+    match (key, values) {
+        ("mode", [mode]) => save.mode = GameMode::from_name(mode)?,
+        ("difficulty", [difficulty]) => save.difficulty = Difficulty::from_name(difficulty)?,
+        ("players", [count]) => {
+            save.player_count = number(count).filter(|count| (1..=MAX_PLAYERS).contains(count))?
+        }
+        ("bots", [count]) => save.bots = number(count)?,
+        ("bot_difficulty", [difficulty]) => {
+            save.bot_difficulty = Difficulty::from_name(difficulty)?
+        }
+        ("level", [level]) => save.level = number(level)?,
+        ("round", [round]) => save.round = number(round)?,
+        ("wins", wins) => save.wins = per_player(wins)?,
+        ("lives", lives) => save.lives = per_player(lives)?,
+        ("score", [id, points, chain, chain_time]) => {
+            save.scores[player_id(id)?.0 - 1] = PlayerScore {
+                points: number(points)?,
+                chain: number(chain)?,
+                chain_time: number(chain_time)?,
+            }
+        }
+        ("time", [time]) => save.time = seconds(time)?,
+        ("boss_defeated", [defeated]) => save.boss_defeated = number(defeated)?,
+        ("hurry_up", [started, next, elapsed]) => {
+            save.hurry_up_started = number(started)?;
+            save.hurry_up_next = number(next)?;
+            save.hurry_up_elapsed = seconds(elapsed)?;
+        }
+        ("rng", [state]) => save.rng = number(state)?,
+        ("breakable", [x, y]) => save.entities.push(SavedEntity::BreakableWall(cell(x, y)?)),
+        ("pickup", [x, y]) => save.entities.push(SavedEntity::SpeedPickup(cell(x, y)?)),
+        ("spawner", [x, y, age, spawn_elapsed, spawned, next_wave, queue @ ..]) => {
+            save.entities.push(SavedEntity::Spawner {
+                cell: cell(x, y)?,
+                age: seconds(age)?,
+                spawn_elapsed: seconds(spawn_elapsed)?,
+                spawned: number(spawned)?,
+                next_wave: number(next_wave)?,
+                queue: queue
+                    .iter()
+                    .map(|glyph| number(glyph))
+                    .collect::<Option<Vec<char>>>()?,
+            })
+        }
+        ("enemy", [glyph, x, y, health, move_duration, move_elapsed]) => {
+            save.entities.push(SavedEntity::Enemy {
+                glyph: number(glyph)?,
+                cell: cell(x, y)?,
+                health: number(health)?,
+                move_duration: seconds(move_duration)?,
+                move_elapsed: seconds(move_elapsed)?,
+            })
+        }
+        ("boss", [x, y, health, next_attack, attack_duration, attack_elapsed]) => {
+            save.entities.push(SavedEntity::Boss {
+                cell: cell(x, y)?,
+                health: number(health)?,
+                next_attack: number(next_attack)?,
+                attack_duration: seconds(attack_duration)?,
+                attack_elapsed: seconds(attack_elapsed)?,
+            })
+        }
+        ("bomb", [x, y, fuse_elapsed, owner]) => save.entities.push(SavedEntity::Bomb {
+            cell: cell(x, y)?,
+            fuse_elapsed: seconds(fuse_elapsed)?,
+            // bosses own no bombs
+            owner: match *owner {
+                "0" => None,
+                owner => Some(player_id(owner)?),
+            },
+        }),
+        ("player", [id, x, y, health, max_bombs, speed_bonus]) => {
+            save.entities.push(SavedEntity::Player {
+                id: player_id(id)?,
+                cell: cell(x, y)?,
+                health: number(health)?,
+                max_bombs: number(max_bombs)?,
+                speed_bonus: seconds(speed_bonus)?,
+            })
+        }
+        _ => return None,
+    }
+    Some(())
+}
+
+fn number<T: FromStr>(value: &str) -> Option<T> {
+    value.parse::<T>().ok()
+}
+
+/// A duration or an amount that can't be negative.
+fn seconds(value: &str) -> Option<f32> {
+    number::<f32>(value).filter(|seconds| seconds.is_finite() && *seconds >= 0.)
+}
+
+fn cell(x: &str, y: &str) -> Option<(i32, i32)> {
+    let cell = (number(x)?, number(y)?);
+    let range = 0..SIZE_IN_CELLS as i32;
+    if range.contains(&cell.0) && range.contains(&cell.1) {
+        Some(cell)
+    } else {
+        None
+    }
+}
+
+fn player_id(value: &str) -> Option<PlayerId> {
+    number(value)
+        .filter(|id| (1..=MAX_PLAYERS).contains(id))
+        .map(PlayerId)
+}
+
+fn per_player(values: &[&str]) -> Option<[u32; MAX_PLAYERS]> {
+    let mut result = [0; MAX_PLAYERS];
+    if values.len() != MAX_PLAYERS {
+        return None;
+    }
+    for (slot, value) in result.iter_mut().zip(values) {
+        *slot = number(value)?;
+    }
+    Some(result)
+}
+
+fn timer(duration: f32, elapsed: f32, repeating: bool) -> Timer {
+    let mut timer = Timer::from_seconds(duration, repeating);
+    timer.set_elapsed(Duration::from_secs_f32(elapsed));
+    timer
+}
+
+/// Sets up the game picked with "Continue" before its level loads.
+pub fn continue_game(
+    save: SaveGame,
+    saved_game: &mut SavedGame,
+    settings: &mut GameSettings,
+    field: &mut Field,
+    battle: &mut Battle,
+) {
+    settings.mode = save.mode;
+    settings.difficulty = save.difficulty;
+    settings.player_count = save.player_count;
+    settings.bots = save.bots.min(save.player_count - 1);
+    settings.bot_difficulty = save.bot_difficulty;
+    field.current_level = save.level;
+    *battle = Battle::new(BATTLE_BEST_OF);
+    battle.round = save.round;
+    battle.wins = save.wins;
+    saved_game.0 = Some(save);
+}
+
+/// Brings back the state of a continued game once `load_field_system` has read its level file.
+pub fn restore_game_system(
+    saved_game: Res<SavedGame>,
+    mut field: ResMut<Field>,
+    mut hurry_up: ResMut<HurryUp>,
+    mut lives: ResMut<Lives>,
+    mut scores: ResMut<Scores>,
+    mut rng: ResMut<GameRng>,
+) {
+    let save = match &saved_game.0 {
+        Some(save) => save,
+        None => return,
+    };
+
+    field.array = save.array;
+    field.time = save.time;
+    field.boss_defeated = save.boss_defeated;
+    *hurry_up = HurryUp {
+        started: save.hurry_up_started,
+        drop_timer: timer(WALL_DROP_INTERVAL, save.hurry_up_elapsed, true),
+        next: save.hurry_up_next,
+    };
+    lives.left = save.lives;
+    scores.players = save.scores;
+    *rng = GameRng::new(save.rng);
+}
+
+/// Spawns what was left on the field of a continued game, in place of the level file's content.
+pub fn restore_entities(
+    commands: &mut Commands,
+    save: &SaveGame,
+    textures: &GameTextures,
+    archetypes: &EnemyArchetypes,
+    settings: &GameSettings,
+    properties: &LevelProperties,
+) {
+    for entity in save.entities.iter() {
+        match entity {
+            SavedEntity::BreakableWall(cell) => spawn_breakable_wall(commands, textures, *cell),
+            SavedEntity::SpeedPickup(cell) => spawn_speed_pickup(commands, *cell),
+            SavedEntity::Spawner {
+                cell,
+                age,
+                spawn_elapsed,
+                spawned,
+                next_wave,
+                queue,
+            } => {
+                let interval = properties
+                    .spawn_every
+                    .as_ref()
+                    .map_or(0., |schedule| schedule.interval);
+                let spawner = Spawner {
+                    age: *age,
+                    spawn_timer: timer(interval, spawn_elapsed.min(interval), true),
+                    spawned: *spawned,
+                    next_wave: *next_wave,
+                    queue: queue.clone(),
+                };
+                spawn_spawner(commands, textures, *cell, spawner);
+            }
+            SavedEntity::Enemy {
+                glyph,
+                cell,
+                health,
+                move_duration,
+                move_elapsed,
+            } => match archetypes
+                .code_for_glyph(*glyph)
+                .and_then(|code| archetypes.from_code(code))
+            {
+                Some((index, archetype)) => {
+                    let enemy = spawn_enemy(
+                        commands,
+                        index,
+                        archetype,
+                        cell.0,
+                        cell.1,
+                        settings.difficulty.enemy_speed(),
+                    );
+                    commands.entity(enemy).insert(Enemy {
+                        archetype: index,
+                        health: *health,
+                        damage: archetype.contact_damage,
+                        last_hit: Duration::ZERO,
+                        move_timer: timer(*move_duration, *move_elapsed, false),
+                    });
+                }
+                None => println!("Unknown enemy glyph '{}' in the saved game.", glyph),
+            },
+            SavedEntity::Boss {
+                cell,
+                health,
+                next_attack,
+                attack_duration,
+                attack_elapsed,
+            } => {
+                let boss = spawn_boss(commands, textures.boss.clone(), cell.0, cell.1);
+                commands.entity(boss).insert(Boss {
+                    health: *health,
+                    last_hit: Duration::ZERO,
+                    move_timer: Timer::from_seconds(1. / BOSS_SPEED, true),
+                    attack_timer: timer(*attack_duration, *attack_elapsed, false),
+                    next_attack: *next_attack,
+                    charge: None,
+                });
+            }
+            SavedEntity::Bomb {
+                cell,
+                fuse_elapsed,
+                owner,
+            } => {
+                spawn_bomb(
+                    commands,
+                    textures,
+                    &Transform::from_translation(cell_to_position(*cell)),
+                    *owner,
+                )
+                .insert(Bomb {
+                    fuse: timer(BOMB_TIMER, fuse_elapsed.min(BOMB_TIMER), false),
+                    owner: *owner,
+                    triggered: false,
+                });
+            }
+            SavedEntity::Player {
+                id,
+                cell,
+                health,
+                max_bombs,
+                speed_bonus,
+            } => {
+                let player = spawn_player(commands, textures, *id, *cell, *health);
+                commands
+                    .entity(player)
+                    .insert(Player {
+                        health: *health,
+                        last_hit: Duration::ZERO,
+                        max_bombs: *max_bombs,
+                    })
+                    .insert(Speed {
+                        base: PLAYER_SPEED,
+                        bonus: *speed_bonus,
+                    });
+            }
+        }
+    }
+}
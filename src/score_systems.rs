@@ -0,0 +1,155 @@
+use std::fs;
+
+use crate::components::*;
+use crate::constants::*;
+use crate::utils::asset_path;
+use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
+
+/// Ends the kill chains of players who haven't killed anything for `CHAIN_WINDOW`.
+pub fn score_chain_system(mut scores: ResMut<Scores>, time: Res<SimTime>) {
+    // leaves the scores untouched without a chain, so the HUD only redraws them on a change
+    if scores.players.iter().all(|score| score.chain_time <= 0.) {
+        return;
+    }
+    for score in scores.players.iter_mut() {
+        if score.chain_time > 0. {
+            score.chain_time -= time.delta_seconds();
+            if score.chain_time <= 0. {
+                score.chain = 0;
+            }
+        }
+    }
+}
+
+/// Points for finishing a level in `seconds`, for every second under `LEVEL_PAR_TIME`.
+pub fn time_bonus(seconds: f32) -> u32 {
+    (LEVEL_PAR_TIME - seconds).max(0.) as u32 * TIME_BONUS_PER_SECOND
+}
+
+/// Queues every player whose score makes it into the table to type their name.
+pub fn start_name_entry(
+    entry: &mut NameEntry,
+    scores: &Scores,
+    high_scores: &HighScores,
+    player_count: usize,
+) {
+    entry.name.clear();
+    entry.queue = (1..=player_count)
+        .map(PlayerId)
+        .filter(|id| high_scores.qualifies(scores.players[id.0 - 1].points))
+        .collect();
+}
+
+/// Types the name of the first queued player, returns true when the name or the queue changed.
+pub fn enter_name(
+    entry: &mut NameEntry,
+    chars: &mut EventReader<ReceivedCharacter>,
+    keys: &Input<KeyCode>,
+    scores: &Scores,
+    high_scores: &mut HighScores,
+) -> bool {
+    let id = match entry.queue.first() {
+        Some(id) => *id,
+        None => return false,
+    };
+
+    let mut changed = false;
+    for event in chars.iter() {
+        let valid = event.char.is_alphanumeric() || event.char == ' ' || event.char == '-';
+        if valid && entry.name.chars().count() < MAX_NAME_LENGTH {
+            entry.name.push(event.char);
+            changed = true;
+        }
+    }
+    if keys.just_pressed(KeyCode::Back) {
+        changed |= entry.name.pop().is_some();
+    }
+
+    if keys.just_pressed(KeyCode::Return) {
+        let name = entry.name.trim();
+        let name = if name.is_empty() {
+            format!("Player {}", id.0)
+        } else {
+            name.to_string()
+        };
+        let points = scores.players[id.0 - 1].points;
+        // a player ahead in the queue may have pushed this score out of the table
+        if high_scores.qualifies(points) {
+            high_scores.insert(HighScore { points, name });
+            save_high_scores(high_scores);
+        }
+
+        entry.queue.remove(0);
+        entry.name.clear();
+        changed = true;
+    }
+    changed
+}
+
+/// The table, with the name being typed if there is one.
+pub fn high_score_text(high_scores: &HighScores, entry: &NameEntry, scores: &Scores) -> String {
+    let mut lines = vec!["High scores".to_string()];
+    if high_scores.entries.is_empty() {
+        lines.push("-".to_string());
+    }
+    lines.extend(
+        high_scores
+            .entries
+            .iter()
+            .enumerate()
+            .map(|(index, high_score)| {
+                format!("{}. {} {}", index + 1, high_score.name, high_score.points)
+            }),
+    );
+
+    if let Some(id) = entry.queue.first() {
+        lines.push(String::new());
+        lines.push(format!(
+            "Player {}, {} points! Type your name: {}_",
+            id.0,
+            scores.players[id.0 - 1].points,
+            entry.name
+        ));
+    }
+    lines.join("\n")
+}
+
+pub fn load_high_scores() -> HighScores {
+    match fs::read_to_string(asset_path(HIGH_SCORES_FILE)) {
+        Ok(text) => parse_high_scores(&text),
+        Err(_) => {
+            println!("No high scores yet.");
+            HighScores::default()
+        }
+    }
+}
+
+/// One `<points> <name>` line per entry.
+fn parse_high_scores(text: &str) -> HighScores {
+    let mut high_scores = HighScores::default();
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        let parsed = line.split_once(' ').and_then(|(points, name)| {
+            Some(HighScore {
+                points: points.parse::<u32>().ok()?,
+                name: name.trim().to_string(),
+            })
+        });
+        match parsed {
+            Some(entry) => high_scores.insert(entry),
+            None => println!("Can't parse high score: {}", line),
+        }
+    }
+    high_scores
+}
+
+fn save_high_scores(high_scores: &HighScores) {
+    let text: String = high_scores
+        .entries
+        .iter()
+        .map(|entry| format!("{} {}\n", entry.points, entry.name))
+        .collect();
+    if fs::write(asset_path(HIGH_SCORES_FILE), text).is_err() {
+        println!("Can't save the high scores.");
+    }
+}
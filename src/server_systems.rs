@@ -0,0 +1,626 @@
+use std::collections::VecDeque;
+use std::f32::consts::FRAC_PI_2;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::bot_systems::bot_system;
+use crate::components::*;
+use crate::constants::*;
+use crate::headless_app;
+use crate::net_systems::press_input;
+use crate::player_systems::MOVES;
+use crate::state_systems::change_state;
+use crate::utils::position_to_cell;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+
+pub const JOIN: u8 = 0;
+pub const WELCOME: u8 = 1;
+pub const ROOM_FULL: u8 = 2;
+pub const INPUT: u8 = 3;
+pub const STATE: u8 = 4;
+pub const WATCH: u8 = 5;
+pub const ACK: u8 = 6;
+
+/// Id in the welcome of a spectator, who has no player.
+pub const SPECTATOR_ID: u8 = 0;
+
+/// Sent instead of a tick when there is none, a state without a baseline has every entity.
+pub const NO_TICK: u32 = u32::MAX;
+
+const MAX_ROOM_NAME: usize = 32;
+
+/// States by their code in the state packets.
+const APP_STATES: [AppState; 7] = [
+    AppState::MainMenu,
+    AppState::Loading,
+    AppState::Playing,
+    AppState::Paused,
+    AppState::LevelComplete,
+    AppState::GameOver,
+    AppState::Remote,
+];
+
+/// Runs the rooms of a dedicated server, each one a headless game of its own.
+pub struct Server {
+    pub socket: UdpSocket,
+    pub rooms: HashMap<String, Room>,
+}
+
+pub struct Room {
+    pub name: String,
+    pub app: App,
+    pub clients: Vec<RoomClient>,
+    /// Addresses the states are broadcast to as well, without a player.
+    pub spectators: Vec<RoomSpectator>,
+    pub tick: u32,
+    /// States sent lately, the bases of the deltas sent to the clients who received them.
+    pub history: VecDeque<NetState>,
+}
+
+pub struct RoomClient {
+    pub address: SocketAddr,
+    pub id: PlayerId,
+    /// Actions held in the latest input.
+    pub input: u8,
+    /// Actions pressed in any input since the last tick, so that short taps aren't missed.
+    pub tapped: u8,
+    pub sequence: Option<u32>,
+    /// Latest state tick the client received.
+    pub acked: Option<u32>,
+    /// Seconds since the last packet of the client.
+    pub silence: f32,
+}
+
+pub struct RoomSpectator {
+    pub address: SocketAddr,
+    pub acked: Option<u32>,
+    pub silence: f32,
+}
+
+impl Server {
+    pub fn bind(port: u16) -> io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        socket.set_nonblocking(true)?;
+        Ok(Server {
+            socket,
+            rooms: HashMap::default(),
+        })
+    }
+
+    /// Reads the packets of the clients, letting them join rooms and storing their inputs.
+    pub fn receive(&mut self) {
+        let mut buffer = [0; 1024];
+        while let Ok((length, sender)) = self.socket.recv_from(&mut buffer) {
+            let packet = &buffer[..length];
+            match packet.first() {
+                Some(&JOIN) => self.join(sender, &packet[1..]),
+                Some(&WATCH) => self.watch(sender, &packet[1..]),
+                Some(&INPUT) => self.receive_input(sender, &packet[1..]),
+                Some(&ACK) => self.receive_ack(sender, &packet[1..]),
+                _ => {}
+            }
+        }
+    }
+
+    fn join(&mut self, sender: SocketAddr, name: &[u8]) {
+        // a client who is already in a room lost its welcome
+        if let Some(client) = self.client_mut(sender) {
+            let id = client.id;
+            send(&self.socket, sender, &[WELCOME, id.0 as u8]);
+            return;
+        }
+
+        let name = room_name(name);
+        if !self.can_open(sender, &name) {
+            return;
+        }
+        let room = self
+            .rooms
+            .entry(name.clone())
+            .or_insert_with(|| Room::new(name));
+
+        // ids of clients who left are taken over, with the player they left behind
+        let id = match (1..=MAX_PLAYERS)
+            .map(PlayerId)
+            .find(|id| room.clients.iter().all(|client| client.id != *id))
+        {
+            Some(id) => id,
+            None => {
+                send(&self.socket, sender, &[ROOM_FULL]);
+                return;
+            }
+        };
+        println!("{} joined room '{}' as player {}.", sender, room.name, id.0);
+        room.clients.push(RoomClient {
+            address: sender,
+            id,
+            input: 0,
+            tapped: 0,
+            sequence: None,
+            acked: None,
+            silence: 0.,
+        });
+        send(&self.socket, sender, &[WELCOME, id.0 as u8]);
+    }
+
+    /// Whether the room is open or another one can be, every room being a whole game. Tells the
+    /// sender the server is full otherwise.
+    fn can_open(&self, sender: SocketAddr, name: &str) -> bool {
+        if self.rooms.contains_key(name) || self.rooms.len() < MAX_ROOMS {
+            return true;
+        }
+        send(&self.socket, sender, &[ROOM_FULL]);
+        false
+    }
+
+    /// Lets a spectator into a room, which is opened if nobody plays there yet.
+    fn watch(&mut self, sender: SocketAddr, name: &[u8]) {
+        if self.spectator_mut(sender).is_some() {
+            send(&self.socket, sender, &[WELCOME, SPECTATOR_ID]);
+            return;
+        }
+
+        let name = room_name(name);
+        if !self.can_open(sender, &name) {
+            return;
+        }
+        let room = self
+            .rooms
+            .entry(name.clone())
+            .or_insert_with(|| Room::new(name));
+        if room.spectators.len() >= MAX_SPECTATORS {
+            send(&self.socket, sender, &[ROOM_FULL]);
+            return;
+        }
+        println!("{} is watching room '{}'.", sender, room.name);
+        room.spectators.push(RoomSpectator {
+            address: sender,
+            acked: None,
+            silence: 0.,
+        });
+        send(&self.socket, sender, &[WELCOME, SPECTATOR_ID]);
+    }
+
+    fn receive_input(&mut self, sender: SocketAddr, bytes: &[u8]) {
+        let mut reader = PacketReader(bytes);
+        let (ack, sequence, bits) = match (reader.u32(), reader.u32(), reader.u8()) {
+            (Some(ack), Some(sequence), Some(bits)) => (ack, sequence, bits),
+            _ => return,
+        };
+        let client = match self.client_mut(sender) {
+            Some(client) => client,
+            None => return,
+        };
+
+        client.silence = 0.;
+        if ack != NO_TICK && client.acked.is_none_or(|acked| ack > acked) {
+            client.acked = Some(ack);
+        }
+        // inputs overtaken by a later one on the way are only worth their taps
+        client.tapped |= bits;
+        if client.sequence.is_none_or(|latest| sequence > latest) {
+            client.sequence = Some(sequence);
+            client.input = bits;
+        }
+    }
+
+    fn receive_ack(&mut self, sender: SocketAddr, bytes: &[u8]) {
+        let ack = match PacketReader(bytes).u32() {
+            Some(ack) => ack,
+            None => return,
+        };
+        if let Some(spectator) = self.spectator_mut(sender) {
+            spectator.silence = 0.;
+            if ack != NO_TICK && spectator.acked.is_none_or(|acked| ack > acked) {
+                spectator.acked = Some(ack);
+            }
+        }
+    }
+
+    fn client_mut(&mut self, address: SocketAddr) -> Option<&mut RoomClient> {
+        self.rooms
+            .values_mut()
+            .flat_map(|room| room.clients.iter_mut())
+            .find(|client| client.address == address)
+    }
+
+    fn spectator_mut(&mut self, address: SocketAddr) -> Option<&mut RoomSpectator> {
+        self.rooms
+            .values_mut()
+            .flat_map(|room| room.spectators.iter_mut())
+            .find(|spectator| spectator.address == address)
+    }
+
+    /// Runs a tick of every room and sends the clients what changed, closing the rooms everybody
+    /// left.
+    pub fn update(&mut self, delta: f32) {
+        for room in self.rooms.values_mut() {
+            let name = room.name.clone();
+            room.clients.retain_mut(|client| {
+                client.silence += delta;
+                if client.silence > PEER_TIMEOUT {
+                    println!("{} left room '{}'.", client.address, name);
+                }
+                client.silence <= PEER_TIMEOUT
+            });
+            room.spectators.retain_mut(|spectator| {
+                spectator.silence += delta;
+                if spectator.silence > PEER_TIMEOUT {
+                    println!("{} stopped watching room '{}'.", spectator.address, name);
+                }
+                spectator.silence <= PEER_TIMEOUT
+            });
+            room.update(&self.socket);
+        }
+
+        self.rooms.retain(|name, room| {
+            let empty = room.clients.is_empty() && room.spectators.is_empty();
+            if empty {
+                println!("Closing room '{}'.", name);
+            }
+            !empty
+        });
+    }
+}
+
+fn room_name(bytes: &[u8]) -> String {
+    let name: String = String::from_utf8_lossy(bytes)
+        .trim()
+        .chars()
+        .take(MAX_ROOM_NAME)
+        .collect();
+    if name.is_empty() {
+        "lobby".to_string()
+    } else {
+        name
+    }
+}
+
+impl Room {
+    fn new(name: String) -> Self {
+        println!("Opening room '{}'.", name);
+        Room {
+            name,
+            app: room_app(),
+            clients: Vec::new(),
+            spectators: Vec::new(),
+            tick: 0,
+            history: VecDeque::new(),
+        }
+    }
+
+    fn update(&mut self, socket: &UdpSocket) {
+        self.start_or_stop(socket);
+
+        let inputs = self
+            .clients
+            .iter_mut()
+            .map(|client| (client.id, client.input | std::mem::take(&mut client.tapped)))
+            .collect();
+        self.app.world.resource_mut::<RoomInputs>().0 = inputs;
+        self.app.update();
+
+        self.tick += 1;
+        let state = net_state(&mut self.app.world, self.tick);
+        let receivers = self
+            .clients
+            .iter()
+            .map(|client| (client.address, client.acked))
+            .chain(
+                self.spectators
+                    .iter()
+                    .map(|spectator| (spectator.address, spectator.acked)),
+            );
+        for (address, acked) in receivers {
+            let baseline =
+                acked.and_then(|tick| self.history.iter().find(|state| state.tick == tick));
+            send(socket, address, &encode_state(&state, baseline));
+        }
+        self.history.push_back(state);
+        if self.history.len() > STATE_HISTORY {
+            self.history.pop_front();
+        }
+    }
+
+    /// Starts a battle once enough clients joined, and waits again once too many of them left.
+    fn start_or_stop(&mut self, socket: &UdpSocket) {
+        let world = &mut self.app.world;
+        let current = *world.resource::<State<AppState>>().current();
+        let enough = self.clients.len() >= ROOM_MIN_PLAYERS;
+
+        if current == AppState::MainMenu && enough {
+            println!("Starting a battle in room '{}'.", self.name);
+            // players are numbered again for every battle, the ones who left free their ids
+            for (index, client) in self.clients.iter_mut().enumerate() {
+                client.id = PlayerId(index + 1);
+                send(socket, client.address, &[WELCOME, client.id.0 as u8]);
+            }
+            let mut settings = world.resource_mut::<GameSettings>();
+            settings.mode = GameMode::Battle;
+            settings.player_count = self.clients.len();
+            *world.resource_mut::<Battle>() = Battle::new(BATTLE_BEST_OF);
+            *world.resource_mut::<Scores>() = Scores::default();
+            world.resource_mut::<Field>().current_level = 1;
+            change_state(
+                &mut world.resource_mut::<State<AppState>>(),
+                AppState::Loading,
+            );
+        } else if current != AppState::MainMenu && !enough {
+            println!("Room '{}' is waiting for players again.", self.name);
+            change_state(
+                &mut world.resource_mut::<State<AppState>>(),
+                AppState::MainMenu,
+            );
+        }
+    }
+}
+
+/// A headless game steered by the inputs of the room clients.
+fn room_app() -> App {
+    let mut app = headless_app();
+    app.insert_resource(RoomInputs::default())
+        .add_system_to_stage(CoreStage::PreUpdate, validate_inputs_system.before(bot_system));
+    app
+}
+
+/// Turns the inputs of the room clients into actions, dropping moves into solid cells and bombs
+/// beyond a player's capacity before the gameplay systems see them.
+pub fn validate_inputs_system(
+    inputs: Res<RoomInputs>,
+    mut actions: ResMut<ActionInput>,
+    player_query: Query<(&PlayerId, &Player, &GridCell)>,
+    solid_query: Query<(&Transform, Option<&GridCell>), With<Solid>>,
+    bomb_query: Query<&Bomb>,
+) {
+    let solids: HashSet<(i32, i32)> = solid_query
+        .iter()
+        .map(|(solid, cell)| {
+            cell.map_or_else(|| position_to_cell(solid.translation), |cell| cell.0)
+        })
+        .collect();
+
+    let mut validated = ActionInput {
+        previous: std::mem::take(&mut actions.pressed),
+        ..default()
+    };
+    for (id, bits) in inputs.0.iter() {
+        // pausing is up to the server, unknown bits are nobody's business
+        let mut bits = bits & !action_bit(Action::Pause) & ((1 << Action::ALL.len()) - 1);
+
+        // players who haven't dropped in yet only press their place bomb action to join
+        if let Some((_, player, cell)) =
+            player_query.iter().find(|(player_id, ..)| *player_id == id)
+        {
+            for (action, (dx, dy), _) in MOVES {
+                if solids.contains(&(cell.0 .0 + dx, cell.0 .1 + dy)) {
+                    bits &= !action_bit(action);
+                }
+            }
+            let placed = bomb_query
+                .iter()
+                .filter(|bomb| bomb.owner == Some(*id))
+                .count();
+            if placed >= player.max_bombs {
+                bits &= !action_bit(Action::PlaceBomb);
+            }
+        }
+        press_input(&mut validated, *id, bits);
+    }
+    *actions = validated;
+}
+
+fn action_bit(action: Action) -> u8 {
+    Action::ALL
+        .iter()
+        .position(|known| *known == action)
+        .map_or(0, |index| 1 << index)
+}
+
+/// What the clients are shown of a room after a tick.
+pub fn net_state(world: &mut World, tick: u32) -> NetState {
+    let mut entities = HashMap::default();
+    add_placed::<Wall>(world, NetKind::Wall, &mut entities);
+    add_placed::<BreakableWall>(world, NetKind::BreakableWall, &mut entities);
+    add_placed::<SlowTile>(world, NetKind::SlowTile, &mut entities);
+    add_placed::<SpeedPickup>(world, NetKind::SpeedPickup, &mut entities);
+    add_placed::<Spawner>(world, NetKind::Spawner, &mut entities);
+    add_placed::<Bomb>(world, NetKind::Bomb, &mut entities);
+
+    for (entity, id, player, cell, sprite) in world
+        .query::<(Entity, &PlayerId, &Player, &GridCell, &TextureAtlasSprite)>()
+        .iter(world)
+    {
+        let net = NetEntity {
+            kind: NetKind::Player,
+            tag: id.0 as u8,
+            frame: sprite.index as u8,
+            cell: cell.0,
+            value: player.health,
+        };
+        entities.insert(entity.to_bits(), net);
+    }
+    for (entity, enemy, cell) in world.query::<(Entity, &Enemy, &GridCell)>().iter(world) {
+        let net = NetEntity {
+            kind: NetKind::Enemy,
+            tag: enemy.archetype as u8,
+            frame: 0,
+            cell: cell.0,
+            value: enemy.health,
+        };
+        entities.insert(entity.to_bits(), net);
+    }
+    for (entity, boss, cell) in world.query::<(Entity, &Boss, &GridCell)>().iter(world) {
+        let net = NetEntity {
+            kind: NetKind::Boss,
+            tag: 0,
+            frame: 0,
+            cell: cell.0,
+            value: boss.health,
+        };
+        entities.insert(entity.to_bits(), net);
+    }
+    for (entity, transform, sprite) in world
+        .query_filtered::<(Entity, &Transform, &TextureAtlasSprite), With<Explosion>>()
+        .iter(world)
+    {
+        let angle = transform.rotation.to_euler(EulerRot::XYZ).2;
+        let net = NetEntity {
+            kind: NetKind::Explosion,
+            tag: ((angle / FRAC_PI_2).round() as i32).rem_euclid(4) as u8,
+            frame: sprite.index as u8,
+            cell: position_to_cell(transform.translation),
+            value: 0,
+        };
+        entities.insert(entity.to_bits(), net);
+    }
+
+    NetState {
+        tick,
+        state: *world.resource::<State<AppState>>().current(),
+        entities,
+    }
+}
+
+/// Entities that stay on their cell, or whose cell is their translation.
+fn add_placed<T: Component>(
+    world: &mut World,
+    kind: NetKind,
+    entities: &mut HashMap<u64, NetEntity>,
+) {
+    for (entity, transform) in world
+        .query_filtered::<(Entity, &Transform), With<T>>()
+        .iter(world)
+    {
+        let net = NetEntity {
+            kind,
+            tag: 0,
+            frame: 0,
+            cell: position_to_cell(transform.translation),
+            value: 0,
+        };
+        entities.insert(entity.to_bits(), net);
+    }
+}
+
+/// A state packet with the entities that changed since `baseline`, every entity without one.
+pub fn encode_state(state: &NetState, baseline: Option<&NetState>) -> Vec<u8> {
+    let empty = HashMap::default();
+    let previous = baseline.map_or(&empty, |baseline| &baseline.entities);
+    let removed: Vec<u64> = previous
+        .keys()
+        .filter(|id| !state.entities.contains_key(*id))
+        .copied()
+        .collect();
+    let changed: Vec<(&u64, &NetEntity)> = state
+        .entities
+        .iter()
+        .filter(|(id, entity)| previous.get(*id) != Some(*entity))
+        .collect();
+
+    let mut packet = vec![STATE];
+    packet.extend(state.tick.to_le_bytes());
+    packet.extend(
+        baseline
+            .map_or(NO_TICK, |baseline| baseline.tick)
+            .to_le_bytes(),
+    );
+    packet.push(
+        APP_STATES
+            .iter()
+            .position(|known| *known == state.state)
+            .unwrap_or(0) as u8,
+    );
+    packet.extend((removed.len() as u16).to_le_bytes());
+    for id in removed {
+        packet.extend(id.to_le_bytes());
+    }
+    packet.extend((changed.len() as u16).to_le_bytes());
+    for (id, entity) in changed {
+        packet.extend(id.to_le_bytes());
+        packet.push(
+            NetKind::ALL
+                .iter()
+                .position(|kind| *kind == entity.kind)
+                .unwrap_or(0) as u8,
+        );
+        packet.extend([
+            entity.tag,
+            entity.frame,
+            entity.cell.0 as u8,
+            entity.cell.1 as u8,
+        ]);
+        packet.extend((entity.value as i16).to_le_bytes());
+    }
+    packet
+}
+
+/// Reads a state packet, without the packet type, applying it to the state it is a delta of.
+pub fn decode_state(bytes: &[u8], states: &VecDeque<NetState>) -> Option<NetState> {
+    let mut reader = PacketReader(bytes);
+    let tick = reader.u32()?;
+    let baseline = reader.u32()?;
+    let state = *APP_STATES.get(reader.u8()? as usize)?;
+
+    let mut entities = match baseline {
+        NO_TICK => HashMap::default(),
+        baseline => states
+            .iter()
+            .find(|state| state.tick == baseline)?
+            .entities
+            .clone(),
+    };
+    for _ in 0..reader.u16()? {
+        entities.remove(&reader.u64()?);
+    }
+    for _ in 0..reader.u16()? {
+        let id = reader.u64()?;
+        let entity = NetEntity {
+            kind: *NetKind::ALL.get(reader.u8()? as usize)?,
+            tag: reader.u8()?,
+            frame: reader.u8()?,
+            cell: (reader.u8()? as i32, reader.u8()? as i32),
+            value: reader.u16()? as i16 as i32,
+        };
+        entities.insert(id, entity);
+    }
+
+    Some(NetState {
+        tick,
+        state,
+        entities,
+    })
+}
+
+/// Reads little endian numbers off the front of a packet.
+pub struct PacketReader<'a>(pub &'a [u8]);
+
+impl<'a> PacketReader<'a> {
+    fn take<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let bytes = self.0.get(..N)?.try_into().ok()?;
+        self.0 = &self.0[N..];
+        Some(bytes)
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        self.take::<1>().map(|[byte]| byte)
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        self.take().map(u16::from_le_bytes)
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        self.take().map(u32::from_le_bytes)
+    }
+
+    pub fn u64(&mut self) -> Option<u64> {
+        self.take().map(u64::from_le_bytes)
+    }
+}
+
+pub fn send(socket: &UdpSocket, address: SocketAddr, packet: &[u8]) {
+    if let Err(error) = socket.send_to(packet, address) {
+        println!("Can't send to {}: {}", address, error);
+    }
+}
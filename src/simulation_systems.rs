@@ -0,0 +1,119 @@
+use std::time::Duration;
+
+use crate::components::*;
+use crate::constants::*;
+use crate::net_systems::{press_input, read_input};
+use bevy::ecs::schedule::ShouldRun;
+use bevy::prelude::*;
+
+/// Runs the `SimulationStage` while playing. Local games step once a frame by the frame time, or
+/// by a fixed tick with `FixedTick`, online games step by a fixed tick, predicting the remote input of ticks it hasn't arrived
+/// for yet and running the mispredicted ones again once it does.
+pub fn simulation_criteria(
+    state: Res<State<AppState>>,
+    time: Res<Time>,
+    mut sim_time: ResMut<SimTime>,
+    mut actions: ResMut<ActionInput>,
+    mut pending: ResMut<PendingState>,
+    lockstep: Option<ResMut<Lockstep>>,
+    fixed_tick: Option<Res<FixedTick>>,
+) -> ShouldRun {
+    let playing = *state.current() == AppState::Playing;
+    let tick_duration = Duration::from_secs(1) / TICK_RATE;
+    let mut lockstep = match lockstep {
+        Some(lockstep) => lockstep,
+        None if playing => {
+            sim_time.advance(match fixed_tick {
+                Some(_) => tick_duration,
+                None => time.delta(),
+            });
+            return ShouldRun::Yes;
+        }
+        None => return ShouldRun::No,
+    };
+
+    if lockstep.stashed.is_none() {
+        lockstep.stashed = Some(std::mem::take(&mut *actions));
+        lockstep.ticks_this_frame = 0;
+        // waiting for the other peer doesn't build up ticks to rush through afterwards
+        lockstep.accumulator =
+            (lockstep.accumulator + time.delta()).min(tick_duration * MAX_TICKS_PER_FRAME);
+        if playing && find_misprediction(&mut lockstep) {
+            // the state change came from a tick that is about to be run again
+            pending.0 = None;
+        }
+    }
+
+    let tick = lockstep.tick as usize;
+    let resimulating = lockstep.tick < lockstep.resimulate_until;
+    // a tick that changes the state ends the level, the ticks after it belong to the next one
+    let ready = playing
+        && pending.0.is_none()
+        && (resimulating
+            || lockstep.accumulator >= tick_duration
+                && lockstep.ticks_this_frame < MAX_TICKS_PER_FRAME)
+        && tick < lockstep.remote_inputs.len() + lockstep.prediction as usize;
+    if !ready {
+        if !playing {
+            lockstep.accumulator = Duration::ZERO;
+        }
+        *actions = lockstep.stashed.take().unwrap_or_default();
+        return ShouldRun::No;
+    }
+
+    // the input sampled now is played INPUT_DELAY ticks later, by both peers
+    if lockstep.local_inputs.len() == tick + INPUT_DELAY {
+        let bits = lockstep
+            .stashed
+            .as_ref()
+            .map_or(0, |local| read_input(local, PlayerId(1)));
+        lockstep.local_inputs.push(bits);
+    }
+
+    // the other peer is expected to keep holding what it held last
+    let remote = match lockstep.remote_inputs.get(tick) {
+        Some(bits) => *bits,
+        None => lockstep.remote_inputs.last().copied().unwrap_or_default(),
+    };
+    lockstep.played_inputs.truncate(tick);
+    lockstep.played_inputs.push(remote);
+
+    let (local_id, remote_id) = (lockstep.local_id, lockstep.remote_id());
+    let mut input = ActionInput::default();
+    if tick > 0 {
+        press_input(&mut input, local_id, lockstep.local_inputs[tick - 1]);
+        press_input(&mut input, remote_id, lockstep.played_inputs[tick - 1]);
+        input.previous = std::mem::take(&mut input.pressed);
+    }
+    press_input(&mut input, local_id, lockstep.local_inputs[tick]);
+    press_input(&mut input, remote_id, remote);
+    *actions = input;
+
+    if !resimulating {
+        lockstep.accumulator -= tick_duration;
+        lockstep.ticks_this_frame += 1;
+    }
+    lockstep.tick += 1;
+    sim_time.advance(tick_duration);
+    ShouldRun::YesAndCheckAgain
+}
+
+/// Compares the remote inputs that arrived with the ones predicted for them. Rewinds to the
+/// first mispredicted tick, its snapshot is restored once the tick runs again.
+fn find_misprediction(lockstep: &mut Lockstep) -> bool {
+    let confirmed = (lockstep.remote_inputs.len() as u32).min(lockstep.tick);
+    let mispredicted = (lockstep.verified..confirmed).find(|tick| {
+        lockstep.played_inputs[*tick as usize] != lockstep.remote_inputs[*tick as usize]
+    });
+    lockstep.verified = lockstep.verified.max(confirmed);
+
+    match mispredicted {
+        Some(tick) => {
+            lockstep.resimulate_until = lockstep.tick;
+            lockstep.tick = tick;
+            lockstep.rollback = Some(tick);
+            true
+        }
+        None => false,
+    }
+}
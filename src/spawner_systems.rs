@@ -0,0 +1,85 @@
+use crate::boss_systems::boss_cells;
+use crate::components::*;
+use crate::enemy_systems::{spawn_enemy, DIRECTIONS};
+use crate::utils::position_to_cell;
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+
+/// What spawned enemies can't be placed on: a boss blocks every cell it covers.
+type BlockingQuery<'a> = (&'a Transform, Option<&'a GridCell>, Option<&'a Boss>);
+type Blocking = Or<(With<Solid>, With<Boss>)>;
+
+pub fn spawner_system(
+    mut commands: Commands,
+    field: Res<Field>,
+    archetypes: Res<EnemyArchetypes>,
+    mut spawner_query: Query<(&Transform, &mut Spawner)>,
+    blocking_query: Query<BlockingQuery, Blocking>,
+    settings: Res<GameSettings>,
+    time: Res<SimTime>,
+) {
+    let mut solids: HashSet<(i32, i32)> = blocking_query
+        .iter()
+        .flat_map(|(solid, cell, boss)| {
+            let cell = cell.map_or_else(|| position_to_cell(solid.translation), |cell| cell.0);
+            match boss {
+                Some(_) => boss_cells(cell),
+                None => vec![cell],
+            }
+        })
+        .collect();
+
+    for (transform, mut spawner) in spawner_query.iter_mut() {
+        spawner.age += time.delta_seconds();
+
+        if let Some(schedule) = &field.properties.spawn_every {
+            spawner.spawn_timer.tick(time.delta());
+            if spawner.spawn_timer.just_finished() && spawner.spawned < schedule.count {
+                spawner.spawned += 1;
+                spawner.queue.push(schedule.glyph);
+            }
+        }
+
+        while let Some(wave) = field.properties.waves.get(spawner.next_wave) {
+            if wave.time > spawner.age {
+                break;
+            }
+            spawner.queue.extend(wave.glyphs.iter().copied());
+            spawner.next_wave += 1;
+        }
+
+        if spawner.queue.is_empty() {
+            continue;
+        }
+
+        let (x, y) = position_to_cell(transform.translation);
+        let free: Vec<(i32, i32)> = DIRECTIONS
+            .iter()
+            .map(|(dx, dy)| (x + dx, y + dy))
+            .filter(|cell| !solids.contains(cell))
+            .collect();
+
+        // enemies that don't fit stay queued until the cells around the spawner clear up
+        let count = free.len().min(spawner.queue.len());
+        let glyphs: Vec<char> = spawner.queue.drain(..count).collect();
+        for (glyph, cell) in glyphs.into_iter().zip(free) {
+            match archetypes
+                .code_for_glyph(glyph)
+                .and_then(|code| archetypes.from_code(code))
+            {
+                Some((index, archetype)) => {
+                    spawn_enemy(
+                        &mut commands,
+                        index,
+                        archetype,
+                        cell.0,
+                        cell.1,
+                        settings.difficulty.enemy_speed(),
+                    );
+                    solids.insert(cell);
+                }
+                None => println!("Unknown enemy glyph '{}' in a spawner.", glyph),
+            }
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use crate::battle_systems::{next_arena, results_text};
+use crate::components::*;
+use crate::constants::*;
+use crate::field_systems::level_exists;
+use crate::rollback_systems::confirm_snapshots;
+use crate::save_systems::{continue_game, load_save_game};
+use crate::score_systems::{enter_name, high_score_text, start_name_entry, time_bonus};
+use bevy::app::AppExit;
+use bevy::ecs::schedule::StateError;
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::window::ReceivedCharacter;
+use bevy_tweening::*;
+
+const MENU_ITEMS: [&str; 7] = [
+    "Continue",
+    "Campaign",
+    "Battle",
+    "Players",
+    "Bots",
+    "Bot level",
+    "Quit",
+];
+/// Menu items changed with left and right rather than chosen.
+const MENU_OPTIONS: [&str; 3] = ["Players", "Bots", "Bot level"];
+
+/// The overlay shown, to swap for another one.
+#[derive(SystemParam)]
+pub struct Overlays<'w, 's> {
+    commands: Commands<'w, 's>,
+    asset_server: Res<'w, AssetServer>,
+    overlay_query: Query<'w, 's, Entity, With<Overlay>>,
+}
+
+impl<'w, 's> Overlays<'w, 's> {
+    /// Despawns the overlay shown.
+    pub fn clear(&mut self) {
+        for entity in self.overlay_query.iter() {
+            self.commands.entity(entity).despawn_recursive();
+        }
+    }
+
+    /// Despawns the overlay shown and draws `text` instead.
+    pub fn replace(&mut self, text: String) {
+        self.clear();
+        spawn_overlay(&mut self.commands, &self.asset_server, text);
+    }
+}
+
+/// Player 1's actions, unless the rebinding screen or an online game takes them.
+type MenuInput<'w> = (
+    Res<'w, ActionInput>,
+    Res<'w, Rebinding>,
+    Option<Res<'w, Lockstep>>,
+    Option<Res<'w, ServerConnection>>,
+);
+
+/// What a new game starts from.
+type GameStart<'w> = (
+    ResMut<'w, GameSettings>,
+    ResMut<'w, Field>,
+    ResMut<'w, Lives>,
+    ResMut<'w, Scores>,
+    ResMut<'w, Battle>,
+    ResMut<'w, SavedGame>,
+);
+
+/// The level or round that just ended.
+type FinishedLevel<'w> = (Res<'w, Field>, Res<'w, GameSettings>, Res<'w, Battle>);
+
+/// The high score table and the names typed into it.
+#[derive(SystemParam)]
+pub struct HighScoreEntry<'w, 's> {
+    chars: EventReader<'w, 's, ReceivedCharacter>,
+    keys: Res<'w, Input<KeyCode>>,
+    entry: ResMut<'w, NameEntry>,
+    high_scores: ResMut<'w, HighScores>,
+}
+
+/// Queues a state change, reporting it when it can't be made.
+pub fn change_state(state: &mut State<AppState>, next: AppState) {
+    report_state_error(state.set(next));
+}
+
+pub fn report_state_error(result: Result<(), StateError>) {
+    if let Err(error) = result {
+        println!("Can't change the game state: {:?}", error);
+    }
+}
+
+/// Draws `text` centered over a darkened screen.
+pub fn spawn_overlay(commands: &mut Commands, asset_server: &AssetServer, text: String) {
+    commands
+        .spawn_bundle(NodeBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                size: Size::new(Val::Percent(100.), Val::Percent(100.)),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                ..default()
+            },
+            color: Color::rgba(0., 0., 0., 0.75).into(),
+            ..default()
+        })
+        .insert(Overlay)
+        .with_children(|parent| {
+            parent.spawn_bundle(TextBundle::from_section(
+                text,
+                TextStyle {
+                    font: asset_server.load("FiraSans-Regular.ttf"),
+                    font_size: 32.,
+                    color: Color::WHITE,
+                },
+            ));
+        });
+}
+
+pub fn despawn_overlay_system(mut commands: Commands, query: Query<Entity, With<Overlay>>) {
+    query.for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
+}
+
+pub fn despawn_level_system(mut commands: Commands, entities: Query<Entity, LevelEntities>) {
+    entities.for_each(|entity| {
+        commands.entity(entity).despawn_recursive();
+    });
+}
+
+pub fn spawn_main_menu_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    menu: Res<Menu>,
+    settings: Res<GameSettings>,
+    lockstep: Option<Res<Lockstep>>,
+    connection: Option<Res<ServerConnection>>,
+) {
+    let text = match (lockstep, connection) {
+        (Some(lockstep), _) => match lockstep.role {
+            NetRole::Host => "Bon'berman\n\nWaiting for the other player to join...",
+            NetRole::Join => "Bon'berman\n\nJoining the host...",
+        }
+        .to_string(),
+        (None, Some(_)) => "Bon'berman\n\nConnecting to the server...".to_string(),
+        (None, None) => menu_text(&menu, &settings),
+    };
+    spawn_overlay(&mut commands, &asset_server, text);
+}
+
+/// Picks the game mode and the number of players with player 1's movement and place bomb actions.
+pub fn main_menu_system(
+    mut overlays: Overlays,
+    input: MenuInput,
+    mut menu: ResMut<Menu>,
+    game: GameStart,
+    mut state: ResMut<State<AppState>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    let (actions, rebinding, lockstep, connection) = input;
+    // an online game starts by itself once the peers or the server found each other
+    if rebinding.open || lockstep.is_some() || connection.is_some() {
+        return;
+    }
+    let (mut settings, mut field, mut lives, mut scores, mut battle, mut saved_game) = game;
+
+    let id = PlayerId(1);
+    let mut selected = if actions.global_just_pressed(GlobalAction::StartBattle) {
+        MENU_ITEMS.iter().position(|item| *item == "Battle")
+    } else if actions.just_pressed(id, Action::PlaceBomb) {
+        Some(menu.selected)
+    } else {
+        None
+    };
+    let mut changed = false;
+
+    if actions.just_pressed(id, Action::MoveUp) {
+        menu.selected = (menu.selected + MENU_ITEMS.len() - 1) % MENU_ITEMS.len();
+        changed = true;
+    }
+    if actions.just_pressed(id, Action::MoveDown) {
+        menu.selected = (menu.selected + 1) % MENU_ITEMS.len();
+        changed = true;
+    }
+    let (left, right) = (
+        actions.just_pressed(id, Action::MoveLeft),
+        actions.just_pressed(id, Action::MoveRight),
+    );
+    match MENU_ITEMS[menu.selected] {
+        "Players" => {
+            if left && settings.player_count > 1 {
+                settings.player_count -= 1;
+                // player 1 is always at the keyboard
+                settings.bots = settings.bots.min(settings.player_count - 1);
+                changed = true;
+            }
+            if right && settings.player_count < MAX_PLAYERS {
+                settings.player_count += 1;
+                changed = true;
+            }
+        }
+        "Bots" => {
+            if left && settings.bots > 0 {
+                settings.bots -= 1;
+                changed = true;
+            }
+            if right && settings.bots + 1 < settings.player_count {
+                settings.bots += 1;
+                changed = true;
+            }
+        }
+        "Bot level" => {
+            let index = Difficulty::ALL
+                .iter()
+                .position(|difficulty| *difficulty == settings.bot_difficulty)
+                .unwrap_or_default();
+            if left && index > 0 {
+                settings.bot_difficulty = Difficulty::ALL[index - 1];
+                changed = true;
+            }
+            if right && index + 1 < Difficulty::ALL.len() {
+                settings.bot_difficulty = Difficulty::ALL[index + 1];
+                changed = true;
+            }
+        }
+        _ => {}
+    }
+    selected = selected.filter(|item| !MENU_OPTIONS.contains(&MENU_ITEMS[*item]));
+
+    match selected.map(|item| MENU_ITEMS[item]) {
+        Some("Continue") => match load_save_game() {
+            Some(save) => {
+                println!("Continuing the saved game.");
+                continue_game(save, &mut saved_game, &mut settings, &mut field, &mut battle);
+                change_state(&mut state, AppState::Loading);
+                return;
+            }
+            None => {
+                println!("Can't continue, there is no saved game.");
+                return;
+            }
+        },
+        Some("Campaign") => {
+            println!("Starting the campaign.");
+            settings.mode = GameMode::Campaign;
+            *lives = Lives::default();
+            *scores = Scores::default();
+        }
+        Some("Battle") => {
+            println!("Starting a battle.");
+            settings.mode = GameMode::Battle;
+            settings.player_count = settings.player_count.max(2);
+            *battle = Battle::new(BATTLE_BEST_OF);
+        }
+        Some(_) => {
+            exit.send(AppExit);
+            return;
+        }
+        None => {
+            if changed {
+                overlays.replace(menu_text(&menu, &settings));
+            }
+            return;
+        }
+    }
+
+    field.current_level = field.first_level;
+    change_state(&mut state, AppState::Loading);
+}
+
+fn menu_text(menu: &Menu, settings: &GameSettings) -> String {
+    let mut lines = vec!["Bon'berman".to_string(), String::new()];
+    lines.extend(MENU_ITEMS.iter().enumerate().map(|(index, item)| {
+        let cursor = if index == menu.selected { ">" } else { " " };
+        match *item {
+            "Players" => format!("{} Players: < {} >", cursor, settings.player_count),
+            "Bots" => format!("{} Bots: < {} >", cursor, settings.bots),
+            "Bot level" => format!("{} Bot level: < {} >", cursor, settings.bot_difficulty.name()),
+            item => format!("{} {}", cursor, item),
+        }
+    }));
+    lines.push(String::new());
+    lines.push("Move and place a bomb with player 1's keys to choose.".to_string());
+    lines.push("F1 rebinds the keys, F5 saves a game in progress.".to_string());
+    lines.join("\n")
+}
+
+/// Shows the "Level N" screen, the level itself is loaded alongside by `load_field_system`.
+pub fn spawn_level_intro_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    field: Res<Field>,
+    settings: Res<GameSettings>,
+    battle: Res<Battle>,
+    mut timer: ResMut<StateTimer>,
+) {
+    let text = match settings.mode {
+        GameMode::Campaign => format!("Level {}", field.current_level),
+        GameMode::Battle => format!("Round {}\nArena {}", battle.round, field.current_level),
+    };
+    spawn_overlay(&mut commands, &asset_server, text);
+    *timer = StateTimer(Timer::from_seconds(LEVEL_INTRO_DURATION, false));
+}
+
+/// Ticks the state timer, true once it has run out.
+fn state_timer_finished(timer: &mut StateTimer, time: &Time) -> bool {
+    timer.0.tick(time.delta());
+    timer.0.finished()
+}
+
+pub fn level_intro_system(
+    mut timer: ResMut<StateTimer>,
+    time: Res<Time>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if state_timer_finished(&mut timer, &time) {
+        change_state(&mut state, AppState::Playing);
+    }
+}
+
+/// Makes the state change a gameplay system asked for, online once every tick up to it was run
+/// with the received remote input.
+pub fn apply_pending_state_system(
+    mut pending: ResMut<PendingState>,
+    lockstep: Option<ResMut<Lockstep>>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if *state.current() != AppState::Playing {
+        pending.0 = None;
+        return;
+    }
+    let next = match pending.0 {
+        Some(next) => next,
+        None => return,
+    };
+
+    if let Some(mut lockstep) = lockstep {
+        if lockstep.verified < lockstep.tick {
+            return;
+        }
+        // the next level starts over, nothing before it is rolled back to
+        confirm_snapshots(&mut lockstep);
+        lockstep.snapshots.clear();
+        lockstep.resimulate_until = lockstep.tick;
+    }
+    pending.0 = None;
+    change_state(&mut state, next);
+}
+
+/// Pauses and resumes the game on any player's pause action.
+pub fn pause_system(
+    actions: Res<ActionInput>,
+    settings: Res<GameSettings>,
+    lockstep: Option<Res<Lockstep>>,
+    mut state: ResMut<State<AppState>>,
+) {
+    // the other peer keeps playing, online games can't be paused
+    if lockstep.is_some() {
+        return;
+    }
+
+    let pressed = (1..=settings.player_count)
+        .map(PlayerId)
+        .any(|id| actions.just_pressed(id, Action::Pause));
+    if !pressed {
+        return;
+    }
+
+    match state.current() {
+        AppState::Playing => report_state_error(state.push(AppState::Paused)),
+        AppState::Paused => report_state_error(state.pop()),
+        _ => {}
+    }
+}
+
+/// Saves a paused game and leaves it for the main menu on any player's detonate action.
+pub fn paused_system(
+    actions: Res<ActionInput>,
+    settings: Res<GameSettings>,
+    mut save_request: ResMut<SaveRequest>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if (1..=settings.player_count)
+        .map(PlayerId)
+        .any(|id| actions.just_pressed(id, Action::Detonate))
+    {
+        save_request.0 = true;
+        report_state_error(state.replace(AppState::MainMenu));
+    }
+}
+
+pub fn spawn_pause_screen_system(mut commands: Commands, asset_server: Res<AssetServer>) {
+    spawn_overlay(
+        &mut commands,
+        &asset_server,
+        "Paused\n\nPause again to resume\nDetonate to save and quit to the main menu".to_string(),
+    );
+}
+
+/// Freezes every tween while the game is paused, timers and fuses stop with the systems ticking them.
+pub fn pause_animators_system(mut query: Query<&mut Animator<Transform>>) {
+    for mut animator in query.iter_mut() {
+        animator.state = AnimatorState::Paused;
+    }
+}
+
+pub fn resume_animators_system(mut query: Query<&mut Animator<Transform>>) {
+    for mut animator in query.iter_mut() {
+        animator.state = AnimatorState::Playing;
+    }
+}
+
+pub fn spawn_level_complete_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    level: FinishedLevel,
+    mut timer: ResMut<StateTimer>,
+    mut scores: ResMut<Scores>,
+    player_query: Query<&PlayerId, With<Player>>,
+) {
+    let (field, settings, battle) = level;
+    let (text, duration) = match settings.mode {
+        GameMode::Campaign => {
+            let bonus = time_bonus(field.time);
+            for id in player_query.iter() {
+                scores.add(*id, bonus);
+            }
+            (
+                format!("Level {} complete!\n\nTime bonus: {}", field.current_level, bonus),
+                LEVEL_COMPLETE_DURATION,
+            )
+        }
+        GameMode::Battle => (results_text(&battle, settings.player_count), RESULTS_DURATION),
+    };
+    spawn_overlay(&mut commands, &asset_server, text);
+    *timer = StateTimer(Timer::from_seconds(duration, false));
+}
+
+/// Loads the next level or arena once the level complete screen has been shown.
+pub fn level_complete_system(
+    mut timer: ResMut<StateTimer>,
+    time: Res<Time>,
+    settings: Res<GameSettings>,
+    pack: Res<LevelPack>,
+    mut field: ResMut<Field>,
+    mut battle: ResMut<Battle>,
+    mut state: ResMut<State<AppState>>,
+) {
+    if !state_timer_finished(&mut timer, &time) {
+        return;
+    }
+
+    match settings.mode {
+        GameMode::Campaign => {
+            field.current_level += 1;
+            if !level_exists(&pack, GameMode::Campaign, field.current_level) {
+                println!("That was the last level, well done!");
+                change_state(&mut state, AppState::MainMenu);
+                return;
+            }
+        }
+        GameMode::Battle => next_arena(&mut battle, &mut field, &pack),
+    }
+    change_state(&mut state, AppState::Loading);
+}
+
+pub fn spawn_game_over_system(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    field: Res<Field>,
+    settings: Res<GameSettings>,
+    scores: Res<Scores>,
+    high_scores: Res<HighScores>,
+    mut entry: ResMut<NameEntry>,
+) {
+    start_name_entry(&mut entry, &scores, &high_scores, settings.player_count);
+    spawn_overlay(
+        &mut commands,
+        &asset_server,
+        game_over_text(field.current_level, &high_scores, &entry, &scores),
+    );
+}
+
+fn game_over_text(level: u32, high_scores: &HighScores, entry: &NameEntry, scores: &Scores) -> String {
+    let mut text = "Game Over!\n\n".to_string();
+    text.push_str(&high_score_text(high_scores, entry, scores));
+    if entry.queue.is_empty() {
+        text.push_str(&format!(
+            "\n\nPlace a bomb to continue from level {}\nDetonate to start over from level 1\nPause to quit to the main menu",
+            level
+        ));
+    }
+    text
+}
+
+/// Lets the players with a high score type their name, then waits for them to continue the level,
+/// start the campaign over or quit.
+pub fn game_over_system(
+    mut overlays: Overlays,
+    name_entry: HighScoreEntry,
+    actions: Res<ActionInput>,
+    game: GameStart,
+    mut state: ResMut<State<AppState>>,
+) {
+    let HighScoreEntry {
+        mut chars,
+        keys,
+        mut entry,
+        mut high_scores,
+    } = name_entry;
+    let (settings, mut field, mut lives, mut scores, ..) = game;
+
+    if !entry.queue.is_empty() {
+        if enter_name(&mut entry, &mut chars, &keys, &scores, &mut high_scores) {
+            overlays.replace(game_over_text(field.current_level, &high_scores, &entry, &scores));
+        }
+        return;
+    }
+
+    let pressed = |action| {
+        (1..=settings.player_count)
+            .map(PlayerId)
+            .any(|id| actions.just_pressed(id, action))
+    };
+
+    if pressed(Action::Pause) {
+        change_state(&mut state, AppState::MainMenu);
+        return;
+    }
+
+    if pressed(Action::Detonate) {
+        println!("Starting over.");
+        field.current_level = field.first_level;
+    } else if pressed(Action::PlaceBomb) {
+        println!("Continuing from level {}.", field.current_level);
+    } else {
+        return;
+    }
+    *lives = Lives::default();
+    *scores = Scores::default();
+    change_state(&mut state, AppState::Loading);
+}
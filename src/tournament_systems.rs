@@ -0,0 +1,384 @@
+use std::fs;
+use std::time::Duration;
+
+use crate::components::*;
+use crate::field_systems::level_exists;
+use crate::protocol_systems::{replay_text, run_match, MatchConfig, MatchResult, PlayerSpec};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TournamentFormat {
+    /// Every bot plays every other bot once.
+    RoundRobin,
+    /// Single elimination, the winner of every match moving on to the next round.
+    Bracket,
+}
+
+impl TournamentFormat {
+    pub fn name(self) -> &'static str {
+        match self {
+            TournamentFormat::RoundRobin => "round_robin",
+            TournamentFormat::Bracket => "bracket",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [TournamentFormat::RoundRobin, TournamentFormat::Bracket]
+            .into_iter()
+            .find(|format| format.name() == name)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TournamentConfig {
+    pub format: TournamentFormat,
+    /// Commands of the bots, in seeding order for a bracket.
+    pub bots: Vec<String>,
+    /// Its arenas are played in turn, one per match.
+    pub pack: LevelPack,
+    /// Match N is played with the random numbers of this seed plus N.
+    pub seed: u64,
+    pub deadline: Duration,
+    pub max_ticks: u32,
+    /// Directory the replay of every match is saved to, none are saved without one.
+    pub replays: Option<String>,
+}
+
+/// A battle between two bots of a tournament.
+#[derive(Clone, Debug)]
+pub struct TournamentMatch {
+    /// From 1, in the order the matches were played.
+    pub number: usize,
+    /// From 1, the bracket round or 1 for the whole round robin.
+    pub round: u32,
+    pub level: u32,
+    pub seed: u64,
+    /// Indices into `TournamentConfig::bots` of players 1 and 2.
+    pub bots: [usize; 2],
+    pub result: MatchResult,
+    /// Index of the bot that won, or went on in a bracket after an undecided match.
+    pub winner: Option<usize>,
+}
+
+/// How a bot did over a tournament.
+#[derive(Clone, Debug, Default)]
+pub struct Standing {
+    pub bot: usize,
+    pub played: u32,
+    pub wins: u32,
+    pub draws: u32,
+    pub losses: u32,
+    /// Points scored in all its matches, which break ties.
+    pub points: u32,
+    /// Bracket round the bot was knocked out in, 0 for the champion or a round robin.
+    pub eliminated: u32,
+}
+
+impl Standing {
+    /// Three for a win and one for a draw.
+    pub fn score(&self) -> u32 {
+        3 * self.wins + self.draws
+    }
+}
+
+/// Plays every match of a tournament, returning them with the standings, the best bot first.
+pub fn run_tournament(config: &TournamentConfig) -> (Vec<TournamentMatch>, Vec<Standing>) {
+    let arenas = (1..)
+        .take_while(|index| level_exists(&config.pack, GameMode::Battle, *index))
+        .count() as u32;
+    if arenas == 0 {
+        println!("The level pack '{}' has no arenas.", config.pack.dir);
+        return (Vec::new(), Vec::new());
+    }
+    if let Some(dir) = &config.replays {
+        if let Err(error) = fs::create_dir_all(dir) {
+            println!("Can't create the replay directory '{}': {}", dir, error);
+        }
+    }
+
+    let mut matches = Vec::new();
+    let mut play = |round: u32, bots: [usize; 2]| {
+        let number = matches.len() + 1;
+        let game = play_match(config, number, round, arenas, bots);
+        let winner = game.winner;
+        matches.push(game);
+        winner
+    };
+
+    match config.format {
+        TournamentFormat::RoundRobin => {
+            for first in 0..config.bots.len() {
+                for second in first + 1..config.bots.len() {
+                    play(1, [first, second]);
+                }
+            }
+        }
+        TournamentFormat::Bracket => {
+            let mut remaining: Vec<usize> = (0..config.bots.len()).collect();
+            let mut round = 1;
+            while remaining.len() > 1 {
+                // the best seeded bots get a bye when the field is odd
+                let byes = remaining.len() % 2;
+                let mut next: Vec<usize> = remaining[..byes].to_vec();
+                for pair in remaining[byes..].chunks(2) {
+                    let winner = play(round, [pair[0], pair[1]]);
+                    next.extend(winner);
+                }
+                remaining = next;
+                round += 1;
+            }
+        }
+    }
+
+    let standings = standings(config, &matches);
+    (matches, standings)
+}
+
+fn play_match(
+    config: &TournamentConfig,
+    number: usize,
+    round: u32,
+    arenas: u32,
+    bots: [usize; 2],
+) -> TournamentMatch {
+    let level = (number as u32 - 1) % arenas + 1;
+    let seed = config.seed.wrapping_add(number as u64);
+    let match_config = MatchConfig {
+        mode: GameMode::Battle,
+        pack: config.pack.clone(),
+        level,
+        seed,
+        deadline: config.deadline,
+        max_ticks: config.max_ticks,
+        bot_difficulty: Difficulty::Normal,
+        players: bots
+            .iter()
+            .map(|bot| PlayerSpec::External(config.bots[*bot].clone()))
+            .collect(),
+    };
+    println!(
+        "Match {}: {} against {} on arena {}.",
+        number, config.bots[bots[0]], config.bots[bots[1]], level
+    );
+    let result = run_match(&match_config);
+
+    if let Some(dir) = &config.replays {
+        let path = format!("{}/match{}.replay", dir, number);
+        if let Err(error) = fs::write(&path, replay_text(&match_config, &result)) {
+            println!("Can't save the replay to '{}': {}", path, error);
+        }
+    }
+
+    // a bracket can't end undecided, more points then the better seed go on
+    let winner = match (result.winner, config.format) {
+        (Some(id), _) => Some(bots[id.0 - 1]),
+        (None, TournamentFormat::RoundRobin) => None,
+        (None, TournamentFormat::Bracket) if result.points[1] > result.points[0] => Some(bots[1]),
+        (None, TournamentFormat::Bracket) => Some(bots[0]),
+    };
+    TournamentMatch {
+        number,
+        round,
+        level,
+        seed,
+        bots,
+        result,
+        winner,
+    }
+}
+
+fn standings(config: &TournamentConfig, matches: &[TournamentMatch]) -> Vec<Standing> {
+    let mut standings: Vec<Standing> = (0..config.bots.len())
+        .map(|bot| Standing {
+            bot,
+            ..Default::default()
+        })
+        .collect();
+    for game in matches {
+        for (index, bot) in game.bots.iter().enumerate() {
+            let standing = &mut standings[*bot];
+            standing.played += 1;
+            standing.points += game.result.points[index];
+            match game.winner {
+                Some(winner) if winner == *bot => standing.wins += 1,
+                Some(_) => {
+                    standing.losses += 1;
+                    if config.format == TournamentFormat::Bracket {
+                        standing.eliminated = game.round;
+                    }
+                }
+                None => standing.draws += 1,
+            }
+        }
+    }
+
+    // knocked out later is better, then the champion above them all
+    let knockout = |standing: &Standing| match standing.eliminated {
+        0 => u32::MAX,
+        round => round,
+    };
+    standings.sort_by(|a, b| {
+        knockout(b)
+            .cmp(&knockout(a))
+            .then(b.score().cmp(&a.score()))
+            .then(b.points.cmp(&a.points))
+            .then(a.bot.cmp(&b.bot))
+    });
+    standings
+}
+
+/// The standings as a table for the terminal.
+pub fn standings_table(config: &TournamentConfig, standings: &[Standing]) -> String {
+    let mut lines = vec![format!(
+        "{:>4}  {:<32} {:>6} {:>4} {:>5} {:>6} {:>6} {:>7}",
+        "Rank", "Bot", "Played", "Wins", "Draws", "Losses", "Score", "Points"
+    )];
+    for (rank, standing) in standings.iter().enumerate() {
+        lines.push(format!(
+            "{:>4}  {:<32} {:>6} {:>4} {:>5} {:>6} {:>6} {:>7}",
+            rank + 1,
+            config.bots[standing.bot],
+            standing.played,
+            standing.wins,
+            standing.draws,
+            standing.losses,
+            standing.score(),
+            standing.points
+        ));
+    }
+    lines.join("\n")
+}
+
+pub fn standings_csv(config: &TournamentConfig, standings: &[Standing]) -> String {
+    let mut lines = vec!["rank,bot,played,wins,draws,losses,score,points".to_string()];
+    for (rank, standing) in standings.iter().enumerate() {
+        lines.push(format!(
+            "{},{},{},{},{},{},{},{}",
+            rank + 1,
+            csv_field(&config.bots[standing.bot]),
+            standing.played,
+            standing.wins,
+            standing.draws,
+            standing.losses,
+            standing.score(),
+            standing.points
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+pub fn standings_json(config: &TournamentConfig, standings: &[Standing]) -> String {
+    let rows: Vec<String> = standings
+        .iter()
+        .enumerate()
+        .map(|(rank, standing)| {
+            format!(
+                concat!(
+                    "  {{\"rank\":{},\"bot\":{},\"played\":{},\"wins\":{},\"draws\":{},",
+                    "\"losses\":{},\"score\":{},\"points\":{}}}"
+                ),
+                rank + 1,
+                json_string(&config.bots[standing.bot]),
+                standing.played,
+                standing.wins,
+                standing.draws,
+                standing.losses,
+                standing.score(),
+                standing.points
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+pub fn matches_csv(config: &TournamentConfig, matches: &[TournamentMatch]) -> String {
+    let mut lines = vec![
+        "match,round,level,seed,bot1,bot2,winner,ticks,health1,health2,points1,points2".to_string(),
+    ];
+    for game in matches {
+        lines.push(format!(
+            "{},{},{},{},{},{},{},{},{},{},{},{}",
+            game.number,
+            game.round,
+            game.level,
+            game.seed,
+            csv_field(&config.bots[game.bots[0]]),
+            csv_field(&config.bots[game.bots[1]]),
+            game.winner
+                .map_or(String::new(), |bot| csv_field(&config.bots[bot])),
+            game.result.ticks,
+            game.result.health[0],
+            game.result.health[1],
+            game.result.points[0],
+            game.result.points[1]
+        ));
+    }
+    lines.join("\n") + "\n"
+}
+
+pub fn matches_json(config: &TournamentConfig, matches: &[TournamentMatch]) -> String {
+    let rows: Vec<String> = matches
+        .iter()
+        .map(|game| {
+            format!(
+                concat!(
+                    "  {{\"match\":{},\"round\":{},\"level\":{},\"seed\":{},\"bots\":[{},{}],",
+                    "\"winner\":{},\"ticks\":{},\"health\":[{},{}],\"points\":[{},{}]}}"
+                ),
+                game.number,
+                game.round,
+                game.level,
+                game.seed,
+                json_string(&config.bots[game.bots[0]]),
+                json_string(&config.bots[game.bots[1]]),
+                game.winner
+                    .map_or("null".to_string(), |bot| json_string(&config.bots[bot])),
+                game.result.ticks,
+                game.result.health[0],
+                game.result.health[1],
+                game.result.points[0],
+                game.result.points[1]
+            )
+        })
+        .collect();
+    format!("[\n{}\n]\n", rows.join(",\n"))
+}
+
+/// Quoted when it has to be, bot commands may well have commas or quotes.
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len() + 2);
+    escaped.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_string_escapes_control_characters() {
+        assert_eq!(
+            json_string("bot \"a\"\\\n\r\t\u{1}"),
+            "\"bot \\\"a\\\"\\\\\\n\\r\\t\\u0001\""
+        );
+    }
+}
@@ -1,12 +1,52 @@
 use bevy::prelude::*;
 use crate::constants::*;
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn is_equal(transform_one: &Transform, transform_two: &Transform) -> bool {
     transform_one.translation.x == transform_two.translation.x
         && transform_one.translation.y == transform_two.translation.y
 }
 
-pub fn is_equal_approximate(transform_one: &Transform, transform_two: &Transform) -> bool {
-    ((transform_two.translation.x - CELL_OFFSET)..(transform_two.translation.x + CELL_OFFSET)).contains(&transform_one.translation.x )
-        && ((transform_two.translation.y - CELL_OFFSET)..(transform_two.translation.y + CELL_OFFSET)).contains(&transform_one.translation.y )
-}
\ No newline at end of file
+/// Converts a world position into the `(column, row)` cell of the field grid.
+pub fn position_to_cell(translation: Vec3) -> (i32, i32) {
+    (
+        ((translation.x + FIELD_OFFSET - CELL_OFFSET) / CELL_SIZE).round() as i32,
+        ((translation.y + FIELD_OFFSET - CELL_OFFSET) / CELL_SIZE).round() as i32,
+    )
+}
+
+/// The world position of the center of a field cell, at the same depth as the field sprites.
+pub fn cell_to_position(cell: (i32, i32)) -> Vec3 {
+    Vec3 {
+        x: cell.0 as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
+        y: cell.1 as f32 * CELL_SIZE - FIELD_OFFSET + CELL_OFFSET,
+        z: 2.,
+    }
+}
+
+/// A different seed every run, taken from the clock.
+pub fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_nanos() as u64)
+}
+
+static ASSETS: OnceLock<String> = OnceLock::new();
+
+/// Reads and writes the files of the game in `dir` instead of `ASSETS_DIR`, before anything is
+/// loaded.
+pub fn set_assets_dir(dir: &str) {
+    if ASSETS.set(dir.trim_end_matches('/').to_string()).is_err() {
+        println!("The assets directory is already set.");
+    }
+}
+
+pub fn assets_dir() -> &'static str {
+    ASSETS.get().map_or(ASSETS_DIR, String::as_str)
+}
+
+/// Path of a file in the assets directory.
+pub fn asset_path(file: &str) -> String {
+    format!("{}/{}", assets_dir(), file)
+}